@@ -0,0 +1,911 @@
+// Our own little ELF64 writer, enough to wrap JIT generated x86-64 code into
+// something Linux will load and run without an assembler or linker in sight,
+// or into an object file a linker can combine with other code.
+// See the System V ABI, or man elf(5), for what every field here means.
+
+use super::jit::{x86_64_linux::Relocation, Arch};
+
+// Where the executable is loaded, the traditional x86-64 base address
+const BASE_ADDR: u64 = 0x40_0000;
+const PAGE_SIZE: u64 = 0x1000;
+
+// Tape size every Brainfuck program gets, same as the interpreter and JIT
+const TAPE_LEN: u64 = 30_000;
+
+const ELF_HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+const SECTION_HEADER_SIZE: u64 = 64;
+const SYMBOL_SIZE: u64 = 24;
+const RELA_SIZE: u64 = 24;
+
+// e_type
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+// e_machine
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+const EM_RISCV: u16 = 243;
+// e_flags, RISC-V records the floating point calling convention, Linux uses doubles
+const EF_RISCV_FLOAT_ABI_DOUBLE: u32 = 0x4;
+// p_type
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474_e551;
+// p_flags
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+// sh_type
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+// sh_flags
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+// st_info, binding in the upper four bits and type in the lower four
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+// st_shndx, for symbols that aren't relative to any section
+const SHN_ABS: u16 = 0xfff1;
+// r_info type
+const R_X86_64_PLT32: u64 = 4;
+
+struct ElfHeader {
+    e_type: u16,
+    e_machine: u16,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_phnum: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+impl ElfHeader {
+    fn write(&self, out: &mut Vec<u8>) {
+        // e_ident: magic, 64-bit, little endian, ELF version 1, System V ABI, padding
+        out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        out.extend_from_slice(&[0; 8]);
+
+        out.extend_from_slice(&self.e_type.to_le_bytes());
+        out.extend_from_slice(&self.e_machine.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        out.extend_from_slice(&self.e_entry.to_le_bytes());
+        out.extend_from_slice(&self.e_phoff.to_le_bytes());
+        out.extend_from_slice(&self.e_shoff.to_le_bytes());
+        out.extend_from_slice(&self.e_flags.to_le_bytes());
+        out.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes()); // e_ehsize
+        out.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes()); // e_phentsize
+        out.extend_from_slice(&self.e_phnum.to_le_bytes());
+        out.extend_from_slice(&(SECTION_HEADER_SIZE as u16).to_le_bytes()); // e_shentsize
+        out.extend_from_slice(&self.e_shnum.to_le_bytes());
+        out.extend_from_slice(&self.e_shstrndx.to_le_bytes());
+    }
+}
+
+struct ProgramHeader {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+impl ProgramHeader {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.p_type.to_le_bytes());
+        out.extend_from_slice(&self.p_flags.to_le_bytes());
+        out.extend_from_slice(&self.p_offset.to_le_bytes());
+        out.extend_from_slice(&self.p_vaddr.to_le_bytes());
+        out.extend_from_slice(&self.p_vaddr.to_le_bytes()); // p_paddr, same as virtual
+        out.extend_from_slice(&self.p_filesz.to_le_bytes());
+        out.extend_from_slice(&self.p_memsz.to_le_bytes());
+        out.extend_from_slice(&self.p_align.to_le_bytes());
+    }
+}
+
+#[derive(Default)]
+struct SectionHeader {
+    sh_name: u32,
+    sh_type: u32,
+    sh_flags: u64,
+    sh_addr: u64,
+    sh_offset: u64,
+    sh_size: u64,
+    sh_link: u32,
+    sh_info: u32,
+    sh_addralign: u64,
+    sh_entsize: u64,
+}
+
+impl SectionHeader {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.sh_name.to_le_bytes());
+        out.extend_from_slice(&self.sh_type.to_le_bytes());
+        out.extend_from_slice(&self.sh_flags.to_le_bytes());
+        out.extend_from_slice(&self.sh_addr.to_le_bytes());
+        out.extend_from_slice(&self.sh_offset.to_le_bytes());
+        out.extend_from_slice(&self.sh_size.to_le_bytes());
+        out.extend_from_slice(&self.sh_link.to_le_bytes());
+        out.extend_from_slice(&self.sh_info.to_le_bytes());
+        out.extend_from_slice(&self.sh_addralign.to_le_bytes());
+        out.extend_from_slice(&self.sh_entsize.to_le_bytes());
+    }
+}
+
+struct Symbol {
+    st_name: u32,
+    st_info: u8,
+    st_shndx: u16,
+    st_value: u64,
+    st_size: u64,
+}
+
+impl Symbol {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.st_name.to_le_bytes());
+        out.push(self.st_info);
+        out.push(0); // st_other, default visibility
+        out.extend_from_slice(&self.st_shndx.to_le_bytes());
+        out.extend_from_slice(&self.st_value.to_le_bytes());
+        out.extend_from_slice(&self.st_size.to_le_bytes());
+    }
+}
+
+// A string table, every name is stored null terminated and
+// referred to by its offset. Offset zero is always the empty string.
+struct StringTable(Vec<u8>);
+
+impl StringTable {
+    fn new() -> Self {
+        Self(vec![0])
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        self.0.extend_from_slice(name.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
+
+// Wrap code from the x86-64 code generator into a static executable. The file is
+// laid out as the ELF header, the program headers, a small entry stub and finally
+// the compiled program, all mapped read/execute as a single segment. The tape
+// gets a segment of its own with nothing backing it in the file (like .bss),
+// so the kernel hands it to us zeroed.
+pub fn x86_64_executable(code: &[u8]) -> Vec<u8> {
+    let program_headers = 3;
+    let stub_addr = BASE_ADDR + ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * program_headers;
+
+    // The entry stub calls the program like the JIT would, with the tape in %rdi
+    // and stdin and stdout as the file descriptors, then exits with status 0 once it returns.
+    // It's padded so the program starts on a 16 byte boundary, like JIT code does.
+    let stub_len = (stub_addr + 29).next_multiple_of(16) - stub_addr;
+    let code_offset = stub_len - 20; // relative to the end of the call
+    let text_len =
+        ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * program_headers + stub_len + code.len() as u64;
+    let tape_addr = (BASE_ADDR + text_len).next_multiple_of(PAGE_SIZE);
+
+    let mut stub: Vec<u8> = Vec::with_capacity(stub_len as usize);
+    stub.push(0xbf); // mov $<tape>, %edi
+    stub.extend_from_slice(&(tape_addr as u32).to_le_bytes());
+    stub.extend_from_slice(&[0xbe, 0x0, 0x0, 0x0, 0x0]); // mov $0, %esi (stdin)
+    stub.extend_from_slice(&[0xba, 0x01, 0x0, 0x0, 0x0]); // mov $1, %edx (stdout)
+    stub.push(0xe8); // call <program>
+    stub.extend_from_slice(&(code_offset as i32).to_le_bytes());
+    stub.extend_from_slice(&[0xb8, 0x3c, 0x0, 0x0, 0x0]); // mov $60, %eax (exit)
+    stub.extend_from_slice(&[0x31, 0xff]); // xor %edi, %edi (status 0)
+    stub.extend_from_slice(&[0x0f, 0x05]); // syscall
+    stub.resize(stub_len as usize, 0xcc); // int3, never reached
+    assert_eq!(stub.len() as u64, stub_len);
+
+    let mut out: Vec<u8> = Vec::with_capacity(text_len as usize);
+
+    ElfHeader {
+        e_type: ET_EXEC,
+        e_machine: EM_X86_64,
+        e_entry: stub_addr,
+        e_phoff: ELF_HEADER_SIZE,
+        e_shoff: 0,
+        e_flags: 0,
+        e_phnum: program_headers as u16,
+        e_shnum: 0,
+        e_shstrndx: 0,
+    }
+    .write(&mut out);
+
+    // Headers, stub and code, everything that is in the file
+    ProgramHeader {
+        p_type: PT_LOAD,
+        p_flags: PF_R | PF_X,
+        p_offset: 0,
+        p_vaddr: BASE_ADDR,
+        p_filesz: text_len,
+        p_memsz: text_len,
+        p_align: PAGE_SIZE,
+    }
+    .write(&mut out);
+
+    // The tape, only exists in memory
+    ProgramHeader {
+        p_type: PT_LOAD,
+        p_flags: PF_R | PF_W,
+        p_offset: 0,
+        p_vaddr: tape_addr,
+        p_filesz: 0,
+        p_memsz: TAPE_LEN,
+        p_align: PAGE_SIZE,
+    }
+    .write(&mut out);
+
+    // Ask for a non-executable stack, nothing here needs one
+    ProgramHeader {
+        p_type: PT_GNU_STACK,
+        p_flags: PF_R | PF_W,
+        p_offset: 0,
+        p_vaddr: 0,
+        p_filesz: 0,
+        p_memsz: 0,
+        p_align: 16,
+    }
+    .write(&mut out);
+
+    out.extend_from_slice(&stub);
+    out.extend_from_slice(code);
+
+    out
+}
+
+// Wrap code from the x86-64 code generator into a relocatable object file, exporting
+// the code as a function called `symbol` with the C signature
+//
+//     uint8_t *symbol(uint8_t *tape);
+//
+// Any external functions the code calls end up as undefined symbols, with one
+// relocation for each call site, so whatever the object gets linked with supplies them.
+pub fn x86_64_object(code: &[u8], relocations: &[Relocation], symbol: &str) -> Vec<u8> {
+    // Section indices, in the order their headers are written
+    const TEXT: u16 = 1;
+    const RELA_TEXT: u32 = 2;
+    const SYMTAB: u32 = 3;
+    const STRTAB: u32 = 4;
+    const SHSTRTAB: u16 = 5;
+    const SECTION_COUNT: u16 = 7;
+
+    let mut shstrtab = StringTable::new();
+    let text_name = shstrtab.add(".text");
+    let rela_text_name = shstrtab.add(".rela.text");
+    let symtab_name = shstrtab.add(".symtab");
+    let strtab_name = shstrtab.add(".strtab");
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    let note_stack_name = shstrtab.add(".note.GNU-stack");
+
+    // Locals have to come before globals in the symbol table, so the null symbol
+    // and the section symbol go first, then our function, then the externals
+    let mut strtab = StringTable::new();
+    let mut symbols = vec![
+        Symbol {
+            st_name: 0,
+            st_info: 0,
+            st_shndx: 0,
+            st_value: 0,
+            st_size: 0,
+        },
+        Symbol {
+            st_name: 0,
+            st_info: (STB_LOCAL << 4) | STT_SECTION,
+            st_shndx: TEXT,
+            st_value: 0,
+            st_size: 0,
+        },
+    ];
+    let first_global = symbols.len() as u32;
+
+    symbols.push(Symbol {
+        st_name: strtab.add(symbol),
+        st_info: (STB_GLOBAL << 4) | STT_FUNC,
+        st_shndx: TEXT,
+        st_value: 0,
+        st_size: code.len() as u64,
+    });
+
+    let mut externals: Vec<&str> = vec![];
+    for relocation in relocations {
+        if !externals.contains(&relocation.symbol) {
+            externals.push(relocation.symbol);
+            symbols.push(Symbol {
+                st_name: strtab.add(relocation.symbol),
+                st_info: (STB_GLOBAL << 4) | STT_NOTYPE,
+                st_shndx: 0, // SHN_UNDEF
+                st_value: 0,
+                st_size: 0,
+            });
+        }
+    }
+
+    // Section contents follow the ELF header back to back, then the section headers
+    let mut out: Vec<u8> = vec![0; ELF_HEADER_SIZE as usize];
+
+    let text_offset = out.len() as u64;
+    out.extend_from_slice(code);
+
+    out.resize(out.len().next_multiple_of(8), 0);
+
+    // Every call site wants the distance from the end of the call to the
+    // function, hence the -4 for the operand itself
+    let rela_offset = out.len() as u64;
+    for relocation in relocations {
+        let symbol_index = first_global
+            + 1
+            + externals
+                .iter()
+                .position(|&name| name == relocation.symbol)
+                .unwrap() as u32;
+
+        out.extend_from_slice(&(relocation.offset as u64).to_le_bytes()); // r_offset
+        out.extend_from_slice(&(((symbol_index as u64) << 32) | R_X86_64_PLT32).to_le_bytes()); // r_info
+        out.extend_from_slice(&(-4i64).to_le_bytes()); // r_addend
+    }
+
+    let symtab_offset = out.len() as u64;
+    symbols.iter().for_each(|symbol| symbol.write(&mut out));
+
+    let strtab_offset = out.len() as u64;
+    out.extend_from_slice(&strtab.0);
+
+    let shstrtab_offset = out.len() as u64;
+    out.extend_from_slice(&shstrtab.0);
+
+    out.resize(out.len().next_multiple_of(8), 0);
+
+    let section_headers_offset = out.len() as u64;
+
+    let mut header = vec![];
+    ElfHeader {
+        e_type: ET_REL,
+        e_machine: EM_X86_64,
+        e_entry: 0,
+        e_phoff: 0,
+        e_shoff: section_headers_offset,
+        e_flags: 0,
+        e_phnum: 0,
+        e_shnum: SECTION_COUNT,
+        e_shstrndx: SHSTRTAB,
+    }
+    .write(&mut header);
+    out[..ELF_HEADER_SIZE as usize].copy_from_slice(&header);
+
+    let sections = [
+        // The mandatory null section
+        SectionHeader::default(),
+        SectionHeader {
+            sh_name: text_name,
+            sh_type: SHT_PROGBITS,
+            sh_flags: SHF_ALLOC | SHF_EXECINSTR,
+            sh_offset: text_offset,
+            sh_size: code.len() as u64,
+            sh_addralign: 16,
+            ..Default::default()
+        },
+        SectionHeader {
+            sh_name: rela_text_name,
+            sh_type: SHT_RELA,
+            sh_flags: SHF_INFO_LINK,
+            sh_offset: rela_offset,
+            sh_size: relocations.len() as u64 * RELA_SIZE,
+            sh_link: SYMTAB,
+            sh_info: TEXT as u32,
+            sh_addralign: 8,
+            sh_entsize: RELA_SIZE,
+            ..Default::default()
+        },
+        SectionHeader {
+            sh_name: symtab_name,
+            sh_type: SHT_SYMTAB,
+            sh_offset: symtab_offset,
+            sh_size: symbols.len() as u64 * SYMBOL_SIZE,
+            sh_link: STRTAB,
+            sh_info: first_global,
+            sh_addralign: 8,
+            sh_entsize: SYMBOL_SIZE,
+            ..Default::default()
+        },
+        SectionHeader {
+            sh_name: strtab_name,
+            sh_type: SHT_STRTAB,
+            sh_offset: strtab_offset,
+            sh_size: strtab.0.len() as u64,
+            sh_addralign: 1,
+            ..Default::default()
+        },
+        SectionHeader {
+            sh_name: shstrtab_name,
+            sh_type: SHT_STRTAB,
+            sh_offset: shstrtab_offset,
+            sh_size: shstrtab.0.len() as u64,
+            sh_addralign: 1,
+            ..Default::default()
+        },
+        // An empty marker section telling the linker we don't need an executable stack
+        SectionHeader {
+            sh_name: note_stack_name,
+            sh_type: SHT_PROGBITS,
+            sh_offset: shstrtab_offset,
+            sh_addralign: 1,
+            ..Default::default()
+        },
+    ];
+
+    assert_eq!(sections.len(), SECTION_COUNT as usize);
+    sections.iter().for_each(|section| section.write(&mut out));
+
+    out
+}
+
+// Describe code that is already sitting in memory at `addr`, for a debugger rather
+// than a linker or loader. The result is a relocatable object whose .text takes up
+// no space in the file (like .bss) but says where the code lives, with the code
+// exported as `symbol`, and `debug_sections` copied in as they are. Those hold
+// addresses in the process already, so there is nothing to relocate.
+pub fn symbol_file(
+    arch: Arch,
+    addr: u64,
+    code_len: u64,
+    symbol: &str,
+    source: &str,
+    debug_sections: &[(&str, &[u8])],
+) -> Vec<u8> {
+    // Section indices, in the order their headers are written, debug sections go last
+    const TEXT: u16 = 1;
+    const SYMTAB: u32 = 2;
+    const STRTAB: u32 = 3;
+    const SHSTRTAB: u16 = 4;
+    let section_count = 5 + debug_sections.len() as u16;
+
+    let (e_machine, e_flags) = match arch {
+        Arch::X86_64 => (EM_X86_64, 0),
+        Arch::Riscv64 => (EM_RISCV, EF_RISCV_FLOAT_ABI_DOUBLE),
+        Arch::Aarch64 => (EM_AARCH64, 0),
+    };
+
+    let mut shstrtab = StringTable::new();
+    let text_name = shstrtab.add(".text");
+    let symtab_name = shstrtab.add(".symtab");
+    let strtab_name = shstrtab.add(".strtab");
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    let debug_names: Vec<u32> = debug_sections
+        .iter()
+        .map(|(name, _)| shstrtab.add(name))
+        .collect();
+
+    // The source file as a local symbol, then the function itself. Symbol values
+    // are relative to their section, which the section's address takes care of.
+    let mut strtab = StringTable::new();
+    let symbols = [
+        Symbol {
+            st_name: 0,
+            st_info: 0,
+            st_shndx: 0,
+            st_value: 0,
+            st_size: 0,
+        },
+        Symbol {
+            st_name: strtab.add(source),
+            st_info: (STB_LOCAL << 4) | STT_FILE,
+            st_shndx: SHN_ABS,
+            st_value: 0,
+            st_size: 0,
+        },
+        Symbol {
+            st_name: strtab.add(symbol),
+            st_info: (STB_GLOBAL << 4) | STT_FUNC,
+            st_shndx: TEXT,
+            st_value: 0,
+            st_size: code_len,
+        },
+    ];
+    let first_global = 2;
+
+    let mut out: Vec<u8> = vec![0; ELF_HEADER_SIZE as usize];
+
+    let symtab_offset = out.len() as u64;
+    symbols.iter().for_each(|symbol| symbol.write(&mut out));
+
+    let strtab_offset = out.len() as u64;
+    out.extend_from_slice(&strtab.0);
+
+    let shstrtab_offset = out.len() as u64;
+    out.extend_from_slice(&shstrtab.0);
+
+    let mut debug_offsets = vec![];
+    for (_, contents) in debug_sections {
+        debug_offsets.push(out.len() as u64);
+        out.extend_from_slice(contents);
+    }
+
+    out.resize(out.len().next_multiple_of(8), 0);
+
+    let section_headers_offset = out.len() as u64;
+
+    let mut header = vec![];
+    ElfHeader {
+        e_type: ET_REL,
+        e_machine,
+        e_entry: 0,
+        e_phoff: 0,
+        e_shoff: section_headers_offset,
+        e_flags,
+        e_phnum: 0,
+        e_shnum: section_count,
+        e_shstrndx: SHSTRTAB,
+    }
+    .write(&mut header);
+    out[..ELF_HEADER_SIZE as usize].copy_from_slice(&header);
+
+    let mut sections = vec![
+        // The mandatory null section
+        SectionHeader::default(),
+        SectionHeader {
+            sh_name: text_name,
+            sh_type: SHT_NOBITS,
+            sh_flags: SHF_ALLOC | SHF_EXECINSTR,
+            sh_addr: addr,
+            sh_size: code_len,
+            sh_addralign: 16,
+            ..Default::default()
+        },
+        SectionHeader {
+            sh_name: symtab_name,
+            sh_type: SHT_SYMTAB,
+            sh_offset: symtab_offset,
+            sh_size: symbols.len() as u64 * SYMBOL_SIZE,
+            sh_link: STRTAB,
+            sh_info: first_global,
+            sh_addralign: 8,
+            sh_entsize: SYMBOL_SIZE,
+            ..Default::default()
+        },
+        SectionHeader {
+            sh_name: strtab_name,
+            sh_type: SHT_STRTAB,
+            sh_offset: strtab_offset,
+            sh_size: strtab.0.len() as u64,
+            sh_addralign: 1,
+            ..Default::default()
+        },
+        SectionHeader {
+            sh_name: shstrtab_name,
+            sh_type: SHT_STRTAB,
+            sh_offset: shstrtab_offset,
+            sh_size: shstrtab.0.len() as u64,
+            sh_addralign: 1,
+            ..Default::default()
+        },
+    ];
+
+    for ((name, offset), (_, contents)) in debug_names
+        .into_iter()
+        .zip(debug_offsets)
+        .zip(debug_sections)
+    {
+        sections.push(SectionHeader {
+            sh_name: name,
+            sh_type: SHT_PROGBITS,
+            sh_offset: offset,
+            sh_size: contents.len() as u64,
+            sh_addralign: 1,
+            ..Default::default()
+        });
+    }
+
+    assert_eq!(sections.len(), section_count as usize);
+    sections.iter().for_each(|section| section.write(&mut out));
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck::{interpreter::Interpreter, ir::IR, jit::x86_64_linux, program::Program};
+
+    fn u16_at(bytes: &[u8], at: u64) -> u16 {
+        u16::from_le_bytes(bytes[at as usize..at as usize + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], at: u64) -> u32 {
+        u32::from_le_bytes(bytes[at as usize..at as usize + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], at: u64) -> u64 {
+        u64::from_le_bytes(bytes[at as usize..at as usize + 8].try_into().unwrap())
+    }
+
+    // Check the parts of the ELF header every file we write shares
+    fn check_ident(file: &[u8], e_type: u16) {
+        assert_eq!(file[..8], [0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        assert_eq!(u16_at(file, 16), e_type);
+        assert_eq!(u16_at(file, 18), EM_X86_64);
+        assert_eq!(u32_at(file, 20), 1); // e_version
+        assert_eq!(u16_at(file, 52), ELF_HEADER_SIZE as u16);
+    }
+
+    // The fields of a section header we check, along with its name
+    #[derive(Debug)]
+    struct Section {
+        name: String,
+        sh_name: u32,
+        sh_type: u32,
+        sh_flags: u64,
+        sh_offset: u64,
+        sh_size: u64,
+        sh_link: u32,
+        sh_info: u32,
+        sh_entsize: u64,
+    }
+
+    impl Section {
+        fn contents<'a>(&self, file: &'a [u8]) -> &'a [u8] {
+            &file[self.sh_offset as usize..(self.sh_offset + self.sh_size) as usize]
+        }
+    }
+
+    // The null terminated string at `offset` in a string table
+    fn string_at(table: &[u8], offset: u32) -> String {
+        let name = &table[offset as usize..];
+        let len = name.iter().position(|&byte| byte == 0).unwrap();
+
+        String::from_utf8(name[..len].to_vec()).unwrap()
+    }
+
+    // Every section header in a file, named through the section name string table
+    fn sections(file: &[u8]) -> Vec<Section> {
+        let shoff = u64_at(file, 40);
+        assert_eq!(u16_at(file, 58), SECTION_HEADER_SIZE as u16);
+        let shnum = u16_at(file, 60) as u64;
+        let shstrndx = u16_at(file, 62) as u64;
+
+        let mut sections: Vec<Section> = (0..shnum)
+            .map(|idx| {
+                let at = shoff + idx * SECTION_HEADER_SIZE;
+                Section {
+                    name: String::new(),
+                    sh_name: u32_at(file, at),
+                    sh_type: u32_at(file, at + 4),
+                    sh_flags: u64_at(file, at + 8),
+                    sh_offset: u64_at(file, at + 24),
+                    sh_size: u64_at(file, at + 32),
+                    sh_link: u32_at(file, at + 40),
+                    sh_info: u32_at(file, at + 44),
+                    sh_entsize: u64_at(file, at + 56),
+                }
+            })
+            .collect();
+
+        let names = sections[shstrndx as usize].contents(file).to_vec();
+        for section in &mut sections {
+            section.name = string_at(&names, section.sh_name);
+        }
+
+        sections
+    }
+
+    fn compile(source: &str) -> Vec<u8> {
+        x86_64_linux::compile(IR::from(Program::new(source))).unwrap()
+    }
+
+    #[test]
+    fn executable_layout() {
+        let code = compile(include_str!("../../test_programs/hello_world.bf"));
+        let exe = x86_64_executable(&code);
+        check_ident(&exe, ET_EXEC);
+
+        let entry = u64_at(&exe, 24);
+        assert_eq!(u64_at(&exe, 32), ELF_HEADER_SIZE); // e_phoff
+        assert_eq!(u64_at(&exe, 40), 0); // e_shoff
+        assert_eq!(u16_at(&exe, 54), PROGRAM_HEADER_SIZE as u16);
+        assert_eq!(u16_at(&exe, 56), 3); // e_phnum
+        assert_eq!(u16_at(&exe, 60), 0); // e_shnum
+
+        // p_type, p_flags, p_offset, p_vaddr, p_filesz, p_memsz and p_align of every segment
+        let segments: Vec<[u64; 7]> = (0..3)
+            .map(|idx| {
+                let at = ELF_HEADER_SIZE + idx * PROGRAM_HEADER_SIZE;
+                [
+                    u32_at(&exe, at) as u64,
+                    u32_at(&exe, at + 4) as u64,
+                    u64_at(&exe, at + 8),
+                    u64_at(&exe, at + 16),
+                    u64_at(&exe, at + 32),
+                    u64_at(&exe, at + 40),
+                    u64_at(&exe, at + 48),
+                ]
+            })
+            .collect();
+
+        // The whole file, mapped where it says it is, with the entry stub straight
+        // after the headers
+        let len = exe.len() as u64;
+        let rx = (PF_R | PF_X) as u64;
+        let rw = (PF_R | PF_W) as u64;
+        assert_eq!(segments[0], [1, rx, 0, BASE_ADDR, len, len, PAGE_SIZE]);
+        assert_eq!(entry, BASE_ADDR + ELF_HEADER_SIZE + 3 * PROGRAM_HEADER_SIZE);
+
+        // The tape, zeroed memory on the first page past the file
+        let tape = (BASE_ADDR + len).next_multiple_of(PAGE_SIZE);
+        assert_eq!(segments[1], [1, rw, 0, tape, 0, TAPE_LEN, PAGE_SIZE]);
+
+        assert_eq!(segments[2], [PT_GNU_STACK as u64, rw, 0, 0, 0, 0, 16]);
+
+        // The stub hands the program the tape and stdin/stdout, then exits with status 0
+        let stub = (entry - BASE_ADDR) as usize;
+        assert_eq!(exe[stub], 0xbf); // mov $<tape>, %edi
+        assert_eq!(u32_at(&exe, stub as u64 + 1) as u64, tape);
+        assert_eq!(
+            exe[stub + 5..stub + 15],
+            [0xbe, 0, 0, 0, 0, 0xba, 1, 0, 0, 0]
+        );
+        assert_eq!(exe[stub + 15], 0xe8); // call <program>
+        assert_eq!(
+            exe[stub + 20..stub + 29],
+            [0xb8, 0x3c, 0, 0, 0, 0x31, 0xff, 0x0f, 0x05]
+        );
+
+        // The call lands on the program, padded out to a 16 byte boundary with int3s
+        let program = stub + 20 + u32_at(&exe, stub as u64 + 16) as usize;
+        assert_eq!((BASE_ADDR as usize + program) % 16, 0);
+        assert!(exe[stub + 29..program].iter().all(|&byte| byte == 0xcc));
+        assert!(program - (stub + 29) < 16);
+        assert_eq!(exe[program..], code);
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn executables_run() {
+        use std::{env, fs, io, os::unix::fs::PermissionsExt, process, thread, time::Duration};
+
+        let source = include_str!("../../test_programs/hello_world.bf");
+        let mut expected = vec![];
+        Interpreter::run_with_io(&Program::new(source), || 0, |byte| expected.push(byte));
+
+        let path = env::temp_dir().join(format!("brainrust-exe-{}", process::id()));
+        fs::write(&path, x86_64_executable(&compile(source))).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        // Other tests fork, and a child that happens to inherit the file while it's
+        // still open for writing keeps it busy until the child is done
+        let output = loop {
+            match process::Command::new(&path).output() {
+                Err(err) if err.kind() == io::ErrorKind::ExecutableFileBusy => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                result => break result.unwrap(),
+            }
+        };
+        fs::remove_file(&path).unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(output.stdout, expected);
+    }
+
+    #[test]
+    fn object_layout() {
+        let options = x86_64_linux::Options {
+            io: x86_64_linux::IoMode::LibcCall,
+            ..Default::default()
+        };
+        let (code, relocations, _) =
+            x86_64_linux::compile_with(IR::from(Program::new(",>.<.")), options).unwrap();
+        let object = x86_64_object(&code, &relocations, "bf_main");
+        check_ident(&object, ET_REL);
+
+        let sections = sections(&object);
+        let names: Vec<&str> = sections
+            .iter()
+            .map(|section| section.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "",
+                ".text",
+                ".rela.text",
+                ".symtab",
+                ".strtab",
+                ".shstrtab",
+                ".note.GNU-stack"
+            ]
+        );
+
+        let text = &sections[1];
+        assert_eq!(text.sh_type, SHT_PROGBITS);
+        assert_eq!(text.sh_flags, SHF_ALLOC | SHF_EXECINSTR);
+        assert_eq!(text.contents(&object), code);
+
+        // Locals first, sh_info says where the globals start
+        let symtab = &sections[3];
+        assert_eq!((symtab.sh_type, symtab.sh_link), (SHT_SYMTAB, 4));
+        assert_eq!((symtab.sh_info, symtab.sh_entsize), (2, SYMBOL_SIZE));
+
+        // Name, binding and type, section and size of every symbol
+        let strtab = sections[4].contents(&object);
+        let symbols: Vec<(String, u8, u16, u64)> = symtab
+            .contents(&object)
+            .chunks_exact(SYMBOL_SIZE as usize)
+            .map(|symbol| {
+                (
+                    string_at(strtab, u32_at(symbol, 0)),
+                    symbol[4],
+                    u16_at(symbol, 6),
+                    u64_at(symbol, 16),
+                )
+            })
+            .collect();
+        let global = |kind| (STB_GLOBAL << 4) | kind;
+        assert_eq!(
+            symbols,
+            [
+                ("".into(), 0, 0, 0),
+                ("".into(), (STB_LOCAL << 4) | STT_SECTION, 1, 0),
+                ("bf_main".into(), global(STT_FUNC), 1, code.len() as u64),
+                ("getchar".into(), global(STT_NOTYPE), 0, 0),
+                ("putchar".into(), global(STT_NOTYPE), 0, 0),
+            ]
+        );
+
+        // One R_X86_64_PLT32 per call, against the symbol of the function called,
+        // with the -4 that makes up for the operand itself
+        let rela_text = &sections[2];
+        assert_eq!(
+            (rela_text.sh_type, rela_text.sh_flags),
+            (SHT_RELA, SHF_INFO_LINK)
+        );
+        assert_eq!((rela_text.sh_link, rela_text.sh_info), (3, 1));
+        assert_eq!(rela_text.sh_entsize, RELA_SIZE);
+
+        let relas: Vec<(u64, u64, u64, i64)> = rela_text
+            .contents(&object)
+            .chunks_exact(RELA_SIZE as usize)
+            .map(|rela| {
+                let info = u64_at(rela, 8);
+                (
+                    u64_at(rela, 0),
+                    info >> 32,
+                    info & 0xffff_ffff,
+                    u64_at(rela, 16) as i64,
+                )
+            })
+            .collect();
+        let expected: Vec<(u64, u64, u64, i64)> = relocations
+            .iter()
+            .map(|relocation| {
+                let symbol = if relocation.symbol == "getchar" { 3 } else { 4 };
+                (relocation.offset as u64, symbol, R_X86_64_PLT32, -4)
+            })
+            .collect();
+        assert_eq!(relocations.len(), 3);
+        assert_eq!(relas, expected);
+
+        // Calls are e8 followed by the operand being relocated
+        for relocation in &relocations {
+            assert_eq!(code[relocation.offset - 1], 0xe8);
+        }
+
+        // An empty, non-executable marker
+        let note = &sections[6];
+        assert_eq!(
+            (note.sh_type, note.sh_flags, note.sh_size),
+            (SHT_PROGBITS, 0, 0)
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use crate::brainfuck::ir::IRInsn;
+use std::fmt::Write;
+
+// Transpile Brainfuck IR into a self-contained C program, with the same
+// 30,000 cell tape of bytes as the interpreter. Reading past the end of input
+// stores EOF truncated to a byte, again same as the interpreter.
+pub fn emit(ir: impl IntoIterator<Item = IRInsn>) -> Result<String, ()> {
+    let mut src = String::new();
+
+    writeln!(src, "#include <stdio.h>").unwrap();
+    writeln!(src).unwrap();
+    writeln!(src, "static unsigned char tape[30000];").unwrap();
+    writeln!(src).unwrap();
+    writeln!(src, "int main(void) {{").unwrap();
+    writeln!(src, "    unsigned char *ptr = tape;").unwrap();
+    writeln!(src).unwrap();
+
+    // Nesting depth of loops, one level of indentation each
+    let mut depth = 1usize;
+
+    for insn in ir {
+        if insn == IRInsn::JumpIfNonZero {
+            depth = depth.checked_sub(1).filter(|&depth| depth > 0).ok_or(())?;
+        }
+
+        let indent = "    ".repeat(depth);
+
+        match insn {
+            IRInsn::IncPtr(amount) => writeln!(src, "{indent}ptr += {amount};"),
+            IRInsn::DecPtr(amount) => writeln!(src, "{indent}ptr -= {amount};"),
+            IRInsn::IncVal(amount) => writeln!(src, "{indent}*ptr += {amount};"),
+            IRInsn::DecVal(amount) => writeln!(src, "{indent}*ptr -= {amount};"),
+            IRInsn::JumpIfZero => writeln!(src, "{indent}while (*ptr) {{"),
+            IRInsn::JumpIfNonZero => writeln!(src, "{indent}}}"),
+            IRInsn::GetChar => writeln!(src, "{indent}*ptr = getchar();"),
+            IRInsn::PutChar => writeln!(src, "{indent}putchar(*ptr);"),
+        }
+        .unwrap();
+
+        if insn == IRInsn::JumpIfZero {
+            depth += 1;
+        }
+    }
+
+    // Any loop left open means unbalanced brackets
+    if depth != 1 {
+        return Err(());
+    }
+
+    writeln!(src).unwrap();
+    writeln!(src, "    return 0;").unwrap();
+    writeln!(src, "}}").unwrap();
+
+    Ok(src)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck::{emit::toolchain, ir::IR, program::Program};
+    use std::{fs, process::Command};
+
+    fn transpile(source: &str) -> Result<String, ()> {
+        emit(IR::from(Program::new(source)))
+    }
+
+    #[test]
+    fn hello_world() {
+        assert_eq!(
+            transpile(include_str!("../../../test_programs/hello_world.bf")).unwrap(),
+            include_str!("../../../test_programs/hello_world.c")
+        );
+    }
+
+    // Every program compiles without a warning, and prints the same as in the interpreter
+    #[test]
+    fn programs_compile_and_run() {
+        let Some(cc) = toolchain::find("CC", "cc") else {
+            return;
+        };
+
+        let dir = toolchain::scratch_dir("c");
+        let (src, exe) = (dir.join("program.c"), dir.join("program"));
+
+        for (source, input) in toolchain::programs() {
+            fs::write(&src, transpile(&source).unwrap()).unwrap();
+
+            let status = Command::new(&cc)
+                .args(["-Wall", "-Werror", "-o"])
+                .arg(&exe)
+                .arg(&src)
+                .status()
+                .unwrap();
+            assert!(status.success());
+
+            let output = toolchain::output(&mut Command::new(&exe), input);
+            assert!(output == toolchain::interpreted(&source, input));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unbalanced_brackets() {
+        use IRInsn::*;
+
+        // Programs are checked for balance when parsed, so build the IR by hand
+        assert!(emit([JumpIfZero, JumpIfZero, JumpIfNonZero]).is_err());
+        assert!(emit([JumpIfZero, JumpIfNonZero, JumpIfNonZero]).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::brainfuck::ir::IRInsn;
+use std::fmt::Write;
+
+// Move the tape pointer by `offset` cells, either way
+fn emit_pointer_move(src: &mut String, next_value: &mut impl FnMut() -> String, offset: i64) {
+    let (old, new) = (next_value(), next_value());
+
+    writeln!(src, "  {old} = load ptr, ptr %ptr").unwrap();
+    writeln!(src, "  {new} = getelementptr i8, ptr {old}, i64 {offset}").unwrap();
+    writeln!(src, "  store ptr {new}, ptr %ptr").unwrap();
+}
+
+// Apply `op`, add or sub, to the current cell
+fn emit_value_op(src: &mut String, next_value: &mut impl FnMut() -> String, op: &str, amount: u8) {
+    let (cell, old, new) = (next_value(), next_value(), next_value());
+
+    writeln!(src, "  {cell} = load ptr, ptr %ptr").unwrap();
+    writeln!(src, "  {old} = load i8, ptr {cell}").unwrap();
+    writeln!(src, "  {new} = {op} i8 {old}, {amount}").unwrap();
+    writeln!(src, "  store i8 {new}, ptr {cell}").unwrap();
+}
+
+// Transpile Brainfuck IR into textual LLVM IR, for running through opt and llc
+// to compare a production optimiser against our own passes. The program becomes
+//
+//     define ptr @bf_main(ptr %tape)
+//
+// the same function `--emit obj` exports, plus a main handing it a zeroed
+// 30,000 cell global tape. Pointers are opaque, as LLVM 17 onwards requires.
+// LLVM 15 and 16 take those by default too, 14 only with -opaque-pointers.
+//
+// The tape pointer lives in a stack slot, loaded and stored around every
+// instruction, which keeps the emitter trivial. mem2reg turns it into
+// proper SSA values, so run opt before judging the output.
+pub fn emit(ir: impl IntoIterator<Item = IRInsn>) -> Result<String, ()> {
+    let mut src = String::new();
+
+    writeln!(src, "@tape = internal global [30000 x i8] zeroinitializer").unwrap();
+    writeln!(src).unwrap();
+    writeln!(src, "declare i32 @getchar()").unwrap();
+    writeln!(src, "declare i32 @putchar(i32)").unwrap();
+    writeln!(src).unwrap();
+    writeln!(src, "define ptr @bf_main(ptr %tape) {{").unwrap();
+    writeln!(src, "entry:").unwrap();
+    writeln!(src, "  %ptr = alloca ptr").unwrap();
+    writeln!(src, "  store ptr %tape, ptr %ptr").unwrap();
+
+    // Every value needs a unique name, as does every loop's basic blocks
+    let mut value = 0usize;
+    let mut next_value = || {
+        value += 1;
+        format!("%v{value}")
+    };
+    let mut loops = 0usize;
+    let mut open_loops: Vec<usize> = vec![];
+
+    for insn in ir {
+        match insn {
+            IRInsn::IncPtr(amount) => emit_pointer_move(&mut src, &mut next_value, amount as i64),
+
+            IRInsn::DecPtr(amount) => {
+                emit_pointer_move(&mut src, &mut next_value, -(amount as i64))
+            }
+
+            IRInsn::IncVal(amount) => emit_value_op(&mut src, &mut next_value, "add", amount),
+
+            IRInsn::DecVal(amount) => emit_value_op(&mut src, &mut next_value, "sub", amount),
+
+            IRInsn::JumpIfZero => {
+                let n = loops;
+                loops += 1;
+                open_loops.push(n);
+                let (cell, val, is_zero) = (next_value(), next_value(), next_value());
+
+                writeln!(src, "  br label %loop{n}").unwrap();
+                writeln!(src).unwrap();
+                writeln!(src, "loop{n}:").unwrap();
+                writeln!(src, "  {cell} = load ptr, ptr %ptr").unwrap();
+                writeln!(src, "  {val} = load i8, ptr {cell}").unwrap();
+                writeln!(src, "  {is_zero} = icmp eq i8 {val}, 0").unwrap();
+                writeln!(src, "  br i1 {is_zero}, label %end{n}, label %body{n}").unwrap();
+                writeln!(src).unwrap();
+                writeln!(src, "body{n}:").unwrap();
+            }
+
+            IRInsn::JumpIfNonZero => {
+                let n = open_loops.pop().ok_or(())?;
+
+                writeln!(src, "  br label %loop{n}").unwrap();
+                writeln!(src).unwrap();
+                writeln!(src, "end{n}:").unwrap();
+            }
+
+            IRInsn::GetChar => {
+                let (cell, char, byte) = (next_value(), next_value(), next_value());
+
+                writeln!(src, "  {cell} = load ptr, ptr %ptr").unwrap();
+                writeln!(src, "  {char} = call i32 @getchar()").unwrap();
+                writeln!(src, "  {byte} = trunc i32 {char} to i8").unwrap();
+                writeln!(src, "  store i8 {byte}, ptr {cell}").unwrap();
+            }
+
+            IRInsn::PutChar => {
+                let (cell, byte, char) = (next_value(), next_value(), next_value());
+
+                writeln!(src, "  {cell} = load ptr, ptr %ptr").unwrap();
+                writeln!(src, "  {byte} = load i8, ptr {cell}").unwrap();
+                writeln!(src, "  {char} = zext i8 {byte} to i32").unwrap();
+                writeln!(src, "  call i32 @putchar(i32 {char})").unwrap();
+            }
+        }
+    }
+
+    // Any loop left open means unbalanced brackets
+    if !open_loops.is_empty() {
+        return Err(());
+    }
+
+    let end = next_value();
+    writeln!(src, "  {end} = load ptr, ptr %ptr").unwrap();
+    writeln!(src, "  ret ptr {end}").unwrap();
+    writeln!(src, "}}").unwrap();
+    writeln!(src).unwrap();
+    writeln!(src, "define i32 @main() {{").unwrap();
+    writeln!(src, "entry:").unwrap();
+    writeln!(src, "  call ptr @bf_main(ptr @tape)").unwrap();
+    writeln!(src, "  ret i32 0").unwrap();
+    writeln!(src, "}}").unwrap();
+
+    Ok(src)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck::{emit::toolchain, ir::IR, program::Program};
+    use std::{fs, process::Command};
+
+    fn transpile(source: &str) -> Result<String, ()> {
+        emit(IR::from(Program::new(source)))
+    }
+
+    #[test]
+    fn hello_world() {
+        assert_eq!(
+            transpile(include_str!("../../../test_programs/hello_world.bf")).unwrap(),
+            include_str!("../../../test_programs/hello_world.ll")
+        );
+    }
+
+    // Every program prints the same under lli as in the interpreter. LLVM 14 only
+    // takes opaque pointers when asked to.
+    #[test]
+    fn programs_run_under_lli() {
+        let Some(lli) = toolchain::find("LLI", "lli") else {
+            return;
+        };
+        let version = Command::new(&lli).arg("--version").output().unwrap().stdout;
+        let major: u32 = String::from_utf8_lossy(&version)
+            .split("LLVM version ")
+            .nth(1)
+            .and_then(|version| version.split('.').next()?.parse().ok())
+            .unwrap();
+
+        let dir = toolchain::scratch_dir("llvm");
+        let module = dir.join("program.ll");
+
+        for (source, input) in toolchain::programs() {
+            fs::write(&module, transpile(&source).unwrap()).unwrap();
+
+            let mut lli = Command::new(&lli);
+            if major < 15 {
+                lli.arg("-opaque-pointers");
+            }
+            lli.arg(&module);
+
+            let output = toolchain::output(&mut lli, input);
+            assert!(output == toolchain::interpreted(&source, input));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unbalanced_brackets() {
+        use IRInsn::*;
+
+        assert!(emit([JumpIfZero, JumpIfZero, JumpIfNonZero]).is_err());
+        assert!(emit([JumpIfZero, JumpIfNonZero, JumpIfNonZero]).is_err());
+    }
+}
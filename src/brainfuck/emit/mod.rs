@@ -0,0 +1,8 @@
+// Backends turning IR into source code for another language, rather than
+// machine code, so programs can go wherever that language's compiler goes.
+pub mod c;
+pub mod llvm;
+pub mod rust;
+#[cfg(test)]
+mod toolchain;
+pub mod wasm;
@@ -0,0 +1,257 @@
+use crate::brainfuck::ir::IRInsn;
+use std::fmt::Write;
+
+// The integer type of each cell, wrapping on overflow
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Cell {
+    U8,
+    U16,
+    U32,
+}
+
+impl Cell {
+    fn name(self) -> &'static str {
+        match self {
+            Cell::U8 => "u8",
+            Cell::U16 => "u16",
+            Cell::U32 => "u32",
+        }
+    }
+}
+
+// What reading from `input` once it has run dry does to the cell
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Eof {
+    // Leave it as it was, what the JIT's read(2) does, and so auto mode too
+    Unchanged,
+    Zero,
+    // The cell type's maximum, which for bytes is what the interpreter stores,
+    // C's getchar(3) returning EOF truncated to one
+    Max,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub cell: Cell,
+    pub eof: Eof,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            cell: Cell::U8,
+            eof: Eof::Unchanged,
+        }
+    }
+}
+
+const PRELUDE: &str = "\
+// Programs that never read, write or move leave parts of this unused
+#![allow(unused)]
+
+use std::io::{self, Read, Write};
+";
+
+// Transpile Brainfuck IR into a Rust module exposing
+//
+//     pub fn run(tape: &mut [Cell], input: &mut impl Read, output: &mut impl Write) -> io::Result<()>
+//
+// where Cell is whichever integer type the options ask for, wrapping on overflow.
+// Bytes read go into a cell as they are, and cells are written out truncated to
+// a byte. Every tape access is bounds checked, so a program walking off either end
+// of the tape panics rather than touching memory it shouldn't.
+pub fn emit(ir: impl IntoIterator<Item = IRInsn>, options: Options) -> Result<String, ()> {
+    let cell = options.cell.name();
+    let byte = match options.cell {
+        Cell::U8 => "tape[ptr]",
+        _ => "tape[ptr] as u8",
+    };
+    let mut src = String::new();
+
+    writeln!(src, "// Generated by brainrust, edit the Brainfuck instead").unwrap();
+    writeln!(src).unwrap();
+    writeln!(src, "{PRELUDE}").unwrap();
+
+    writeln!(
+        src,
+        "fn get_char(input: &mut impl Read, cell: &mut {cell}) -> io::Result<()> {{"
+    )
+    .unwrap();
+    writeln!(src, "    let mut byte = [0u8];").unwrap();
+    writeln!(src).unwrap();
+    writeln!(src, "    match input.read_exact(&mut byte) {{").unwrap();
+    writeln!(src, "        Ok(()) => *cell = byte[0].into(),").unwrap();
+    match options.eof {
+        Eof::Unchanged => writeln!(
+            src,
+            "        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {{}}"
+        ),
+        Eof::Zero => writeln!(
+            src,
+            "        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => *cell = 0,"
+        ),
+        Eof::Max => writeln!(
+            src,
+            "        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => *cell = {cell}::MAX,"
+        ),
+    }
+    .unwrap();
+    writeln!(src, "        Err(err) => return Err(err),").unwrap();
+    writeln!(src, "    }}").unwrap();
+    writeln!(src).unwrap();
+    writeln!(src, "    Ok(())").unwrap();
+    writeln!(src, "}}").unwrap();
+    writeln!(src).unwrap();
+
+    writeln!(
+        src,
+        "pub fn run(tape: &mut [{cell}], input: &mut impl Read, output: &mut impl Write) -> io::Result<()> {{"
+    )
+    .unwrap();
+    writeln!(src, "    let mut ptr = 0usize;").unwrap();
+    writeln!(src).unwrap();
+
+    // Nesting depth of loops, one level of indentation each
+    let mut depth = 1usize;
+
+    for insn in ir {
+        if insn == IRInsn::JumpIfNonZero {
+            depth = depth.checked_sub(1).filter(|&depth| depth > 0).ok_or(())?;
+        }
+
+        let indent = "    ".repeat(depth);
+
+        match insn {
+            IRInsn::IncPtr(amount) => writeln!(src, "{indent}ptr += {amount};"),
+            IRInsn::DecPtr(amount) => writeln!(
+                src,
+                "{indent}ptr = ptr.checked_sub({amount}).expect(\"Moved off the start of the tape\");"
+            ),
+            IRInsn::IncVal(amount) => {
+                writeln!(src, "{indent}tape[ptr] = tape[ptr].wrapping_add({amount});")
+            }
+            IRInsn::DecVal(amount) => {
+                writeln!(src, "{indent}tape[ptr] = tape[ptr].wrapping_sub({amount});")
+            }
+            IRInsn::JumpIfZero => writeln!(src, "{indent}while tape[ptr] != 0 {{"),
+            IRInsn::JumpIfNonZero => writeln!(src, "{indent}}}"),
+            IRInsn::GetChar => writeln!(src, "{indent}get_char(input, &mut tape[ptr])?;"),
+            IRInsn::PutChar => writeln!(src, "{indent}output.write_all(&[{byte}])?;"),
+        }
+        .unwrap();
+
+        if insn == IRInsn::JumpIfZero {
+            depth += 1;
+        }
+    }
+
+    // Any loop left open means unbalanced brackets
+    if depth != 1 {
+        return Err(());
+    }
+
+    writeln!(src).unwrap();
+    writeln!(src, "    output.flush()").unwrap();
+    writeln!(src, "}}").unwrap();
+
+    Ok(src)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck::{ir::IR, program::Program};
+
+    fn transpile(source: &str, cell: Cell, eof: Eof) -> String {
+        emit(IR::from(Program::new(source)), Options { cell, eof }).unwrap()
+    }
+
+    #[test]
+    fn cells_have_the_configured_type() {
+        let src = transpile(",+.", Cell::U16, Eof::Unchanged);
+
+        assert!(
+            src.contains("fn get_char(input: &mut impl Read, cell: &mut u16) -> io::Result<()> {")
+        );
+        assert!(src.contains(
+            "pub fn run(tape: &mut [u16], input: &mut impl Read, output: &mut impl Write) -> io::Result<()> {"
+        ));
+        assert!(src.contains("    get_char(input, &mut tape[ptr])?;"));
+        assert!(src.contains("    tape[ptr] = tape[ptr].wrapping_add(1);"));
+        assert!(src.contains("    output.write_all(&[tape[ptr] as u8])?;"));
+
+        let src = transpile(".", Cell::U8, Eof::Unchanged);
+        assert!(src.contains("    output.write_all(&[tape[ptr]])?;"));
+    }
+
+    #[test]
+    fn end_of_input_follows_the_policy() {
+        let arm = "        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof =>";
+
+        for (cell, eof, action) in [
+            (Cell::U8, Eof::Unchanged, "{}"),
+            (Cell::U16, Eof::Zero, "*cell = 0,"),
+            (Cell::U8, Eof::Max, "*cell = u8::MAX,"),
+            (Cell::U32, Eof::Max, "*cell = u32::MAX,"),
+        ] {
+            assert!(transpile(",", cell, eof).contains(&format!("{arm} {action}\n")));
+        }
+    }
+
+    #[test]
+    fn unbalanced_brackets() {
+        use IRInsn::*;
+
+        let options = Options::default();
+        assert!(emit([JumpIfZero, JumpIfZero, JumpIfNonZero], options).is_err());
+        assert!(emit([JumpIfZero, JumpIfNonZero, JumpIfNonZero], options).is_err());
+    }
+
+    #[test]
+    fn generated_modules_run() {
+        use std::{env, fs, process};
+
+        // The loop only runs if 256 didn't wrap to zero, then the second cell is read
+        // into with the input already used up
+        let source = format!("{}[>+<[-]]>.,.", "+".repeat(256));
+
+        for (cell, eof, expected) in [
+            (Cell::U8, Eof::Unchanged, [0, 0]),
+            (Cell::U16, Eof::Unchanged, [1, 1]),
+            (Cell::U16, Eof::Zero, [1, 0]),
+            (Cell::U8, Eof::Max, [0, 0xff]),
+            (Cell::U32, Eof::Max, [1, 0xff]),
+        ] {
+            let dir = env::temp_dir().join(format!("brainrust-rust-{}", process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("generated.rs"), transpile(&source, cell, eof)).unwrap();
+            fs::write(
+                dir.join("main.rs"),
+                "mod generated;\n\
+                 fn main() {\n    \
+                     let mut tape = vec![0; 30000];\n    \
+                     generated::run(&mut tape, &mut std::io::stdin(), &mut std::io::stdout()).unwrap();\n\
+                 }\n",
+            )
+            .unwrap();
+
+            let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+            let status = process::Command::new(rustc)
+                .args(["-D", "warnings", "-o"])
+                .arg(dir.join("main"))
+                .arg(dir.join("main.rs"))
+                .status()
+                .unwrap();
+            assert!(status.success(), "{cell:?} cells, {eof:?} at EOF");
+
+            let output = process::Command::new(dir.join("main"))
+                .stdin(process::Stdio::null())
+                .output()
+                .unwrap();
+            fs::remove_dir_all(&dir).unwrap();
+
+            assert!(output.status.success());
+            assert_eq!(output.stdout, expected, "{cell:?} cells, {eof:?} at EOF");
+        }
+    }
+}
@@ -0,0 +1,95 @@
+// Running what the source emitters produce through the toolchain it is meant
+// for, so the tests check the output actually works rather than how it reads.
+// A toolchain that isn't installed skips the test instead of failing it.
+
+use crate::brainfuck::{interpreter::Interpreter, program::Program};
+use std::{
+    env, fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+// Programs to run, with their input. Between them they cover I/O, nested loops,
+// and runs too long for a single byte.
+pub fn programs() -> Vec<(String, &'static [u8])> {
+    vec![
+        (
+            include_str!("../../../test_programs/hello_world.bf").into(),
+            b"",
+        ),
+        (
+            include_str!("../../../test_programs/sierpinski.bf").into(),
+            b"",
+        ),
+        (include_str!("../../../test_programs/beer.bf").into(), b""),
+        (
+            include_str!("../../../test_programs/3out.bf").into(),
+            b"abc",
+        ),
+        (
+            include_str!("../../../test_programs/fibonacci.bf").into(),
+            b"\x0a",
+        ),
+        (
+            format!(
+                "{}{}.{}.",
+                ">".repeat(300),
+                "+".repeat(300),
+                "<".repeat(256)
+            ),
+            b"",
+        ),
+    ]
+}
+
+// What the interpreter prints for `source`. Input runs out the way getchar's
+// does, with EOF truncated to a byte.
+pub fn interpreted(source: &str, input: &[u8]) -> Vec<u8> {
+    let mut input = input.iter();
+    let mut output = vec![];
+
+    Interpreter::run_with_io(
+        &Program::new(source),
+        || input.next().copied().unwrap_or(0xff),
+        |byte| output.push(byte),
+    );
+
+    output
+}
+
+// A tool from the environment variable `var`, or by its usual name, if it runs at all
+pub fn find(var: &str, name: &str) -> Option<String> {
+    let tool = env::var(var).unwrap_or_else(|_| name.into());
+    let found = Command::new(&tool)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+
+    found.then_some(tool)
+}
+
+// A scratch directory of the test's own, `name` telling tests apart
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("brainrust-{name}-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+// Run `command` with `input` on stdin, handing back what it printed
+pub fn output(command: &mut Command, input: &[u8]) -> Vec<u8> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{command:?} failed");
+
+    output.stdout
+}
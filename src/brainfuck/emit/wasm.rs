@@ -0,0 +1,493 @@
+use crate::brainfuck::{
+    ir::IRInsn,
+    leb128::{write_signed, write_unsigned},
+};
+use std::fmt::Write;
+
+// The module we build looks like this, in the text format:
+//
+//     (module
+//       (type (func (result i32)))
+//       (type (func (param i32) (result i32)))
+//       (import "env" "getchar" (func (type 0)))
+//       (import "env" "putchar" (func (type 1)))
+//       (func (type 1) ...)
+//       (memory 1)
+//       (export "run" (func 2))
+//       (export "memory" (memory 0)))
+//
+// `run` takes the tape's address in linear memory and returns where the tape
+// pointer ended up, same as the JIT compiled functions. A single 64KiB page
+// holds the whole 30,000 cell tape. getchar and putchar have their C signatures,
+// so a host can pass a libc straight through, or write its own.
+
+const MAGIC: &[u8] = b"\0asm";
+const VERSION: &[u8] = &[1, 0, 0, 0];
+
+// Section ids
+const TYPE_SECTION: u8 = 1;
+const IMPORT_SECTION: u8 = 2;
+const FUNCTION_SECTION: u8 = 3;
+const MEMORY_SECTION: u8 = 5;
+const EXPORT_SECTION: u8 = 7;
+const CODE_SECTION: u8 = 10;
+
+// Type indices
+const GETCHAR_TYPE: u32 = 0;
+const PUTCHAR_TYPE: u32 = 1;
+const RUN_TYPE: u32 = 1;
+
+// Function indices, imports come first
+const GETCHAR: u32 = 0;
+const PUTCHAR: u32 = 1;
+const RUN: u32 = 2;
+
+// The tape pointer, run's only parameter
+const PTR: u32 = 0;
+
+const FUNC_TYPE: u8 = 0x60;
+const I32: u8 = 0x7f;
+const EMPTY_BLOCK_TYPE: u8 = 0x40;
+const FUNC_EXTERNAL: u8 = 0x00;
+const MEMORY_EXTERNAL: u8 = 0x02;
+
+// The handful of Wasm instructions Brainfuck needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Instr {
+    Block,
+    Loop,
+    End,
+    BrIf(u32),
+    LocalGet(u32),
+    LocalSet(u32),
+    I32Const(i32),
+    I32Add,
+    I32Sub,
+    I32Eqz,
+    I32Load8U,
+    I32Store8,
+    Call(u32),
+    Drop,
+}
+
+// Lower IR to the body of `run`. Each loop becomes a block wrapping a loop,
+// leaving the block skips the loop, and branching to the loop repeats it:
+//
+//     block
+//       loop
+//         <cell> i32.eqz br_if 1
+//         ...
+//         <cell> br_if 0
+//       end
+//     end
+fn lower(ir: impl IntoIterator<Item = IRInsn>) -> Result<Vec<Instr>, ()> {
+    use Instr::*;
+
+    let mut body = vec![];
+    let mut depth = 0usize;
+
+    let cell = [LocalGet(PTR), I32Load8U];
+
+    for insn in ir {
+        match insn {
+            IRInsn::IncPtr(amount) => body.extend([
+                LocalGet(PTR),
+                I32Const(amount as i32),
+                I32Add,
+                LocalSet(PTR),
+            ]),
+
+            IRInsn::DecPtr(amount) => body.extend([
+                LocalGet(PTR),
+                I32Const(amount as i32),
+                I32Sub,
+                LocalSet(PTR),
+            ]),
+
+            IRInsn::IncVal(amount) => {
+                body.push(LocalGet(PTR));
+                body.extend(cell);
+                body.extend([I32Const(amount as i32), I32Add, I32Store8]);
+            }
+
+            IRInsn::DecVal(amount) => {
+                body.push(LocalGet(PTR));
+                body.extend(cell);
+                body.extend([I32Const(amount as i32), I32Sub, I32Store8]);
+            }
+
+            IRInsn::JumpIfZero => {
+                body.extend([Block, Loop]);
+                body.extend(cell);
+                body.extend([I32Eqz, BrIf(1)]);
+                depth += 1;
+            }
+
+            IRInsn::JumpIfNonZero => {
+                depth = depth.checked_sub(1).ok_or(())?;
+                body.extend(cell);
+                body.extend([BrIf(0), End, End]);
+            }
+
+            IRInsn::GetChar => body.extend([LocalGet(PTR), Call(GETCHAR), I32Store8]),
+
+            IRInsn::PutChar => {
+                body.extend(cell);
+                body.extend([Call(PUTCHAR), Drop]);
+            }
+        }
+    }
+
+    // Any loop left open means unbalanced brackets
+    if depth != 0 {
+        return Err(());
+    }
+
+    body.extend([LocalGet(PTR), End]);
+
+    Ok(body)
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    write_unsigned(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn write_section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    write_unsigned(out, contents.len() as u32);
+    out.extend_from_slice(contents);
+}
+
+fn encode(instr: Instr, out: &mut Vec<u8>) {
+    use Instr::*;
+
+    match instr {
+        Block => out.extend([0x02, EMPTY_BLOCK_TYPE]),
+        Loop => out.extend([0x03, EMPTY_BLOCK_TYPE]),
+        End => out.push(0x0b),
+        BrIf(depth) => {
+            out.push(0x0d);
+            write_unsigned(out, depth);
+        }
+        LocalGet(idx) => {
+            out.push(0x20);
+            write_unsigned(out, idx);
+        }
+        LocalSet(idx) => {
+            out.push(0x21);
+            write_unsigned(out, idx);
+        }
+        I32Const(value) => {
+            out.push(0x41);
+            write_signed(out, value);
+        }
+        I32Add => out.push(0x6a),
+        I32Sub => out.push(0x6b),
+        I32Eqz => out.push(0x45),
+        // Memory accesses are followed by their alignment and offset, both zero
+        I32Load8U => out.extend([0x2d, 0x00, 0x00]),
+        I32Store8 => out.extend([0x3a, 0x00, 0x00]),
+        Call(idx) => {
+            out.push(0x10);
+            write_unsigned(out, idx);
+        }
+        Drop => out.push(0x1a),
+    }
+}
+
+// Compile Brainfuck IR to a binary Wasm module
+pub fn emit(ir: impl IntoIterator<Item = IRInsn>) -> Result<Vec<u8>, ()> {
+    let body = lower(ir)?;
+
+    let mut module = vec![];
+    module.extend_from_slice(MAGIC);
+    module.extend_from_slice(VERSION);
+
+    let mut types = vec![];
+    write_unsigned(&mut types, 2);
+    types.extend([FUNC_TYPE, 0, 1, I32]);
+    types.extend([FUNC_TYPE, 1, I32, 1, I32]);
+    write_section(&mut module, TYPE_SECTION, &types);
+
+    let mut imports = vec![];
+    write_unsigned(&mut imports, 2);
+    for (name, type_idx) in [("getchar", GETCHAR_TYPE), ("putchar", PUTCHAR_TYPE)] {
+        write_name(&mut imports, "env");
+        write_name(&mut imports, name);
+        imports.push(FUNC_EXTERNAL);
+        write_unsigned(&mut imports, type_idx);
+    }
+    write_section(&mut module, IMPORT_SECTION, &imports);
+
+    let mut functions = vec![];
+    write_unsigned(&mut functions, 1);
+    write_unsigned(&mut functions, RUN_TYPE);
+    write_section(&mut module, FUNCTION_SECTION, &functions);
+
+    // One memory, with a minimum of one page and no maximum
+    write_section(&mut module, MEMORY_SECTION, &[1, 0x00, 1]);
+
+    let mut exports = vec![];
+    write_unsigned(&mut exports, 2);
+    write_name(&mut exports, "run");
+    exports.push(FUNC_EXTERNAL);
+    write_unsigned(&mut exports, RUN);
+    write_name(&mut exports, "memory");
+    exports.push(MEMORY_EXTERNAL);
+    write_unsigned(&mut exports, 0);
+    write_section(&mut module, EXPORT_SECTION, &exports);
+
+    // No locals besides the parameter
+    let mut code = vec![0];
+    body.into_iter().for_each(|instr| encode(instr, &mut code));
+
+    let mut bodies = vec![];
+    write_unsigned(&mut bodies, 1);
+    write_unsigned(&mut bodies, code.len() as u32);
+    bodies.extend(code);
+    write_section(&mut module, CODE_SECTION, &bodies);
+
+    Ok(module)
+}
+
+// Compile Brainfuck IR to the same module as emit, in the Wasm text format
+pub fn emit_text(ir: impl IntoIterator<Item = IRInsn>) -> Result<String, ()> {
+    use Instr::*;
+
+    let body = lower(ir)?;
+
+    let mut src = String::new();
+    writeln!(src, "(module").unwrap();
+    writeln!(src, "  (type $nullary (func (result i32)))").unwrap();
+    writeln!(src, "  (type $unary (func (param i32) (result i32)))").unwrap();
+    writeln!(
+        src,
+        "  (import \"env\" \"getchar\" (func $getchar (type $nullary)))"
+    )
+    .unwrap();
+    writeln!(
+        src,
+        "  (import \"env\" \"putchar\" (func $putchar (type $unary)))"
+    )
+    .unwrap();
+    writeln!(
+        src,
+        "  (func $run (type $unary) (param $ptr i32) (result i32)"
+    )
+    .unwrap();
+
+    // Nesting depth of blocks and loops, one level of indentation each.
+    // The final End closes the function itself, so leave it to the closing paren.
+    let mut depth = 2usize;
+
+    for instr in &body[..body.len() - 1] {
+        if *instr == End {
+            depth -= 1;
+        }
+
+        let indent = "  ".repeat(depth);
+
+        match *instr {
+            Block => writeln!(src, "{indent}block"),
+            Loop => writeln!(src, "{indent}loop"),
+            End => writeln!(src, "{indent}end"),
+            BrIf(depth) => writeln!(src, "{indent}br_if {depth}"),
+            LocalGet(_) => writeln!(src, "{indent}local.get $ptr"),
+            LocalSet(_) => writeln!(src, "{indent}local.set $ptr"),
+            I32Const(value) => writeln!(src, "{indent}i32.const {value}"),
+            I32Add => writeln!(src, "{indent}i32.add"),
+            I32Sub => writeln!(src, "{indent}i32.sub"),
+            I32Eqz => writeln!(src, "{indent}i32.eqz"),
+            I32Load8U => writeln!(src, "{indent}i32.load8_u"),
+            I32Store8 => writeln!(src, "{indent}i32.store8"),
+            Call(GETCHAR) => writeln!(src, "{indent}call $getchar"),
+            Call(_) => writeln!(src, "{indent}call $putchar"),
+            Drop => writeln!(src, "{indent}drop"),
+        }
+        .unwrap();
+
+        if matches!(instr, Block | Loop) {
+            depth += 1;
+        }
+    }
+
+    writeln!(src, "  )").unwrap();
+    writeln!(src, "  (memory (export \"memory\") 1)").unwrap();
+    writeln!(src, "  (export \"run\" (func $run))").unwrap();
+    writeln!(src, ")").unwrap();
+
+    Ok(src)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck::{ir::IR, program::Program};
+
+    fn read_unsigned(bytes: &mut &[u8]) -> u32 {
+        let (mut value, mut shift) = (0u32, 0);
+
+        loop {
+            let byte = bytes[0];
+            *bytes = &bytes[1..];
+            value |= ((byte & 0x7f) as u32) << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                return value;
+            }
+        }
+    }
+
+    fn read_name<'a>(bytes: &mut &'a [u8]) -> &'a str {
+        let len = read_unsigned(bytes) as usize;
+        let (name, rest) = bytes.split_at(len);
+        *bytes = rest;
+
+        std::str::from_utf8(name).unwrap()
+    }
+
+    // Split a module into its sections, checking the header and that
+    // every section's size adds up to exactly the end of the module
+    fn sections(module: &[u8]) -> Vec<(u8, &[u8])> {
+        assert_eq!(&module[..4], MAGIC);
+        assert_eq!(&module[4..8], VERSION);
+
+        let mut bytes = &module[8..];
+        let mut sections = vec![];
+
+        while !bytes.is_empty() {
+            let id = bytes[0];
+            bytes = &bytes[1..];
+            let len = read_unsigned(&mut bytes) as usize;
+            let (contents, rest) = bytes.split_at(len);
+            sections.push((id, contents));
+            bytes = rest;
+        }
+
+        sections
+    }
+
+    fn compile(source: &str) -> Vec<u8> {
+        emit(IR::from(Program::new(source))).unwrap()
+    }
+
+    #[test]
+    fn section_layout() {
+        let module = compile(include_str!("../../../test_programs/hello_world.bf"));
+        let ids: Vec<u8> = sections(&module).iter().map(|(id, _)| *id).collect();
+
+        assert_eq!(
+            ids,
+            [
+                TYPE_SECTION,
+                IMPORT_SECTION,
+                FUNCTION_SECTION,
+                MEMORY_SECTION,
+                EXPORT_SECTION,
+                CODE_SECTION
+            ]
+        );
+    }
+
+    #[test]
+    fn imports_and_exports() {
+        let module = compile(",.");
+        let sections = sections(&module);
+
+        let (_, types) = sections[0];
+        assert_eq!(types, [2, FUNC_TYPE, 0, 1, I32, FUNC_TYPE, 1, I32, 1, I32]);
+
+        let (_, mut imports) = sections[1];
+        assert_eq!(read_unsigned(&mut imports), 2);
+        for (name, type_idx) in [("getchar", GETCHAR_TYPE), ("putchar", PUTCHAR_TYPE)] {
+            assert_eq!(read_name(&mut imports), "env");
+            assert_eq!(read_name(&mut imports), name);
+            assert_eq!(imports[0], FUNC_EXTERNAL);
+            imports = &imports[1..];
+            assert_eq!(read_unsigned(&mut imports), type_idx);
+        }
+        assert!(imports.is_empty());
+
+        let (_, functions) = sections[2];
+        assert_eq!(functions, [1, RUN_TYPE as u8]);
+
+        let (_, memories) = sections[3];
+        assert_eq!(memories, [1, 0x00, 1]);
+
+        let (_, mut exports) = sections[4];
+        assert_eq!(read_unsigned(&mut exports), 2);
+        assert_eq!(read_name(&mut exports), "run");
+        assert_eq!(exports[..2], [FUNC_EXTERNAL, RUN as u8]);
+        exports = &exports[2..];
+        assert_eq!(read_name(&mut exports), "memory");
+        assert_eq!(exports, [MEMORY_EXTERNAL, 0]);
+    }
+
+    #[test]
+    fn function_body() {
+        let module = compile("+[->+<].");
+        let (_, mut code) = sections(&module)[5];
+
+        assert_eq!(read_unsigned(&mut code), 1);
+        assert_eq!(read_unsigned(&mut code) as usize, code.len());
+
+        #[rustfmt::skip]
+        let expected: &[u8] = &[
+            0x00, // no locals
+            0x20, 0x00, 0x20, 0x00, 0x2d, 0x00, 0x00, 0x41, 0x01, 0x6a, 0x3a, 0x00, 0x00, // +
+            0x02, 0x40, 0x03, 0x40, 0x20, 0x00, 0x2d, 0x00, 0x00, 0x45, 0x0d, 0x01, // [
+            0x20, 0x00, 0x20, 0x00, 0x2d, 0x00, 0x00, 0x41, 0x01, 0x6b, 0x3a, 0x00, 0x00, // -
+            0x20, 0x00, 0x41, 0x01, 0x6a, 0x21, 0x00, // >
+            0x20, 0x00, 0x20, 0x00, 0x2d, 0x00, 0x00, 0x41, 0x01, 0x6a, 0x3a, 0x00, 0x00, // +
+            0x20, 0x00, 0x41, 0x01, 0x6b, 0x21, 0x00, // <
+            0x20, 0x00, 0x2d, 0x00, 0x00, 0x0d, 0x00, 0x0b, 0x0b, // ]
+            0x20, 0x00, 0x2d, 0x00, 0x00, 0x10, 0x01, 0x1a, // .
+            0x20, 0x00, 0x0b, // return the tape pointer
+        ];
+        assert_eq!(code, expected);
+    }
+
+    #[test]
+    fn blocks_balance() {
+        let ir = IR::from(Program::new(include_str!(
+            "../../../test_programs/sierpinski.bf"
+        )));
+        let body = lower(ir).unwrap();
+
+        let opened = body
+            .iter()
+            .filter(|instr| matches!(instr, Instr::Block | Instr::Loop))
+            .count();
+        let closed = body.iter().filter(|instr| **instr == Instr::End).count();
+
+        // One extra end, closing the function body
+        assert_eq!(closed, opened + 1);
+        assert_eq!(body.last(), Some(&Instr::End));
+    }
+
+    #[test]
+    fn text_matches_binary() {
+        let source = include_str!("../../../test_programs/beer.bf");
+        let text = emit_text(IR::from(Program::new(source))).unwrap();
+        let body = lower(IR::from(Program::new(source))).unwrap();
+
+        // One line per instruction, bar the function's closing end
+        let instructions = text
+            .lines()
+            .skip_while(|line| !line.contains("(func $run"))
+            .skip(1)
+            .take_while(|line| *line != "  )")
+            .count();
+        assert_eq!(instructions, body.len() - 1);
+
+        assert_eq!(text.matches('(').count(), text.matches(')').count());
+    }
+
+    #[test]
+    fn unbalanced_brackets() {
+        assert_eq!(emit([IRInsn::JumpIfZero]), Err(()));
+        assert_eq!(emit([IRInsn::JumpIfNonZero]), Err(()));
+    }
+}
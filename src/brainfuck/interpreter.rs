@@ -1,7 +1,8 @@
 use super::{
-    ir::IR,
+    ir::{self, IRInsn, IR},
     program::{Operator, Program},
-    Eval,
+    tape::Tape,
+    watched, Budget, Eval, ExecutionResult, Limit, LimitExceeded, Meter,
 };
 use std::ffi::c_int;
 
@@ -12,15 +13,51 @@ extern "C" {
 
 pub struct Interpreter;
 
-impl Eval for Interpreter {
-    // The interpreter just executes the program, returns nothing
-    type Output = ();
-
-    fn eval_source(program: Program) -> Result<Self::Output, ()> {
+impl Interpreter {
+    // Run a program with I/O going through the given closures instead of
+    // libc's getchar/putchar, handy when output needs to be captured.
+    pub fn run_with_io(
+        program: &Program,
+        input: impl FnMut() -> u8,
+        output: impl FnMut(u8),
+    ) -> ExecutionResult {
         // According to this source, https://gist.github.com/roachhd/dce54bec8ba55fb17d3a
         // standard Brainfuck has 30,000 bytes of memory to work with,
-        // so initialize an array of 30,000 bytes to start.
-        let mut mem = [0u8; 30_000];
+        // so start with a tape of 30,000 bytes. With no budget there's nothing to exceed.
+        Self::run_on(program, Tape::new(), Budget::default(), input, output).unwrap()
+    }
+
+    // Run a program doing I/O through libc, stopping it once it goes over `budget`
+    pub fn run_within(program: &Program, budget: Budget) -> Result<ExecutionResult, LimitExceeded> {
+        // The interpreter reads the clock itself, the watchdog only gets it out of reads
+        watched(
+            budget.timeout,
+            || {},
+            || {
+                Self::run_on(
+                    program,
+                    Tape::new(),
+                    budget,
+                    || unsafe { getchar() as u8 },
+                    |byte| unsafe {
+                        putchar(byte as c_int);
+                    },
+                )
+            },
+        )
+    }
+
+    // Same as run_with_io, on a tape the caller brings along, within a budget.
+    // Nothing here allocates, which is what running under the sandbox's system
+    // call filter needs.
+    pub fn run_on(
+        program: &Program,
+        mut mem: Tape,
+        budget: Budget,
+        mut input: impl FnMut() -> u8,
+        mut output: impl FnMut(u8),
+    ) -> Result<ExecutionResult, LimitExceeded> {
+        let mut meter = Meter::new(budget);
 
         // Work with two pointers, one for memory, or tape, the other as an instruction pointer
         // that points to the current brainfuck operator. Both of these are array offsets, technically
@@ -49,27 +86,165 @@ impl Eval for Interpreter {
 
                 Operator::JumpIfNonZero => {
                     if mem[mem_ptr] != 0 {
+                        if let Some(limit) = meter.step() {
+                            return Err(LimitExceeded {
+                                limit,
+                                position: ip,
+                                tape: mem,
+                                pointer: mem_ptr,
+                            });
+                        }
+
                         ip = program.bwd_jump_table[&ip];
                     }
                 }
 
-                Operator::GetChar => unsafe {
-                    mem[mem_ptr] = getchar() as u8;
-                },
+                Operator::GetChar => {
+                    let byte = input();
+
+                    // Input can take any amount of time to turn up, if at all
+                    if meter.out_of_time() {
+                        return Err(LimitExceeded {
+                            limit: Limit::Time,
+                            position: ip,
+                            tape: mem,
+                            pointer: mem_ptr,
+                        });
+                    }
 
-                Operator::PutChar => unsafe {
-                    putchar(mem[mem_ptr] as c_int);
-                },
+                    mem[mem_ptr] = byte;
+                }
+
+                Operator::PutChar => output(mem[mem_ptr]),
             }
 
             // Don't forget to increment the instruction pointer for next operation!
             ip += 1;
         }
 
-        Ok(())
+        Ok(ExecutionResult::finished(mem, mem_ptr))
+    }
+}
+
+impl Eval for Interpreter {
+    // The interpreter executes the program, handing back the state it left the tape in
+    type Output = ExecutionResult;
+
+    fn eval_source(program: Program) -> Result<Self::Output, ()> {
+        Self::run_within(&program, Budget::default()).map_err(|_| ())
     }
 
     fn eval_ir(ir: IR) -> Result<Self::Output, ()> {
-        unimplemented!()
+        let mut machine = IrMachine::new(ir)?;
+
+        while !machine.is_finished() {
+            machine.step(
+                |cell| unsafe { *cell = getchar() as u8 },
+                |byte| unsafe {
+                    putchar(byte as c_int);
+                },
+            );
+        }
+
+        Ok(machine.into_result())
+    }
+}
+
+// What running one IR instruction did, as far as loops go
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    Next,
+    // Went into the body of the loop opened at this position
+    EnteredLoop(usize),
+    // Jumped back to the start of the body of the loop opened at this position
+    LoopedBack(usize),
+}
+
+// An IR program part way through running, an instruction at a time. Interpreting
+// IR runs one of these to the end, the tiered executor steps through one itself
+// so it can hand hot loops over to compiled code along the way.
+pub struct IrMachine {
+    insns: Vec<IRInsn>,
+    jump_table: Box<[usize]>,
+    pub mem: Tape,
+    pub mem_ptr: usize,
+    pub ip: usize,
+}
+
+impl IrMachine {
+    pub fn new(ir: IR) -> Result<Self, ()> {
+        let insns: Vec<IRInsn> = ir.into_iter().collect();
+        let jump_table = ir::bracket_pairs(&insns)?;
+
+        Ok(IrMachine {
+            insns,
+            jump_table,
+            mem: Tape::new(),
+            mem_ptr: 0,
+            ip: 0,
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.ip >= self.insns.len()
+    }
+
+    // The loop opened at `start`, brackets and all
+    pub fn loop_ir(&self, start: usize) -> IR {
+        self.insns[start..=self.jump_table[start]]
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    // Carry on right after the loop opened at `start`, once something else has run it to the end
+    pub fn skip_loop(&mut self, start: usize) {
+        self.ip = self.jump_table[start] + 1;
+    }
+
+    // Run the instruction at ip. Same as interpreting source, only with collapsed
+    // operators. Brackets land on their partner, then skip past it like any other
+    // instruction. Input is handed the current cell to fill in.
+    pub fn step(&mut self, input: impl FnOnce(&mut u8), output: impl FnOnce(u8)) -> Step {
+        let ip = self.ip;
+        let mem = &mut self.mem;
+        let mem_ptr = self.mem_ptr;
+        self.ip += 1;
+
+        match self.insns[ip] {
+            IRInsn::IncPtr(amount) => self.mem_ptr += amount as usize,
+
+            IRInsn::DecPtr(amount) => self.mem_ptr -= amount as usize,
+
+            IRInsn::IncVal(amount) => mem[mem_ptr] = mem[mem_ptr].wrapping_add(amount),
+
+            IRInsn::DecVal(amount) => mem[mem_ptr] = mem[mem_ptr].wrapping_sub(amount),
+
+            IRInsn::JumpIfZero => {
+                if mem[mem_ptr] == 0 {
+                    self.ip = self.jump_table[ip] + 1;
+                } else {
+                    return Step::EnteredLoop(ip);
+                }
+            }
+
+            IRInsn::JumpIfNonZero => {
+                if mem[mem_ptr] != 0 {
+                    let start = self.jump_table[ip];
+                    self.ip = start + 1;
+                    return Step::LoopedBack(start);
+                }
+            }
+
+            IRInsn::GetChar => input(&mut mem[mem_ptr]),
+
+            IRInsn::PutChar => output(mem[mem_ptr]),
+        }
+
+        Step::Next
+    }
+
+    pub fn into_result(self) -> ExecutionResult {
+        ExecutionResult::finished(self.mem, self.mem_ptr)
     }
 }
@@ -20,20 +20,27 @@ pub enum IRInsn {
 }
 
 impl IRInsn {
-    fn is_collapsible(&self) -> bool {
+    // Whether the next instruction can be folded into this one. Runs too long for
+    // one instruction's amount start a new instruction rather than wrapping, so
+    // nothing is lost for backends with cells wider than a byte.
+    fn can_collapse_with(&self, other_insn: &Self) -> bool {
         use IRInsn::*;
 
-        matches!(self, IncPtr(_) | DecPtr(_) | IncVal(_) | DecVal(_))
+        match (self, other_insn) {
+            (IncPtr(x), IncPtr(y)) | (DecPtr(x), DecPtr(y)) => x.checked_add(*y).is_some(),
+            (IncVal(x), IncVal(y)) | (DecVal(x), DecVal(y)) => x.checked_add(*y).is_some(),
+            _ => false,
+        }
     }
 
     fn collapse_with(&mut self, other_insn: Self) {
         use IRInsn::*;
         // You should only collapse two instructions to one if
         // they are the same type of instruction!
-        assert!(self.tag() == other_insn.tag());
+        assert!(self.can_collapse_with(&other_insn));
 
         match (self, other_insn) {
-            (IncPtr(x), IncPtr(y)) | (DecPtr(x), DecPtr(y)) => *x = x.wrapping_add(y),
+            (IncPtr(x), IncPtr(y)) | (DecPtr(x), DecPtr(y)) => *x += y,
 
             (IncVal(x), IncVal(y)) | (DecVal(x), DecVal(y)) => *x += y,
 
@@ -79,9 +86,8 @@ where
 
     fn next(&mut self) -> Option<Self::Item> {
         if let Some(mut curr_insn) = self.iter.next() {
-            while let Some(collapsible) = self
-                .iter
-                .next_if(|insn| curr_insn.is_collapsible() && curr_insn.tag() == insn.tag())
+            while let Some(collapsible) =
+                self.iter.next_if(|insn| curr_insn.can_collapse_with(insn))
             {
                 curr_insn.collapse_with(collapsible);
             }
@@ -112,6 +118,12 @@ impl From<Program> for IR {
     }
 }
 
+impl FromIterator<IRInsn> for IR {
+    fn from_iter<T: IntoIterator<Item = IRInsn>>(iter: T) -> Self {
+        Self(RefCell::new(iter.into_iter().collect()))
+    }
+}
+
 impl IntoIterator for IR {
     type Item = IRInsn;
     type IntoIter = std::vec::IntoIter<Self::Item>;
@@ -120,3 +132,50 @@ impl IntoIterator for IR {
         self.0.into_inner().into_vec().into_iter()
     }
 }
+
+// The source line of every instruction IR::from(program) produces, being the line
+// of the first operator it was collapsed from. Walks the operators grouping
+// them exactly like collapse does, without building the IR itself.
+pub fn source_lines(program: &Program) -> Vec<u32> {
+    let mut lines = vec![];
+    let mut ops = program.code.iter().zip(program.lines.iter()).peekable();
+
+    while let Some((&op, &line)) = ops.next() {
+        let mut insn = IRInsn::from(op);
+
+        while let Some((&next, _)) = ops.next_if(|(&next, _)| insn.can_collapse_with(&next.into()))
+        {
+            insn.collapse_with(next.into());
+        }
+
+        lines.push(line);
+    }
+
+    lines
+}
+
+// For every bracket in a list of IR instructions, the position of its matching
+// bracket. Positions of anything that isn't a bracket are left as zero.
+pub fn bracket_pairs(insns: &[IRInsn]) -> Result<Box<[usize]>, ()> {
+    let mut pairs = vec![0; insns.len()].into_boxed_slice();
+    let mut open_brackets = vec![];
+
+    for (idx, insn) in insns.iter().enumerate() {
+        match insn {
+            IRInsn::JumpIfZero => open_brackets.push(idx),
+            IRInsn::JumpIfNonZero => {
+                let partner = open_brackets.pop().ok_or(())?;
+                pairs[idx] = partner;
+                pairs[partner] = idx;
+            }
+
+            _ => {}
+        }
+    }
+
+    if open_brackets.is_empty() {
+        Ok(pairs)
+    } else {
+        Err(())
+    }
+}
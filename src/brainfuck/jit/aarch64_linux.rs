@@ -0,0 +1,776 @@
+// The code generator in this file is plain Rust, so it is built on every host,
+// which keeps it testable. Only aarch64 Linux gets the executable `Jit` on top.
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+use super::JittedFunction;
+use super::{
+    cache::{self, Key},
+    Arch,
+};
+use crate::brainfuck::{
+    ir::{IRInsn, IR},
+    program::{Operator, Program},
+    Eval,
+};
+
+// Register numbers we need when building instructions by hand. The tape pointer
+// lives in x0 (first argument), w9 holds the current cell and x10 large constants.
+// The input and output file descriptors come in x1 and x2, and live in x3 and x4.
+// Counted code keeps its Fuel pointer in x5.
+const X0: u32 = 0;
+const X1: u32 = 1;
+const X2: u32 = 2;
+const X3: u32 = 3;
+const X4: u32 = 4;
+const X5: u32 = 5;
+const X8: u32 = 8;
+const W9: u32 = 9;
+const X10: u32 = 10;
+const XZR: u32 = 31;
+
+// Linux AArch64 system call numbers, passed in x8
+const SYS_READ: u32 = 63;
+const SYS_WRITE: u32 = 64;
+
+// Base encodings for every instruction we emit, with every
+// register and immediate field left zeroed
+const LDRB: u32 = 0x3940_0000; // ldrb w0, [x0]
+const STRB: u32 = 0x3900_0000; // strb w0, [x0]
+const LDR_X: u32 = 0xf940_0000; // ldr x0, [x0]
+const STR_X: u32 = 0xf900_0000; // str x0, [x0]
+const ADD_W_IMM: u32 = 0x1100_0000; // add w0, w0, #0
+const SUB_W_IMM: u32 = 0x5100_0000; // sub w0, w0, #0
+const ADD_X_IMM: u32 = 0x9100_0000; // add x0, x0, #0
+const SUB_X_IMM: u32 = 0xd100_0000; // sub x0, x0, #0
+const SUBS_X_IMM: u32 = 0xf100_0000; // subs x0, x0, #0
+const ADD_X_REG: u32 = 0x8b00_0000; // add x0, x0, x0
+const SUB_X_REG: u32 = 0xcb00_0000; // sub x0, x0, x0
+const ORR_X_REG: u32 = 0xaa00_0000; // orr x0, x0, x0
+const MOVZ: u32 = 0xd280_0000; // movz x0, #0
+const MOVK_LSL16: u32 = 0xf2a0_0000; // movk x0, #0, lsl #16
+const CBZ: u32 = 0x3400_0000; // cbz w0, 0
+const CBNZ: u32 = 0x3500_0000; // cbnz w0, 0
+const B: u32 = 0x1400_0000; // b 0
+const B_PL: u32 = 0x5400_0005; // b.pl 0, its offset is where cbz/cbnz keep theirs
+const SVC: u32 = 0xd400_0001; // svc #0
+const RET: u32 = 0xd65f_03c0; // ret
+
+// AArch64 add/sub (immediate)
+// | sf | op | S | 100010 | sh | imm12 | Rn | Rd |
+fn encode_add_sub_immediate(base: u32, rd: u32, rn: u32, imm: u32) -> u32 {
+    assert!(imm < (1 << 12)); // 12 bit unsigned valid range
+
+    base | (imm << 10) | (rn << 5) | rd
+}
+
+// AArch64 add/sub/orr (shifted register), always with a shift of zero
+// | sf | opc | 01011 | shift | 0 | Rm | imm6 | Rn | Rd |
+fn encode_register(base: u32, rd: u32, rn: u32, rm: u32) -> u32 {
+    base | (rm << 16) | (rn << 5) | rd
+}
+
+// AArch64 move wide (immediate), the hw shift lives in the base encoding
+// | sf | opc | 100101 | hw | imm16 | Rd |
+fn encode_move_wide(base: u32, rd: u32, imm: u32) -> u32 {
+    assert!(imm < (1 << 16));
+
+    base | (imm << 5) | rd
+}
+
+// AArch64 load/store register (unsigned immediate), always with an offset of zero
+// | size | 111 | 0 | 01 | opc | imm12 | Rn | Rt |
+fn encode_load_store(base: u32, rt: u32, rn: u32) -> u32 {
+    base | (rn << 5) | rt
+}
+
+// AArch64 compare and branch (immediate)
+// | sf | 011010 | op | imm19 | Rt |
+//
+// The offset is encoded in instructions (multiples of four bytes), +/- 1MB
+fn encode_cb_offset(cb_insn: &mut u32, offset: i32) {
+    assert!((-(1 << 20)..(1 << 20)).contains(&offset));
+    assert!(offset % 4 == 0);
+
+    *cb_insn |= (((offset >> 2) as u32) & 0x7_ffff) << 5;
+}
+
+// AArch64 unconditional branch (immediate)
+// | op | 00101 | imm26 |
+//
+// The offset is encoded in instructions (multiples of four bytes), +/- 128MB
+fn encode_b_offset(b_insn: &mut u32, offset: i32) {
+    assert!((-(1 << 27)..(1 << 27)).contains(&offset));
+    assert!(offset % 4 == 0);
+
+    *b_insn |= ((offset >> 2) as u32) & 0x3ff_ffff;
+}
+
+// Move the tape pointer in x0 by any amount, forwards or backwards. Moves that fit
+// the 12 bit immediate of add/sub are done in place, bigger moves go through x10.
+fn emit_pointer_move(code: &mut Vec<u32>, amount: u32, forward: bool) {
+    if amount < (1 << 12) {
+        let op = if forward { ADD_X_IMM } else { SUB_X_IMM };
+        code.push(encode_add_sub_immediate(op, X0, X0, amount)); // add/sub x0, x0, #<amount>
+        return;
+    }
+
+    code.push(encode_move_wide(MOVZ, X10, amount & 0xffff)); // movz x10, #<amount[15:0]>
+
+    if amount >> 16 != 0 {
+        code.push(encode_move_wide(MOVK_LSL16, X10, amount >> 16)); // movk x10, #<amount[31:16]>, lsl #16
+    }
+
+    let op = if forward { ADD_X_REG } else { SUB_X_REG };
+    code.push(encode_register(op, X0, X0, X10)); // add/sub x0, x0, x10
+}
+
+// Add a (wrapping) amount to the cell under the tape pointer
+fn emit_value_add(code: &mut Vec<u32>, amount: u8, increment: bool) {
+    let op = if increment { ADD_W_IMM } else { SUB_W_IMM };
+
+    code.extend_from_slice(&[
+        encode_load_store(LDRB, W9, X0),                     // ldrb w9, [x0]
+        encode_add_sub_immediate(op, W9, W9, amount as u32), // add/sub w9, w9, #<amount>
+        encode_load_store(STRB, W9, X0),                     // strb w9, [x0]
+    ]);
+}
+
+// An inlined read(2)/write(2) of a single byte at the tape pointer, syscall(fd, buffer, length).
+// The kernel takes arguments in x0-x2, the syscall number in x8, and only clobbers x0
+// with its return value, so the tape pointer rides out the svc in x1 as the buffer.
+// The file descriptor comes from the register the prologue left it in.
+fn emit_syscall(code: &mut Vec<u32>, number: u32, fd: u32) {
+    code.extend_from_slice(&[
+        encode_register(ORR_X_REG, X1, XZR, X0), // mov x1, x0 (buffer)
+        encode_register(ORR_X_REG, X0, XZR, fd), // mov x0, <fd>
+        encode_move_wide(MOVZ, X2, 1),           // mov x2, #1 (length)
+        encode_move_wide(MOVZ, X8, number),      // mov x8, #<syscall number>
+        SVC,                                     // svc #0 (system call)
+        encode_register(ORR_X_REG, X0, XZR, X1), // mov x0, x1 (restore tape pointer)
+    ]);
+}
+
+// Loops are rotated, tested once per iteration at the bottom. The opening bracket
+// jumps straight down to the test, and the closing bracket is the test, branching
+// back up to the body while the cell isn't zero:
+//
+//         b .Ltest
+//     .Lbody:
+//         <body>
+//     .Ltest:
+//         ldrb w9, [x0]
+//         cbnz w9, .Lbody
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum BranchKind {
+    // An opening bracket's jump to its loop's test
+    Always,
+    // A closing bracket's branch back to its loop's body
+    IfNonZero,
+}
+
+// The shapes a bracket's branch can take, from smallest to largest. Jumps are
+// always short, a b reaches as far as the long form of a test does.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum BranchForm {
+    // cbnz w9, target, or for jumps, b target
+    Short,
+    // cbz w9, 8; b target
+    Long,
+}
+
+// A bracket's branch, kept out of the straight-line code until every branch's
+// form has been settled. `at` is the word index in the straight-line code the
+// branch sits in front of, and `partner` indexes the matching bracket's branch.
+struct Branch {
+    at: usize,
+    kind: BranchKind,
+    partner: usize,
+    form: BranchForm,
+}
+
+impl Branch {
+    // Size of the branch in instruction words
+    fn len(&self) -> usize {
+        match self.form {
+            BranchForm::Short => 1,
+            BranchForm::Long => 2,
+        }
+    }
+
+    // Whether the branch can reach a target `offset` bytes away from its first instruction
+    fn reaches(&self, offset: i64) -> bool {
+        match (self.form, self.kind) {
+            (BranchForm::Short, BranchKind::IfNonZero) => (-(1 << 20)..(1 << 20)).contains(&offset),
+            (BranchForm::Short, BranchKind::Always) => (-(1 << 27)..(1 << 27)).contains(&offset),
+            // The jump sits one instruction after the inverted branch
+            (BranchForm::Long, _) => (-(1 << 27)..(1 << 27)).contains(&(offset - 4)),
+        }
+    }
+}
+
+// Byte address of every branch, accounting for the size of the branches before it
+fn branch_addresses(branches: &[Branch]) -> Vec<i64> {
+    let mut inserted_words = 0;
+
+    branches
+        .iter()
+        .map(|branch| {
+            let addr = (branch.at + inserted_words) as i64 * 4;
+            inserted_words += branch.len();
+            addr
+        })
+        .collect()
+}
+
+// How far the branch at `idx` has to go. A jump goes to the load in front of its
+// partner, a test goes to the first instruction after its partner.
+fn branch_offset(branches: &[Branch], addrs: &[i64], idx: usize) -> i64 {
+    let partner = branches[idx].partner;
+
+    let target = match branches[idx].kind {
+        BranchKind::Always => addrs[partner] - 4,
+        BranchKind::IfNonZero => addrs[partner] + branches[partner].len() as i64 * 4,
+    };
+
+    target - addrs[idx]
+}
+
+// Branch relaxation: start every branch in its short form, then grow the tests
+// that can't reach their target until nothing changes. Returns None when a
+// loop is too large for even the long form.
+fn relax_branches(branches: &mut [Branch]) -> Option<Vec<i64>> {
+    loop {
+        let addrs = branch_addresses(branches);
+        let mut grew = false;
+
+        for idx in 0..branches.len() {
+            let offset = branch_offset(branches, &addrs, idx);
+            let branch = &mut branches[idx];
+
+            if !branch.reaches(offset) {
+                if branch.form == BranchForm::Long || branch.kind == BranchKind::Always {
+                    return None;
+                }
+
+                branch.form = BranchForm::Long;
+                grew = true;
+            }
+        }
+
+        if !grew {
+            return Some(addrs);
+        }
+    }
+}
+
+// Emit the instructions for a single, already relaxed, branch jumping `offset` bytes
+fn emit_branch(code: &mut Vec<u32>, branch: &Branch, offset: i32) {
+    match (branch.kind, branch.form) {
+        (BranchKind::Always, _) => {
+            let mut b = B;
+            encode_b_offset(&mut b, offset);
+            code.push(b);
+        }
+
+        (BranchKind::IfNonZero, BranchForm::Short) => {
+            let mut cbnz = CBNZ | W9;
+            encode_cb_offset(&mut cbnz, offset);
+            code.push(cbnz);
+        }
+
+        (BranchKind::IfNonZero, BranchForm::Long) => {
+            let mut skip = CBZ | W9;
+            encode_cb_offset(&mut skip, 8);
+
+            let mut b = B;
+            encode_b_offset(&mut b, offset - 4);
+
+            code.extend_from_slice(&[skip, b]);
+        }
+    }
+}
+
+// Compile Brainfuck IR to AArch64 machine code. The generated function takes
+// the tape pointer in x0, the input and output file descriptors in x1 and x2,
+// and returns the final tape pointer, also in x0.
+pub fn compile(ir: impl IntoIterator<Item = IRInsn>) -> Result<Vec<u8>, ()> {
+    compile_mapped(ir).map(|(code, _)| code)
+}
+
+// Choices the code generator leaves to its caller
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    // Count loop back-edges against the Fuel passed in as a fourth argument, in x3,
+    // and stop once it runs out
+    pub count_steps: bool,
+}
+
+// Same as compile, also handing back where each IR instruction's code starts,
+// with one extra entry at the end for where the function's epilogue starts
+pub fn compile_mapped(ir: impl IntoIterator<Item = IRInsn>) -> Result<(Vec<u8>, Vec<usize>), ()> {
+    compile_with(ir, Options::default())
+}
+
+// Going back around a loop takes a step, running out stops at the loop's closing
+// bracket, IR instruction `idx`. Tape pointer and cells are always up to date in
+// memory, so stopping is just returning. The count goes in front of the test
+// proper, a load and branch like any other loop's, so the jump into the loop
+// still lands on the load.
+//
+//         ldrb w9, [x0]
+//         cbz  w9, .Ltest
+//         ldr  x10, [x5]
+//         subs x10, x10, #1
+//         str  x10, [x5]
+//         b.pl .Ltest
+//         mov  x10, #<idx>
+//         str  x10, [x5, #8]
+//         ret
+//     .Ltest:
+fn emit_step(code: &mut Vec<u32>, idx: usize) {
+    let idx = idx as u32;
+    let mut stop = vec![encode_move_wide(MOVZ, X10, idx & 0xffff)]; // movz x10, #<idx[15:0]>
+    if idx >> 16 != 0 {
+        stop.push(encode_move_wide(MOVK_LSL16, X10, idx >> 16)); // movk x10, #<idx[31:16]>, lsl #16
+    }
+    // The offset of ldr/str is in doublewords
+    stop.push(encode_load_store(STR_X, X10, X5) | (1 << 10)); // str x10, [x5, #8]
+    stop.push(RET); // ret
+
+    let mut skip_count = CBZ | W9;
+    encode_cb_offset(&mut skip_count, (5 + stop.len() as i32) * 4);
+    let mut skip_stop = B_PL;
+    encode_cb_offset(&mut skip_stop, (1 + stop.len() as i32) * 4);
+
+    code.extend_from_slice(&[
+        encode_load_store(LDRB, W9, X0),                   // ldrb w9, [x0]
+        skip_count,                                        // cbz w9, .Ltest
+        encode_load_store(LDR_X, X10, X5),                 // ldr x10, [x5]
+        encode_add_sub_immediate(SUBS_X_IMM, X10, X10, 1), // subs x10, x10, #1
+        encode_load_store(STR_X, X10, X5),                 // str x10, [x5]
+        skip_stop,                                         // b.pl .Ltest
+    ]);
+    code.extend_from_slice(&stop);
+}
+
+// Same as compile_mapped, with a choice of options
+pub fn compile_with(
+    ir: impl IntoIterator<Item = IRInsn>,
+    options: Options,
+) -> Result<(Vec<u8>, Vec<usize>), ()> {
+    // Straight-line code is built as a list of 32-bit instruction words, bracket
+    // branches are tracked separately and only woven in once their sizes are known
+    let mut code: Vec<u32> = Vec::with_capacity(1024);
+    let mut branches: Vec<Branch> = vec![];
+    let mut open_brackets: Vec<usize> = vec![];
+    // Word index in the straight-line code each IR instruction starts at, and
+    // how many branches the instructions before it have
+    let mut ir_starts: Vec<(usize, usize)> = vec![];
+
+    // The Fuel pointer gets out of the way of the file descriptors first
+    if options.count_steps {
+        code.push(encode_register(ORR_X_REG, X5, XZR, X3)); // mov x5, x3
+    }
+
+    // The syscalls need x1 and x2 themselves, so the file descriptors move out of the way
+    code.extend_from_slice(&[
+        encode_register(ORR_X_REG, X3, XZR, X1), // mov x3, x1
+        encode_register(ORR_X_REG, X4, XZR, X2), // mov x4, x2
+    ]);
+
+    // Iterate over IR instructions, emitting the correct machine code
+    // to the code buffer for every instruction. Once we have iterated and
+    // emitted all our machine code, buffer should be have all instructions to run
+    for (idx, ir_insn) in ir.into_iter().enumerate() {
+        ir_starts.push((code.len(), branches.len()));
+
+        match ir_insn {
+            IRInsn::IncVal(operand) => emit_value_add(&mut code, operand, true),
+
+            IRInsn::DecVal(operand) => emit_value_add(&mut code, operand, false),
+
+            IRInsn::IncPtr(operand) => emit_pointer_move(&mut code, operand, true),
+
+            IRInsn::DecPtr(operand) => emit_pointer_move(&mut code, operand, false),
+
+            IRInsn::JumpIfZero => {
+                // b <the loop's test>
+                open_brackets.push(branches.len());
+                branches.push(Branch {
+                    at: code.len(),
+                    kind: BranchKind::Always,
+                    partner: 0,
+                    form: BranchForm::Short,
+                });
+            }
+
+            IRInsn::JumpIfNonZero => {
+                let partner = open_brackets.pop().ok_or(())?;
+                if options.count_steps {
+                    emit_step(&mut code, idx);
+                }
+
+                // Load the current cell to compare against zero
+                code.push(encode_load_store(LDRB, W9, X0)); // ldrb w9, [x0]
+
+                // cbnz w9, <the loop's body>
+                branches[partner].partner = branches.len();
+                branches.push(Branch {
+                    at: code.len(),
+                    kind: BranchKind::IfNonZero,
+                    partner,
+                    form: BranchForm::Short,
+                });
+            }
+
+            IRInsn::GetChar => emit_syscall(&mut code, SYS_READ, X3),
+
+            IRInsn::PutChar => emit_syscall(&mut code, SYS_WRITE, X4),
+        }
+    }
+
+    ir_starts.push((code.len(), branches.len()));
+    code.push(RET); // ret
+
+    if !open_brackets.is_empty() {
+        return Err(());
+    }
+
+    // Pick the smallest form for every branch, then weave the branches
+    // into the straight-line code at their final addresses
+    let addrs = relax_branches(&mut branches).ok_or(())?;
+    let mut linked: Vec<u32> = Vec::with_capacity(code.len() + branches.len() * 2);
+    let mut copied = 0;
+
+    for (idx, branch) in branches.iter().enumerate() {
+        linked.extend_from_slice(&code[copied..branch.at]);
+        copied = branch.at;
+
+        let offset = branch_offset(&branches, &addrs, idx);
+        emit_branch(&mut linked, branch, offset as i32);
+    }
+
+    linked.extend_from_slice(&code[copied..]);
+
+    // AArch64 Linux always runs with little endian instruction fetch
+    let code = linked.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+
+    // Every instruction moves along by the size of the branches woven in for the
+    // instructions before it
+    let mut woven = vec![0];
+    for branch in &branches {
+        woven.push(woven.last().unwrap() + branch.len());
+    }
+
+    let ir_offsets = ir_starts
+        .iter()
+        .map(|&(start, branches_before)| (start + woven[branches_before]) * 4)
+        .collect();
+
+    Ok((code, ir_offsets))
+}
+
+// The fast compile tier, a single pass template compiler working straight off the
+// operators, like x86_64_linux::compile_template. Every operator gets a fixed
+// snippet of code, and loops are patched the moment their closing bracket is
+// reached. An opening bracket's b reaches as far as any test can, and a closing
+// bracket knows how far back its body is, so it picks the smallest test that gets there.
+pub fn compile_template(program: &Program) -> Result<Vec<u8>, ()> {
+    compile_template_mapped(program).map(|(code, _)| code)
+}
+
+// Same as compile_template, also handing back where each operator's code starts,
+// with one extra entry at the end for where the function's epilogue starts
+pub fn compile_template_mapped(program: &Program) -> Result<(Vec<u8>, Vec<usize>), ()> {
+    let mut code: Vec<u32> = Vec::with_capacity(program.code.len() * 3);
+    let mut op_offsets: Vec<usize> = Vec::with_capacity(program.code.len() + 1);
+    let mut open_brackets: Vec<usize> = vec![];
+
+    code.extend_from_slice(&[
+        encode_register(ORR_X_REG, X3, XZR, X1), // mov x3, x1
+        encode_register(ORR_X_REG, X4, XZR, X2), // mov x4, x2
+    ]);
+
+    for &op in program.code.iter() {
+        op_offsets.push(code.len() * 4);
+
+        match op {
+            Operator::IncrementPtr => emit_pointer_move(&mut code, 1, true),
+
+            Operator::DecrementPtr => emit_pointer_move(&mut code, 1, false),
+
+            Operator::IncrementValue => emit_value_add(&mut code, 1, true),
+
+            Operator::DecrementValue => emit_value_add(&mut code, 1, false),
+
+            Operator::JumpIfZero => {
+                open_brackets.push(code.len());
+                code.push(B); // b <patched below>
+            }
+
+            Operator::JumpIfNonZero => {
+                let jump = open_brackets.pop().ok_or(())?;
+                let test = code.len();
+                code.push(encode_load_store(LDRB, W9, X0)); // ldrb w9, [x0]
+
+                let mut branch = Branch {
+                    at: code.len(),
+                    kind: BranchKind::IfNonZero,
+                    partner: 0,
+                    form: BranchForm::Short,
+                };
+                let offset = (jump as i64 + 1 - code.len() as i64) * 4;
+                if !branch.reaches(offset) {
+                    branch.form = BranchForm::Long;
+                }
+                if !branch.reaches(offset) {
+                    return Err(());
+                }
+                emit_branch(&mut code, &branch, offset as i32);
+
+                // The jump lands on the test, the branch just past the jump
+                encode_b_offset(&mut code[jump], (test - jump) as i32 * 4);
+            }
+
+            Operator::GetChar => emit_syscall(&mut code, SYS_READ, X3),
+
+            Operator::PutChar => emit_syscall(&mut code, SYS_WRITE, X4),
+        }
+    }
+
+    op_offsets.push(code.len() * 4);
+    code.push(RET); // ret
+
+    if !open_brackets.is_empty() {
+        return Err(());
+    }
+
+    Ok((
+        code.iter().flat_map(|insn| insn.to_le_bytes()).collect(),
+        op_offsets,
+    ))
+}
+
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+pub struct Jit;
+
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+impl Eval for Jit {
+    type Output = JittedFunction;
+
+    // The fast compile tier, see compile_template
+    fn eval_source(src: Program) -> Result<Self::Output, ()> {
+        let ops: Vec<IRInsn> = src.code.iter().map(|&op| op.into()).collect();
+        let key = Key::new(Arch::Aarch64, "fast", &ops);
+        let (code, _) = cache::cached(&key, || compile_template_mapped(&src))?;
+
+        Ok(JittedFunction::from_code(&code))
+    }
+
+    fn eval_ir(ir: IR) -> Result<Self::Output, ()> {
+        let ir: Vec<IRInsn> = ir.into_iter().collect();
+        let key = Key::new(Arch::Aarch64, "optimised", &ir);
+        let (code, _) = cache::cached(&key, || compile_mapped(ir.iter().cloned()))?;
+
+        Ok(JittedFunction::from_code(&code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reference encodings below were produced with `llvm-mc -triple=aarch64 -show-encoding`
+
+    // Compile, leaving out the prologue every function starts with
+    fn compile_source(source: &str) -> Vec<u32> {
+        let ir: IR = Program::new(source).into();
+
+        let code: Vec<u32> = compile(ir)
+            .unwrap()
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+
+        assert_eq!(
+            code[..2],
+            [
+                0xaa0103e3, // mov x3, x1
+                0xaa0203e4, // mov x4, x2
+            ]
+        );
+
+        code[2..].to_vec()
+    }
+
+    #[test]
+    fn encodes_data_processing() {
+        assert_eq!(encode_load_store(LDRB, W9, X0), 0x39400009); // ldrb w9, [x0]
+        assert_eq!(encode_load_store(STRB, W9, X0), 0x39000009); // strb w9, [x0]
+        assert_eq!(encode_add_sub_immediate(ADD_W_IMM, W9, W9, 255), 0x1103fd29); // add w9, w9, #255
+        assert_eq!(encode_add_sub_immediate(SUB_W_IMM, W9, W9, 3), 0x51000d29); // sub w9, w9, #3
+        assert_eq!(
+            encode_add_sub_immediate(ADD_X_IMM, X0, X0, 4095),
+            0x913ffc00
+        ); // add x0, x0, #4095
+        assert_eq!(encode_add_sub_immediate(SUB_X_IMM, X0, X0, 1), 0xd1000400); // sub x0, x0, #1
+        assert_eq!(encode_register(ADD_X_REG, X0, X0, X10), 0x8b0a0000); // add x0, x0, x10
+        assert_eq!(encode_register(SUB_X_REG, X0, X0, X10), 0xcb0a0000); // sub x0, x0, x10
+        assert_eq!(encode_register(ORR_X_REG, X1, XZR, X0), 0xaa0003e1); // mov x1, x0
+        assert_eq!(encode_register(ORR_X_REG, X0, XZR, X1), 0xaa0103e0); // mov x0, x1
+    }
+
+    #[test]
+    fn encodes_move_wide() {
+        assert_eq!(encode_move_wide(MOVZ, X10, 3000), 0xd281770a); // mov x10, #3000
+        assert_eq!(encode_move_wide(MOVK_LSL16, X10, 0xffff), 0xf2bfffea); // movk x10, #65535, lsl #16
+        assert_eq!(encode_move_wide(MOVZ, X8, SYS_READ), 0xd28007e8); // mov x8, #63
+        assert_eq!(encode_move_wide(MOVZ, X8, SYS_WRITE), 0xd2800808); // mov x8, #64
+    }
+
+    #[test]
+    fn encodes_branch_offsets() {
+        let mut cbz = CBZ | W9;
+        encode_cb_offset(&mut cbz, -(1 << 20));
+        assert_eq!(cbz, 0x34800009); // cbz w9, #-1048576
+
+        let mut cbnz = CBNZ | W9;
+        encode_cb_offset(&mut cbnz, (1 << 20) - 4);
+        assert_eq!(cbnz, 0x357fffe9); // cbnz w9, #1048572
+
+        let mut b = B;
+        encode_b_offset(&mut b, -(1 << 27));
+        assert_eq!(b, 0x16000000); // b #-134217728
+
+        let mut b = B;
+        encode_b_offset(&mut b, (1 << 27) - 4);
+        assert_eq!(b, 0x15ffffff); // b #134217724
+    }
+
+    #[test]
+    fn compiles_every_instruction() {
+        assert_eq!(
+            compile_source("+++>>--<[,.]"),
+            [
+                0x39400009, // ldrb w9, [x0]
+                0x11000d29, // add w9, w9, #3
+                0x39000009, // strb w9, [x0]
+                0x91000800, // add x0, x0, #2
+                0x39400009, // ldrb w9, [x0]
+                0x51000929, // sub w9, w9, #2
+                0x39000009, // strb w9, [x0]
+                0xd1000400, // sub x0, x0, #1
+                0x1400000d, // b #52
+                0xaa0003e1, // mov x1, x0
+                0xaa0303e0, // mov x0, x3
+                0xd2800022, // mov x2, #1
+                0xd28007e8, // mov x8, #63
+                0xd4000001, // svc #0
+                0xaa0103e0, // mov x0, x1
+                0xaa0003e1, // mov x1, x0
+                0xaa0403e0, // mov x0, x4
+                0xd2800022, // mov x2, #1
+                0xd2800808, // mov x8, #64
+                0xd4000001, // svc #0
+                0xaa0103e0, // mov x0, x1
+                0x39400009, // ldrb w9, [x0]
+                0x35fffe69, // cbnz w9, #-52
+                0xd65f03c0, // ret
+            ]
+        );
+    }
+
+    // Counted loops take a step in front of the test, with the jump in skipping it
+    #[test]
+    fn counted_loops_take_a_step_per_back_edge() {
+        let options = Options { count_steps: true };
+        let code: Vec<u32> = compile_with(IR::from(Program::new("[-]")), options)
+            .unwrap()
+            .0
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+
+        assert_eq!(
+            code,
+            [
+                0xaa0303e5, // mov x5, x3
+                0xaa0103e3, // mov x3, x1
+                0xaa0203e4, // mov x4, x2
+                0x1400000d, // b <the test>
+                0x39400009, // ldrb w9, [x0]
+                0x51000529, // sub w9, w9, #1
+                0x39000009, // strb w9, [x0]
+                0x39400009, // ldrb w9, [x0]
+                0x34000109, // cbz w9, <the test>
+                0xf94000aa, // ldr x10, [x5]
+                0xf100054a, // subs x10, x10, #1
+                0xf90000aa, // str x10, [x5]
+                0x54000085, // b.pl <the test>
+                0xd280004a, // mov x10, #2
+                0xf90004aa, // str x10, [x5, #8]
+                0xd65f03c0, // ret
+                0x39400009, // ldrb w9, [x0]
+                0x35fffe69, // cbnz w9, <the body>
+                0xd65f03c0, // ret
+            ]
+        );
+    }
+
+    #[test]
+    fn templates_patch_loops_in_one_pass() {
+        let code: Vec<u32> = compile_template(&Program::new("[-]>"))
+            .unwrap()
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+
+        assert_eq!(
+            code[2..],
+            [
+                0x14000004, // b #16
+                0x39400009, // ldrb w9, [x0]
+                0x51000529, // sub w9, w9, #1
+                0x39000009, // strb w9, [x0]
+                0x39400009, // ldrb w9, [x0]
+                0x35ffff89, // cbnz w9, #-16
+                0x91000400, // add x0, x0, #1
+                0xd65f03c0, // ret
+            ]
+        );
+
+        // Bodies out of reach of a cbnz take the long form of the test
+        let code = compile_template(&Program::new(&format!("[{}]", ">+<".repeat(60_000)))).unwrap();
+        let words = code.len() / 4;
+        let word = |idx: usize| u32::from_le_bytes(code[idx * 4..idx * 4 + 4].try_into().unwrap());
+        assert_eq!(word(words - 3), 0x34000049); // cbz w9, #8
+        assert_eq!(word(words - 2) & 0xfc00_0000, B);
+    }
+
+    #[test]
+    fn large_pointer_moves_go_through_x10() {
+        assert_eq!(
+            compile_source(&"<".repeat(70_000)),
+            [
+                0xd2822e0a, // mov x10, #4464
+                0xf2a0002a, // movk x10, #1, lsl #16
+                0xcb0a0000, // sub x0, x0, x10
+                0xd65f03c0, // ret
+            ]
+        );
+    }
+
+    #[test]
+    fn long_loops_relax_their_branches() {
+        // Alternate instructions so the IR can't collapse the loop body into a few,
+        // each ">+<" is five instructions so the body is over a megabyte
+        let code = compile_source(&format!("[{}]", ">+<".repeat(60_000)));
+
+        // b <the test>, which doesn't need to grow
+        assert_eq!(code[0] & 0xfc00_0000, B);
+
+        // ldrb w9, [x0]; cbz w9, #8; b <the body>
+        let test = &code[code.len() - 4..code.len() - 1];
+        assert_eq!(test[..2], [0x39400009, 0x34000049]);
+        assert_eq!(test[2] & 0xfc00_0000, B);
+    }
+}
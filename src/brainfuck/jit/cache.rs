@@ -0,0 +1,268 @@
+// A cache of finished machine code on disk, so running the same program again
+// skips compilation. Entries are content addressed, named after a hash of
+// everything that decides what code comes out: the IR, which tier compiled it,
+// for which architecture, for what tape, by which version of brainrust and of its
+// code generators. The key itself is stored in the entry too and compared on
+// lookup, so a hash collision is just a miss.
+//
+// Every version gets a directory of its own, and directories left behind by
+// other versions are cleared out when the cache is opened, code generators change
+// between versions and old code must never be picked up.
+use super::{Arch, CODEGEN_VERSION};
+use crate::brainfuck::{ir::IRInsn, tape::TAPE_LEN};
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::PathBuf,
+    process,
+    sync::OnceLock,
+};
+
+// Bump whenever the entry layout changes
+const FORMAT_VERSION: u32 = 1;
+const MAGIC: &[u8; 4] = b"BFJC";
+
+// Which brainrust and which code generators, naming the directory entries live in
+fn version() -> String {
+    format!("{}-codegen{CODEGEN_VERSION}", env!("CARGO_PKG_VERSION"))
+}
+
+// The cache the JIT consults, if enable was called
+static CACHE: OnceLock<Cache> = OnceLock::new();
+
+// Everything the compiled code depends on, spelled out
+pub struct Key(Vec<u8>);
+
+impl Key {
+    pub fn new(arch: Arch, tier: &str, ir: &[IRInsn]) -> Self {
+        let mut key = format!("brainrust {}\n{arch:?} {tier} tape {TAPE_LEN}\n", version());
+
+        for insn in ir {
+            key.push_str(&format!("{insn:?}\n"));
+        }
+
+        Self(key.into_bytes())
+    }
+
+    // 64-bit FNV-1a, tiny and stable, unlike std's hashers which may change between releases
+    fn hash(&self) -> u64 {
+        self.0.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+        })
+    }
+}
+
+// Split `len` bytes off the front of an entry, if there are that many left
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let (taken, remaining) = rest.split_at_checked(len)?;
+    *rest = remaining;
+    Some(taken)
+}
+
+fn take_u64(rest: &mut &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(take(rest, 8)?.try_into().ok()?))
+}
+
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    // Open the cache under `base`, dropping whatever other versions left there
+    pub fn open(base: PathBuf) -> io::Result<Self> {
+        let version = version();
+        let dir = base.join(&version);
+        fs::create_dir_all(&dir)?;
+
+        for entry in fs::read_dir(&base)?.flatten() {
+            if entry.file_name() != version.as_str() && entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+
+        Ok(Self { dir })
+    }
+
+    fn path(&self, key: &Key) -> PathBuf {
+        self.dir.join(format!("{:016x}", key.hash()))
+    }
+
+    // The code and IR offsets stored for `key`, if any. Anything unreadable,
+    // truncated or written for another key counts as a miss.
+    pub fn load(&self, key: &Key) -> Option<(Vec<u8>, Vec<usize>)> {
+        let entry = fs::read(self.path(key)).ok()?;
+        let mut rest = entry.as_slice();
+
+        if take(&mut rest, 4)? != MAGIC || take(&mut rest, 4)? != FORMAT_VERSION.to_le_bytes() {
+            return None;
+        }
+
+        let key_len = take_u64(&mut rest)? as usize;
+        if take(&mut rest, key_len)? != key.0 {
+            return None;
+        }
+
+        let offset_count = take_u64(&mut rest)? as usize;
+        let ir_offsets = (0..offset_count)
+            .map(|_| take_u64(&mut rest).map(|offset| offset as usize))
+            .collect::<Option<Vec<usize>>>()?;
+
+        let code_len = take_u64(&mut rest)? as usize;
+        let code = take(&mut rest, code_len)?.to_vec();
+
+        if !rest.is_empty() {
+            return None;
+        }
+
+        Some((code, ir_offsets))
+    }
+
+    // Store code for `key`. Entries are written to a file of their own and renamed
+    // into place, so other processes never see one half written.
+    pub fn store(&self, key: &Key, code: &[u8], ir_offsets: &[usize]) -> io::Result<()> {
+        let mut entry = vec![];
+        entry.extend_from_slice(MAGIC);
+        entry.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        entry.extend_from_slice(&(key.0.len() as u64).to_le_bytes());
+        entry.extend_from_slice(&key.0);
+        entry.extend_from_slice(&(ir_offsets.len() as u64).to_le_bytes());
+        for &offset in ir_offsets {
+            entry.extend_from_slice(&(offset as u64).to_le_bytes());
+        }
+        entry.extend_from_slice(&(code.len() as u64).to_le_bytes());
+        entry.extend_from_slice(code);
+
+        let path = self.path(key);
+        let temp = path.with_extension(format!("{}.tmp", process::id()));
+
+        fs::File::create(&temp)?.write_all(&entry)?;
+        fs::rename(&temp, &path)
+    }
+}
+
+// Where the cache lives unless told otherwise, following the XDG base directory spec
+pub fn default_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|dir| dir.join("brainrust"))
+}
+
+// Have the JIT use the cache under `base` from now on
+pub fn enable(base: PathBuf) -> io::Result<()> {
+    let cache = Cache::open(base)?;
+    let _ = CACHE.set(cache);
+
+    Ok(())
+}
+
+// Look `key` up in the cache, if it's enabled, or compile and store the result.
+// Failing to store is no reason to fail the compilation, the next run just tries again.
+pub fn cached(
+    key: &Key,
+    compile: impl FnOnce() -> Result<(Vec<u8>, Vec<usize>), ()>,
+) -> Result<(Vec<u8>, Vec<usize>), ()> {
+    let Some(cache) = CACHE.get() else {
+        return compile();
+    };
+
+    if let Some(hit) = cache.load(key) {
+        return Ok(hit);
+    }
+
+    let (code, ir_offsets) = compile()?;
+    let _ = cache.store(key, &code, &ir_offsets);
+
+    Ok((code, ir_offsets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck::{ir::IR, program::Program};
+
+    fn temp_base(name: &str) -> PathBuf {
+        let base = env::temp_dir().join(format!("brainrust-cache-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&base);
+        base
+    }
+
+    fn ir(src: &str) -> Vec<IRInsn> {
+        IR::from(Program::new(src)).into_iter().collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let base = temp_base("round-trip");
+        let cache = Cache::open(base.clone()).unwrap();
+
+        let ir = ir("++[>+<-].");
+        let key = Key::new(Arch::Riscv64, "optimised", &ir);
+        assert_eq!(cache.load(&key), None);
+
+        let (code, ir_offsets) = Arch::Riscv64
+            .compile_mapped(ir.iter().cloned().collect())
+            .unwrap();
+        cache.store(&key, &code, &ir_offsets).unwrap();
+        assert_eq!(cache.load(&key), Some((code, ir_offsets)));
+
+        // Anything else the code depends on is a different entry
+        let other_arch = Key::new(Arch::Aarch64, "optimised", &ir);
+        let other_tier = Key::new(Arch::Riscv64, "fast", &ir);
+        let other_ir = Key::new(Arch::Riscv64, "optimised", &ir[1..]);
+        assert_eq!(cache.load(&other_arch), None);
+        assert_eq!(cache.load(&other_tier), None);
+        assert_eq!(cache.load(&other_ir), None);
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn corrupt_entries_miss() {
+        let base = temp_base("corrupt");
+        let cache = Cache::open(base.clone()).unwrap();
+
+        let key = Key::new(Arch::X86_64, "optimised", &ir("+."));
+        cache.store(&key, &[0xc3], &[0, 1]).unwrap();
+
+        let path = cache.path(&key);
+        let entry = fs::read(&path).unwrap();
+        fs::write(&path, &entry[..entry.len() - 1]).unwrap();
+        assert_eq!(cache.load(&key), None);
+
+        // Same name, another key, as if the hashes collided
+        let mut entry = entry;
+        let last_key_byte = 16 + key.0.len() - 1;
+        entry[last_key_byte] ^= 1;
+        fs::write(&path, &entry).unwrap();
+        assert_eq!(cache.load(&key), None);
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn other_versions_are_dropped() {
+        let base = temp_base("versions");
+        // Older brainrust, and this brainrust before a code generator changed
+        let old_codegen = format!(
+            "{}-codegen{}",
+            env!("CARGO_PKG_VERSION"),
+            CODEGEN_VERSION - 1
+        );
+        for old in ["0.0.0-old", &old_codegen] {
+            fs::create_dir_all(base.join(old)).unwrap();
+            fs::write(base.join(old).join("0123456789abcdef"), b"stale").unwrap();
+        }
+
+        Cache::open(base.clone()).unwrap();
+
+        let dirs: Vec<_> = fs::read_dir(&base)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(dirs, [version().as_str()]);
+
+        fs::remove_dir_all(base).unwrap();
+    }
+}
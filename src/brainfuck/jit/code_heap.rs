@@ -0,0 +1,280 @@
+// Where JittedFunctions keep their machine code. Rather than every function getting
+// pages of its own, functions are carved out of chunks shared with many others,
+// and the space goes back to its chunk to be reused once the function is dropped.
+//
+// Every chunk is a memfd mapped twice, once read/write for copying code in, and
+// once read/execute for running it. No page is ever writable and executable at
+// the same time, and copying in new code never changes the protection of pages
+// other functions may be running from.
+use nix::{
+    sys::{
+        memfd::{memfd_create, MemFdCreateFlag},
+        mman::{mmap, munmap, MapFlags, ProtFlags},
+    },
+    unistd::ftruncate,
+};
+use std::{
+    ffi::{c_char, c_void},
+    num::NonZero,
+    ptr::NonNull,
+    slice,
+    sync::Mutex,
+};
+
+// Chunks are at least this big, a multiple of every page size Linux uses
+const CHUNK_SIZE: usize = 64 * 1024;
+// Every function starts on this boundary, which branch predictors like
+const ALIGN: usize = 16;
+
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+extern "C" {
+    // Provided by compiler-rt/libgcc, cleans the data cache and invalidates
+    // the instruction cache for a range of freshly written code
+    fn __clear_cache(start: *mut c_char, end: *mut c_char);
+}
+
+// Every chunk there is, any thread may compile or drop functions
+static HEAP: Mutex<Vec<Chunk>> = Mutex::new(vec![]);
+
+struct Chunk {
+    // Where the two views of the chunk are mapped
+    write: usize,
+    exec: usize,
+    len: usize,
+    // Unused ranges as (offset, length), in order, with no two touching
+    free: Vec<(usize, usize)>,
+}
+
+impl Chunk {
+    fn map(len: usize) -> Self {
+        let fd = memfd_create(c"brainrust-jit", MemFdCreateFlag::MFD_CLOEXEC)
+            .expect("Failed to get memory from OS for JIT compilation!");
+        ftruncate(&fd, len as i64).expect("Failed to get memory from OS for JIT compilation!");
+
+        // Both views share the same pages, the file itself can go once they're mapped
+        let view = |prot| unsafe {
+            mmap(
+                None,
+                NonZero::new_unchecked(len),
+                prot,
+                MapFlags::MAP_SHARED,
+                &fd,
+                0,
+            )
+            .expect("Failed to get executable memory from OS for JIT compilation!")
+            .as_ptr() as usize
+        };
+
+        Chunk {
+            write: view(ProtFlags::PROT_READ | ProtFlags::PROT_WRITE),
+            exec: view(ProtFlags::PROT_READ | ProtFlags::PROT_EXEC),
+            len,
+            free: vec![(0, len)],
+        }
+    }
+
+    fn unmap(self) {
+        for view in [self.write, self.exec] {
+            unsafe {
+                munmap(NonNull::new_unchecked(view as *mut c_void), self.len)
+                    .expect("Failed to release memory back to OS!");
+            }
+        }
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        (self.exec..self.exec + self.len).contains(&addr)
+    }
+
+    fn is_unused(&self) -> bool {
+        self.free == [(0, self.len)]
+    }
+
+    // Take `len` bytes from the first free range big enough, returning their offset
+    fn allocate(&mut self, len: usize) -> Option<usize> {
+        let idx = self.free.iter().position(|&(_, free)| free >= len)?;
+        let (offset, free) = self.free[idx];
+
+        if free == len {
+            self.free.remove(idx);
+        } else {
+            self.free[idx] = (offset + len, free - len);
+        }
+
+        Some(offset)
+    }
+
+    // Give back `len` bytes at `offset`, merging with the free ranges either side
+    fn release(&mut self, offset: usize, len: usize) {
+        let idx = self.free.partition_point(|&(free, _)| free < offset);
+        self.free.insert(idx, (offset, len));
+
+        if let Some(&(next, next_len)) = self.free.get(idx + 1) {
+            if offset + len == next {
+                self.free[idx].1 += next_len;
+                self.free.remove(idx + 1);
+            }
+        }
+
+        if idx > 0 {
+            let (prev, prev_len) = self.free[idx - 1];
+            if prev + prev_len == offset {
+                self.free[idx - 1].1 += self.free[idx].1;
+                self.free.remove(idx);
+            }
+        }
+    }
+}
+
+// A function's code in the heap, given back to its chunk when dropped
+pub struct CodeBlock {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// Nothing writes to a block's code once it is allocated, so sharing it between
+// threads is as safe as sharing any other immutable data
+unsafe impl Send for CodeBlock {}
+unsafe impl Sync for CodeBlock {}
+
+impl CodeBlock {
+    // Where the code can be called
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr()
+    }
+
+    pub fn code(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+// Copy finished machine code into the heap, ready to run
+pub fn allocate(code: &[u8]) -> CodeBlock {
+    let len = code.len().next_multiple_of(ALIGN);
+    let mut chunks = HEAP.lock().unwrap();
+
+    let (chunk, offset) = match chunks
+        .iter_mut()
+        .enumerate()
+        .find_map(|(idx, chunk)| Some((idx, chunk.allocate(len)?)))
+    {
+        Some(found) => found,
+        None => {
+            let mut chunk = Chunk::map(len.next_multiple_of(CHUNK_SIZE));
+            let offset = chunk.allocate(len).unwrap();
+            chunks.push(chunk);
+
+            (chunks.len() - 1, offset)
+        }
+    };
+
+    let chunk = &chunks[chunk];
+    let exec = (chunk.exec + offset) as *mut u8;
+
+    unsafe {
+        slice::from_raw_parts_mut((chunk.write + offset) as *mut u8, code.len())
+            .copy_from_slice(code);
+    }
+
+    // Unlike x86, AArch64 and RISC-V don't keep the instruction cache coherent
+    // with data writes, so the new code has to be flushed through before running it
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    unsafe {
+        __clear_cache(exec.cast(), exec.add(code.len()).cast());
+    }
+
+    CodeBlock {
+        ptr: NonNull::new(exec).unwrap(),
+        len: code.len(),
+    }
+}
+
+impl Drop for CodeBlock {
+    fn drop(&mut self) {
+        let addr = self.ptr.as_ptr() as usize;
+        let mut chunks = HEAP.lock().unwrap();
+
+        let idx = chunks
+            .iter()
+            .position(|chunk| chunk.contains(addr))
+            .unwrap();
+        let chunk = &mut chunks[idx];
+        chunk.release(addr - chunk.exec, self.len.next_multiple_of(ALIGN));
+
+        // Keep one empty chunk around for whatever gets compiled next, hand any
+        // others back to the OS
+        if chunk.is_unused() && chunks.iter().filter(|chunk| chunk.is_unused()).count() > 1 {
+            chunks.swap_remove(idx).unmap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A chunk that only exists on paper, for exercising the bookkeeping
+    fn unmapped(len: usize) -> Chunk {
+        Chunk {
+            write: 0,
+            exec: 0,
+            len,
+            free: vec![(0, len)],
+        }
+    }
+
+    #[test]
+    fn free_ranges_merge() {
+        let mut chunk = unmapped(64);
+        let offsets: Vec<usize> = (0..4).map(|_| chunk.allocate(16).unwrap()).collect();
+        assert_eq!(offsets, [0, 16, 32, 48]);
+        assert_eq!(chunk.allocate(16), None);
+
+        chunk.release(16, 16);
+        chunk.release(48, 16);
+        assert_eq!(chunk.free, [(16, 16), (48, 16)]);
+
+        // Filling the gap between two free ranges merges all three
+        chunk.release(32, 16);
+        assert_eq!(chunk.free, [(16, 48)]);
+
+        chunk.release(0, 16);
+        assert!(chunk.is_unused());
+    }
+
+    #[test]
+    fn first_fit() {
+        let mut chunk = unmapped(64);
+        for _ in 0..4 {
+            chunk.allocate(16).unwrap();
+        }
+        chunk.release(0, 16);
+        chunk.release(32, 32);
+
+        assert_eq!(chunk.allocate(32), Some(32));
+        assert_eq!(chunk.allocate(16), Some(0));
+        assert_eq!(chunk.free, []);
+    }
+
+    #[test]
+    fn functions_share_chunks() {
+        let code = [0xc3; 20];
+        let blocks: Vec<CodeBlock> = (0..100).map(|_| allocate(&code)).collect();
+
+        // A hundred small functions fit in a single chunk, or two should tests
+        // running alongside have taken the rest of one
+        let addrs: Vec<usize> = blocks.iter().map(|block| block.as_ptr() as usize).collect();
+        let chunks = HEAP
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|chunk| addrs.iter().any(|&addr| chunk.contains(addr)))
+            .count();
+        assert!(chunks <= 2);
+
+        for block in &blocks {
+            assert_eq!(block.code(), code);
+            assert_eq!(block.as_ptr() as usize % ALIGN, 0);
+        }
+    }
+}
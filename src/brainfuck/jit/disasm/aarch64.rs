@@ -0,0 +1,163 @@
+use super::Insn;
+
+// Register `idx` as a 64 or 32 bit register, 31 being the zero register
+fn register(idx: u32, wide: bool) -> String {
+    match (idx, wide) {
+        (31, true) => "xzr".to_string(),
+        (31, false) => "wzr".to_string(),
+        (idx, true) => format!("x{idx}"),
+        (idx, false) => format!("w{idx}"),
+    }
+}
+
+// Sign extend the lowest `bits` bits of `value`
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+// Decode one instruction word, None for anything the backend doesn't emit
+fn decode_word(insn: u32, offset: usize) -> Option<Insn> {
+    let rd = insn & 0x1f;
+    let rn = (insn >> 5) & 0x1f;
+    let rm = (insn >> 16) & 0x1f;
+    let wide = insn >> 31 == 1;
+    let op = if (insn >> 30) & 1 == 0 { "add" } else { "sub" };
+
+    let target = |imm: i32| (offset as i64 + imm as i64 * 4) as usize;
+
+    let text = if insn == 0xd65f_03c0 {
+        "ret".to_string()
+    } else if insn & 0xffe0_001f == 0xd400_0001 {
+        format!("svc #{}", (insn >> 5) & 0xffff)
+    } else if insn & 0xffc0_0000 == 0x3940_0000 || insn & 0xffc0_0000 == 0x3900_0000 {
+        let mnemonic = if insn & 0x0040_0000 != 0 {
+            "ldrb"
+        } else {
+            "strb"
+        };
+        let imm = (insn >> 10) & 0xfff;
+        let address = if imm == 0 {
+            format!("[x{rn}]")
+        } else {
+            format!("[x{rn}, #{imm}]")
+        };
+
+        format!("{mnemonic} {}, {address}", register(rd, false))
+    } else if insn & 0xffc0_0000 == 0xf940_0000 || insn & 0xffc0_0000 == 0xf900_0000 {
+        // 64 bit ldr/str, with the offset in doublewords
+        let mnemonic = if insn & 0x0040_0000 != 0 {
+            "ldr"
+        } else {
+            "str"
+        };
+        let imm = ((insn >> 10) & 0xfff) * 8;
+        let address = if imm == 0 {
+            format!("[x{rn}]")
+        } else {
+            format!("[x{rn}, #{imm}]")
+        };
+
+        format!("{mnemonic} {}, {address}", register(rd, true))
+    } else if insn & 0x1f80_0000 == 0x1100_0000 {
+        // add/sub (immediate), setting flags or not, never shifted
+        let flags = if (insn >> 29) & 1 == 1 { "s" } else { "" };
+        let imm = (insn >> 10) & 0xfff;
+        format!(
+            "{op}{flags} {}, {}, #{imm}",
+            register(rd, wide),
+            register(rn, wide)
+        )
+    } else if insn & 0x3fe0_fc00 == 0x0b00_0000 {
+        // add/sub (shifted register), never setting flags and never shifted
+        format!(
+            "{op} {}, {}, {}",
+            register(rd, wide),
+            register(rn, wide),
+            register(rm, wide)
+        )
+    } else if insn & 0x7fe0_fc00 == 0x2a00_0000 {
+        // orr (shifted register), mov is an orr with the zero register
+        if rn == 31 {
+            format!("mov {}, {}", register(rd, wide), register(rm, wide))
+        } else {
+            format!(
+                "orr {}, {}, {}",
+                register(rd, wide),
+                register(rn, wide),
+                register(rm, wide)
+            )
+        }
+    } else if insn & 0x1f80_0000 == 0x1280_0000 && (insn >> 29) & 0b11 >= 2 {
+        // movz/movk, movn is never emitted
+        let mnemonic = if (insn >> 29) & 0b11 == 2 {
+            "movz"
+        } else {
+            "movk"
+        };
+        let imm = (insn >> 5) & 0xffff;
+        let shift = ((insn >> 21) & 0b11) * 16;
+
+        if shift == 0 {
+            format!("{mnemonic} {}, #{imm}", register(rd, wide))
+        } else {
+            format!("{mnemonic} {}, #{imm}, lsl #{shift}", register(rd, wide))
+        }
+    } else if insn & 0x7e00_0000 == 0x3400_0000 {
+        let mnemonic = if insn & 0x0100_0000 == 0 {
+            "cbz"
+        } else {
+            "cbnz"
+        };
+        let imm = sign_extend((insn >> 5) & 0x7_ffff, 19);
+
+        return Some(Insn::branch(
+            offset,
+            4,
+            format!("{mnemonic} {}, ", register(rd, wide)),
+            target(imm),
+        ));
+    } else if insn & 0xff00_0010 == 0x5400_0000 {
+        const CONDITIONS: [&str; 16] = [
+            "eq", "ne", "hs", "lo", "mi", "pl", "vs", "vc", "hi", "ls", "ge", "lt", "gt", "le",
+            "al", "nv",
+        ];
+        let imm = sign_extend((insn >> 5) & 0x7_ffff, 19);
+
+        return Some(Insn::branch(
+            offset,
+            4,
+            format!("b.{} ", CONDITIONS[(insn & 0xf) as usize]),
+            target(imm),
+        ));
+    } else if insn & 0xfc00_0000 == 0x1400_0000 {
+        let imm = sign_extend(insn & 0x3ff_ffff, 26);
+
+        return Some(Insn::branch(offset, 4, "b ", target(imm)));
+    } else {
+        return None;
+    };
+
+    Some(Insn::new(offset, 4, text))
+}
+
+// Decode AArch64 code from our backend, words we don't know come out as `.inst`
+pub fn decode(code: &[u8]) -> Vec<Insn> {
+    code.chunks(4)
+        .enumerate()
+        .map(|(idx, chunk)| {
+            let offset = idx * 4;
+
+            match chunk.try_into() {
+                Ok(bytes) => {
+                    let word = u32::from_le_bytes(bytes);
+                    decode_word(word, offset)
+                        .unwrap_or_else(|| Insn::new(offset, 4, format!(".inst {word:#010x}")))
+                }
+
+                // A trailing partial word can't be an instruction
+                Err(_) => Insn::new(offset, chunk.len(), format!(".byte {:#04x}", chunk[0])),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,334 @@
+// Disassemblers for exactly the instructions our backends emit, nothing more.
+// Working from the finished machine code, rather than notes kept while generating
+// it, means what gets printed is what actually runs, patched jumps and all.
+use super::Arch;
+use crate::brainfuck::ir::IRInsn;
+use std::{collections::BTreeMap, fmt::Write};
+
+pub mod aarch64;
+pub mod riscv64;
+pub mod x86_64;
+
+// A single decoded instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Insn {
+    pub offset: usize,
+    pub len: usize,
+    // GNU as syntax, missing the branch target for branches
+    pub text: String,
+    // Code offset a branch goes to, relative offsets are resolved by the decoder
+    pub target: Option<usize>,
+    // Whether GNU as assembles `text` back into the same bytes. Some of our
+    // encodings aren't the ones an assembler picks, those have to be spelled out.
+    pub canonical: bool,
+}
+
+impl Insn {
+    fn new(offset: usize, len: usize, text: impl Into<String>) -> Self {
+        Self {
+            offset,
+            len,
+            text: text.into(),
+            target: None,
+            canonical: true,
+        }
+    }
+
+    fn branch(offset: usize, len: usize, text: impl Into<String>, target: usize) -> Self {
+        Self {
+            target: Some(target),
+            ..Self::new(offset, len, text)
+        }
+    }
+}
+
+pub fn decode(arch: Arch, code: &[u8]) -> Vec<Insn> {
+    match arch {
+        Arch::X86_64 => x86_64::decode(code),
+        Arch::Riscv64 => riscv64::decode(code),
+        Arch::Aarch64 => aarch64::decode(code),
+    }
+}
+
+// Assembler directives every listing needs, and how the assembler spells comments
+fn syntax(arch: Arch) -> (&'static [&'static str], &'static str) {
+    match arch {
+        Arch::X86_64 => (&[], "#"),
+        // Keep the assembler from swapping in 2 byte compressed instructions
+        Arch::Riscv64 => (&[".option norvc"], "#"),
+        Arch::Aarch64 => (&[], "//"),
+    }
+}
+
+// Every branch target gets a label, numbered in address order
+fn labels(insns: &[Insn]) -> BTreeMap<usize, String> {
+    let mut targets: Vec<usize> = insns.iter().filter_map(|insn| insn.target).collect();
+    targets.sort_unstable();
+    targets.dedup();
+
+    targets
+        .into_iter()
+        .filter(|target| insns.iter().any(|insn| insn.offset == *target))
+        .enumerate()
+        .map(|(idx, target)| (target, format!(".L{idx}")))
+        .collect()
+}
+
+// Disassemble generated code into a GNU as source file defining it as `bf_main`,
+// which assembles back into the very same bytes
+pub fn assembly(arch: Arch, code: &[u8]) -> String {
+    let insns = decode(arch, code);
+    let labels = labels(&insns);
+    let (directives, comment) = syntax(arch);
+
+    let mut src = String::new();
+    writeln!(src, "\t.text").unwrap();
+    directives
+        .iter()
+        .for_each(|directive| writeln!(src, "\t{directive}").unwrap());
+    writeln!(src, "\t.globl bf_main").unwrap();
+    writeln!(src, "\t.type bf_main, %function").unwrap();
+    writeln!(src, "bf_main:").unwrap();
+
+    for insn in &insns {
+        if let Some(label) = labels.get(&insn.offset) {
+            writeln!(src, "{label}:").unwrap();
+        }
+
+        // Targets that aren't an instruction we know of stay relative to this one
+        let target = insn.target.map(|target| {
+            labels
+                .get(&target)
+                .cloned()
+                .unwrap_or_else(|| format!(".{:+}", target as i64 - insn.offset as i64))
+        });
+        let text = format!("{}{}", insn.text, target.unwrap_or_default());
+
+        if insn.canonical {
+            writeln!(src, "\t{text}").unwrap();
+        } else {
+            let bytes: Vec<String> = code[insn.offset..insn.offset + insn.len]
+                .iter()
+                .map(|byte| format!("{byte:#04x}"))
+                .collect();
+
+            writeln!(src, "\t.byte {} {comment} {text}", bytes.join(", ")).unwrap();
+        }
+    }
+
+    writeln!(src, "\t.size bf_main, .-bf_main").unwrap();
+
+    src
+}
+
+// Disassemble code mapped at address `base`, one instruction per line with its
+// address, bytes, and the IR instruction it was generated for. `ir_offsets` is
+// where each IR instruction's code starts, plus where the epilogue starts, as
+// handed back by the compile_mapped functions.
+pub fn dump(arch: Arch, code: &[u8], base: usize, ir: &[IRInsn], ir_offsets: &[usize]) -> String {
+    let (_, comment) = syntax(arch);
+    let mut out = String::new();
+
+    for insn in decode(arch, code) {
+        let bytes: Vec<String> = code[insn.offset..insn.offset + insn.len]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        let target = insn
+            .target
+            .map(|target| format!("{:#x}", base + target))
+            .unwrap_or_default();
+        let text = format!("{}{}", insn.text, target);
+
+        // The last IR instruction starting at or before this one is where it came
+        // from, anything before the first is the function's prologue
+        let idx = ir_offsets.partition_point(|&start| start <= insn.offset);
+        let origin = match idx.checked_sub(1) {
+            None => "prologue".to_string(),
+            Some(idx) if idx < ir.len() => format!("{idx}: {:?}", ir[idx]),
+            _ => "epilogue".to_string(),
+        };
+
+        writeln!(
+            out,
+            "{:#018x}  {:<21}  {text:<32} {comment} {origin}",
+            base + insn.offset,
+            bytes.join(" ")
+        )
+        .unwrap();
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck::{
+        ir::IR,
+        jit::{aarch64_linux, riscv64_linux, x86_64_linux},
+        program::Program,
+    };
+
+    // Compile with every backend, counting steps or not
+    fn compile_every_backend(source: &str) -> Vec<(Arch, Vec<u8>)> {
+        let ir = || IR::from(Program::new(source));
+        let x86_64 = x86_64_linux::Options {
+            count_steps: true,
+            ..Default::default()
+        };
+        let riscv64 = riscv64_linux::Options { count_steps: true };
+        let aarch64 = aarch64_linux::Options { count_steps: true };
+
+        vec![
+            (Arch::X86_64, Arch::X86_64.compile(ir()).unwrap()),
+            (Arch::Riscv64, Arch::Riscv64.compile(ir()).unwrap()),
+            (Arch::Aarch64, Arch::Aarch64.compile(ir()).unwrap()),
+            (
+                Arch::X86_64,
+                x86_64_linux::compile_with(ir(), x86_64).unwrap().0,
+            ),
+            (
+                Arch::Riscv64,
+                riscv64_linux::compile_with(ir(), riscv64).unwrap().0,
+            ),
+            (
+                Arch::Aarch64,
+                aarch64_linux::compile_with(ir(), aarch64).unwrap().0,
+            ),
+        ]
+    }
+
+    // Every backend's output decodes completely, with no bytes left over
+    // and no instructions the disassembler had to give up on
+    #[test]
+    fn decodes_every_backend() {
+        for source in [
+            include_str!("../../../../test_programs/hello_world.bf"),
+            include_str!("../../../../test_programs/3out.bf"),
+            ">>>>>>>>>>[<<<<<<<<<<+>>>>>>>>>>-]",
+        ] {
+            for (arch, code) in compile_every_backend(source) {
+                let insns = decode(arch, &code);
+
+                let mut offset = 0;
+                for insn in &insns {
+                    assert_eq!(insn.offset, offset);
+                    assert!(!insn.text.starts_with('.'), "{arch:?}: {}", insn.text);
+                    offset += insn.len;
+                }
+                assert_eq!(offset, code.len());
+
+                // Every loop branches somewhere inside the function
+                assert!(insns
+                    .iter()
+                    .filter_map(|insn| insn.target)
+                    .all(|target| target < code.len()));
+            }
+        }
+    }
+
+    // Every line is attributed to the IR instruction that generated it,
+    // branches woven in after the fact included
+    #[test]
+    fn dump_origins() {
+        let ir: Vec<IRInsn> = IR::from(Program::new("+[>-]")).into_iter().collect();
+
+        for arch in [Arch::X86_64, Arch::Riscv64, Arch::Aarch64] {
+            let (code, ir_offsets) = arch.compile_mapped(ir.iter().cloned().collect()).unwrap();
+            let dump = dump(arch, &code, 0x1000, &ir, &ir_offsets);
+
+            let origins: Vec<&str> = dump
+                .lines()
+                .map(|line| line.rsplit_once(['#', '/']).unwrap().1.trim())
+                .collect();
+            let mut expected = origins.clone();
+            expected.dedup();
+
+            assert_eq!(
+                expected,
+                [
+                    "prologue",
+                    "0: IncVal(1)",
+                    "1: JumpIfZero",
+                    "2: IncPtr(1)",
+                    "3: DecVal(1)",
+                    "4: JumpIfNonZero",
+                    "epilogue"
+                ],
+                "{arch:?}"
+            );
+            assert!(dump.starts_with("0x0000000000001000  "));
+        }
+    }
+
+    #[test]
+    fn labels_loops() {
+        let code = Arch::X86_64
+            .compile(IR::from(Program::new("+[-]")))
+            .unwrap();
+        let src = assembly(Arch::X86_64, &code);
+
+        assert_eq!(
+            src,
+            "\t.text
+\t.globl bf_main
+\t.type bf_main, %function
+bf_main:
+\tmov %rsi, %r8
+\tmov %rdx, %r9
+\tmovzbl (%rdi), %eax
+\tadd $1, %al
+\tmov %al, (%rdi)
+\tjmp .L1
+.L0:
+\tsub $1, %al
+\tmov %al, (%rdi)
+.L1:
+\ttest %al, %al
+\tjnz .L0
+\tmov %rdi, %rax
+\tret
+\t.size bf_main, .-bf_main
+"
+        );
+    }
+
+    // Branches only take 6 bytes when 2 can't reach, and aligned loop bodies start
+    // on 16 byte boundaries, however the branches around them ended up
+    #[test]
+    fn relaxes_and_aligns_loops() {
+        // The outer loop's body is too long for an 8 bit displacement
+        let source = format!("+[>[-]{}<-]", "+>".repeat(40));
+        let ir: Vec<IRInsn> = IR::from(Program::new(&source)).into_iter().collect();
+        let options = x86_64_linux::Options {
+            align_loops: true,
+            ..Default::default()
+        };
+        let (code, _, ir_offsets) =
+            x86_64_linux::compile_with(ir.iter().cloned(), options).unwrap();
+        let insns = decode(Arch::X86_64, &code);
+
+        let branches: Vec<(&str, usize)> = insns
+            .iter()
+            .filter(|insn| insn.target.is_some())
+            .map(|insn| (insn.text.as_str(), insn.len))
+            .collect();
+        assert_eq!(
+            branches,
+            [
+                ("{disp32} jmp ", 5),
+                ("jmp ", 2),
+                ("jnz ", 2),
+                ("{disp32} jnz ", 6)
+            ]
+        );
+
+        // Both loop bodies, right after their opening brackets' jumps
+        for bracket in [1, 3] {
+            assert_eq!(ir_offsets[bracket + 1] % 16, 0);
+        }
+        assert!(insns.iter().all(|insn| !insn.text.starts_with('.')));
+    }
+}
@@ -0,0 +1,119 @@
+use super::Insn;
+
+const REGISTERS: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+// Sign extend the lowest `bits` bits of `value`
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+// Decode one instruction word, None for anything the backend doesn't emit
+fn decode_word(insn: u32, offset: usize) -> Option<Insn> {
+    let opcode = insn & 0x7f;
+    let rd = REGISTERS[((insn >> 7) & 0x1f) as usize];
+    let funct3 = (insn >> 12) & 0b111;
+    let rs1 = REGISTERS[((insn >> 15) & 0x1f) as usize];
+    let rs2 = REGISTERS[((insn >> 20) & 0x1f) as usize];
+    let funct7 = insn >> 25;
+
+    let i_imm = sign_extend(insn >> 20, 12);
+    let s_imm = sign_extend(((insn >> 25) << 5) | ((insn >> 7) & 0x1f), 12);
+    let b_imm = sign_extend(
+        ((insn >> 31) << 12)
+            | (((insn >> 7) & 0b1) << 11)
+            | (((insn >> 25) & 0b11_1111) << 5)
+            | (((insn >> 8) & 0b1111) << 1),
+        13,
+    );
+    let j_imm = sign_extend(
+        ((insn >> 31) << 20)
+            | (((insn >> 12) & 0xff) << 12)
+            | (((insn >> 20) & 0b1) << 11)
+            | (((insn >> 21) & 0x3ff) << 1),
+        21,
+    );
+    let u_imm = insn >> 12;
+
+    let target = |imm: i32| (offset as i64 + imm as i64) as usize;
+
+    let text = match (opcode, funct3) {
+        (0x03, 0b000) => format!("lb {rd}, {i_imm}({rs1})"),
+        (0x23, 0b000) => format!("sb {rs2}, {s_imm}({rs1})"),
+        (0x03, 0b011) => format!("ld {rd}, {i_imm}({rs1})"),
+        (0x23, 0b011) => format!("sd {rs2}, {s_imm}({rs1})"),
+
+        // li and mv are plain addis, and assemble back into exactly that
+        (0x13, 0b000) if rs1 == "zero" => format!("li {rd}, {i_imm}"),
+        (0x13, 0b000) if i_imm == 0 => format!("mv {rd}, {rs1}"),
+        (0x13, 0b000) => format!("addi {rd}, {rs1}, {i_imm}"),
+        (0x13, 0b001) if funct7 >> 1 == 0 => format!("slli {rd}, {rs1}, {}", i_imm & 0x3f),
+        (0x13, 0b101) if funct7 >> 1 == 0 => format!("srli {rd}, {rs1}, {}", i_imm & 0x3f),
+        (0x1b, 0b000) => format!("addiw {rd}, {rs1}, {i_imm}"),
+
+        (0x33, 0b000) if funct7 == 0 => format!("add {rd}, {rs1}, {rs2}"),
+        (0x33, 0b000) if funct7 == 0b010_0000 => format!("sub {rd}, {rs1}, {rs2}"),
+
+        (0x37, _) => format!("lui {rd}, {u_imm:#x}"),
+        (0x17, _) => format!("auipc {rd}, {u_imm:#x}"),
+
+        (0x63, 0b000 | 0b001 | 0b101) => {
+            let mnemonic = match funct3 {
+                0b000 => "beq",
+                0b001 => "bne",
+                _ => "bge",
+            };
+            let text = if rs2 == "zero" {
+                format!("{mnemonic}z {rs1}, ")
+            } else {
+                format!("{mnemonic} {rs1}, {rs2}, ")
+            };
+
+            return Some(Insn::branch(offset, 4, text, target(b_imm)));
+        }
+
+        (0x6f, _) => {
+            let text = if rd == "zero" {
+                "j ".to_string()
+            } else {
+                format!("jal {rd}, ")
+            };
+
+            return Some(Insn::branch(offset, 4, text, target(j_imm)));
+        }
+
+        (0x67, 0b000) if rd == "zero" && rs1 == "ra" && i_imm == 0 => "ret".to_string(),
+        (0x67, 0b000) => format!("jalr {rd}, {i_imm}({rs1})"),
+
+        (0x73, 0b000) if insn == 0x73 => "ecall".to_string(),
+
+        _ => return None,
+    };
+
+    Some(Insn::new(offset, 4, text))
+}
+
+// Decode RV64I code from our backend, words we don't know come out as `.word`
+pub fn decode(code: &[u8]) -> Vec<Insn> {
+    code.chunks(4)
+        .enumerate()
+        .map(|(idx, chunk)| {
+            let offset = idx * 4;
+
+            match chunk.try_into() {
+                Ok(bytes) => {
+                    let word = u32::from_le_bytes(bytes);
+                    decode_word(word, offset)
+                        .unwrap_or_else(|| Insn::new(offset, 4, format!(".word {word:#010x}")))
+                }
+
+                // A trailing partial word can't be an instruction
+                Err(_) => Insn::new(offset, chunk.len(), format!(".byte {:#04x}", chunk[0])),
+            }
+        })
+        .collect()
+}
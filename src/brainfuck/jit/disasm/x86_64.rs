@@ -0,0 +1,181 @@
+use super::Insn;
+
+// The NOPs loops are aligned with, spelled so GNU as picks the same encoding
+const NOPS: [(&[u8], &str); 9] = [
+    (&[0x90], "nop"),
+    (&[0x66, 0x90], "xchg %ax, %ax"),
+    (&[0x0f, 0x1f, 0x00], "nopl (%rax)"),
+    (&[0x0f, 0x1f, 0x40, 0x00], "{disp8} nopl 0(%rax)"),
+    (
+        &[0x0f, 0x1f, 0x44, 0x00, 0x00],
+        "{disp8} nopl 0(%rax,%rax,1)",
+    ),
+    (
+        &[0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00],
+        "{disp8} nopw 0(%rax,%rax,1)",
+    ),
+    (
+        &[0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00],
+        "{disp32} nopl 0(%rax)",
+    ),
+    (
+        &[0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+        "{disp32} nopl 0(%rax,%rax,1)",
+    ),
+    (
+        &[0x66, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+        "{disp32} nopw 0(%rax,%rax,1)",
+    ),
+];
+
+fn imm32(bytes: &[u8]) -> i32 {
+    i32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+// Decode one instruction, None for anything the backends don't emit
+fn decode_one(code: &[u8], offset: usize) -> Option<Insn> {
+    let bytes = &code[offset..];
+    let at = |len: usize| bytes.get(..len);
+
+    // Branch targets are relative to the end of the instruction
+    let relative = |len: usize, rel: i32| (offset as i64 + len as i64 + rel as i64) as usize;
+
+    if let Some((nop, text)) = NOPS.iter().find(|(nop, _)| bytes.starts_with(nop)) {
+        return Some(Insn::new(offset, nop.len(), *text));
+    }
+
+    let insn = match *at(1)? {
+        [0x57] => Insn::new(offset, 1, "push %rdi"),
+        [0x5f] => Insn::new(offset, 1, "pop %rdi"),
+        [0xc3] => Insn::new(offset, 1, "ret"),
+
+        _ => match *at(2)? {
+            [0x0f, 0x05] => Insn::new(offset, 2, "syscall"),
+            [0xfe, 0x07] => Insn::new(offset, 2, "incb (%rdi)"),
+            [0xfe, 0x0f] => Insn::new(offset, 2, "decb (%rdi)"),
+            [0x8a, 0x07] => Insn::new(offset, 2, "mov (%rdi), %al"),
+            [0x88, 0x07] => Insn::new(offset, 2, "mov %al, (%rdi)"),
+            [0x84, 0xc0] => Insn::new(offset, 2, "test %al, %al"),
+            [0x04, imm] => Insn::new(offset, 2, format!("add ${imm}, %al")),
+            [0x2c, imm] => Insn::new(offset, 2, format!("sub ${imm}, %al")),
+
+            // Both sizes of jmp, jz, jnz and jns. GNU as uses the 8 bit form whenever the
+            // target is close enough, which is exactly when we do, {disp32} keeps the 32
+            // bit displacement of branches that had to be grown.
+            [cc @ (0x74 | 0x75 | 0x79), rel] => {
+                let mnemonic = condition(cc);
+
+                Insn::branch(
+                    offset,
+                    2,
+                    format!("{mnemonic} "),
+                    relative(2, rel as i8 as i32),
+                )
+            }
+
+            [0x0f, cc @ (0x84 | 0x85 | 0x89)] => {
+                let mnemonic = condition(cc);
+                let rel = imm32(bytes.get(2..6)?);
+
+                Insn::branch(
+                    offset,
+                    6,
+                    format!("{{disp32}} {mnemonic} "),
+                    relative(6, rel),
+                )
+            }
+
+            [0xeb, rel] => Insn::branch(offset, 2, "jmp ", relative(2, rel as i8 as i32)),
+
+            [0xe9, ..] => {
+                let rel = imm32(bytes.get(1..5)?);
+                Insn::branch(offset, 5, "{disp32} jmp ", relative(5, rel))
+            }
+
+            [0xe8, ..] => {
+                let rel = imm32(bytes.get(1..5)?);
+                Insn::branch(offset, 5, "call ", relative(5, rel))
+            }
+
+            _ => match *at(3)? {
+                [0x48, 0xff, 0xc7] => Insn::new(offset, 3, "incq %rdi"),
+                [0x48, 0xff, 0xcf] => Insn::new(offset, 3, "decq %rdi"),
+                [0x48, 0x89, 0xfe] => Insn::new(offset, 3, "mov %rdi, %rsi"),
+                [0x48, 0x89, 0xf8] => Insn::new(offset, 3, "mov %rdi, %rax"),
+                [0x49, 0x89, 0xf0] => Insn::new(offset, 3, "mov %rsi, %r8"),
+                [0x49, 0x89, 0xd1] => Insn::new(offset, 3, "mov %rdx, %r9"),
+                [0x49, 0x89, 0xca] => Insn::new(offset, 3, "mov %rcx, %r10"),
+                [0x4c, 0x89, 0xc7] => Insn::new(offset, 3, "mov %r8, %rdi"),
+                [0x4c, 0x89, 0xcf] => Insn::new(offset, 3, "mov %r9, %rdi"),
+                [0x0f, 0xb6, 0x3f] => Insn::new(offset, 3, "movzbl (%rdi), %edi"),
+                [0x0f, 0xb6, 0x07] => Insn::new(offset, 3, "movzbl (%rdi), %eax"),
+                [0x80, 0x07, imm] => Insn::new(offset, 3, format!("addb ${imm}, (%rdi)")),
+                [0x80, 0x2f, imm] => Insn::new(offset, 3, format!("subb ${imm}, (%rdi)")),
+                [0x80, 0x3f, imm] => Insn::new(offset, 3, format!("cmpb ${imm}, (%rdi)")),
+
+                [0x48, 0x81, modrm @ (0xc7 | 0xef)] => {
+                    let mnemonic = if modrm == 0xc7 { "addq" } else { "subq" };
+                    let imm = imm32(bytes.get(3..7)?);
+
+                    // Immediates that fit a byte have a shorter form, which is
+                    // what an assembler reaches for given the choice
+                    Insn {
+                        canonical: i8::try_from(imm).is_err(),
+                        ..Insn::new(offset, 7, format!("{mnemonic} ${imm}, %rdi"))
+                    }
+                }
+
+                [0x49, 0x83, 0x2a] => {
+                    let imm = *bytes.get(3)? as i8;
+                    Insn::new(offset, 4, format!("subq ${imm}, (%r10)"))
+                }
+
+                [0x49, 0xc7, 0x42] if bytes.get(3) == Some(&0x08) => {
+                    let imm = imm32(bytes.get(4..8)?);
+                    Insn::new(offset, 8, format!("movq ${imm}, 8(%r10)"))
+                }
+
+                [0x48, 0xc7, modrm @ (0xc0 | 0xc7 | 0xc2)] => {
+                    let register = match modrm {
+                        0xc0 => "%rax",
+                        0xc7 => "%rdi",
+                        _ => "%rdx",
+                    };
+                    let imm = imm32(bytes.get(3..7)?);
+
+                    Insn::new(offset, 7, format!("movq ${imm}, {register}"))
+                }
+
+                _ => return None,
+            },
+        },
+    };
+
+    Some(insn)
+}
+
+// A conditional branch's mnemonic, by the low nibble of its opcode
+fn condition(opcode: u8) -> &'static str {
+    match opcode & 0xf {
+        0x4 => "jz",
+        0x5 => "jnz",
+        _ => "jns",
+    }
+}
+
+// Decode x86-64 code from our backend, bytes that don't start
+// an instruction we know of come out as a single `.byte` each
+pub fn decode(code: &[u8]) -> Vec<Insn> {
+    let mut insns = vec![];
+    let mut offset = 0;
+
+    while offset < code.len() {
+        let insn = decode_one(code, offset)
+            .unwrap_or_else(|| Insn::new(offset, 1, format!(".byte {:#04x}", code[offset])));
+
+        offset += insn.len;
+        insns.push(insn);
+    }
+
+    insns
+}
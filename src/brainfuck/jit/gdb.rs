@@ -0,0 +1,335 @@
+// Let GDB debug JIT code at the level of the Brainfuck source, through its JIT
+// compilation interface. GDB puts a breakpoint on __jit_debug_register_code, and
+// whenever it's hit, reads __jit_debug_descriptor to find the in-memory object
+// file that was just added or removed. That object describes our code the way a
+// compiler would describe a normal binary, a symbol for the function and DWARF
+// line info tying its addresses to lines of the .bf file, so `break hello.bf:3`
+// and `step` work as they would for C. See "JIT Compilation Interface" in the
+// GDB manual for the structures, and the DWARF standard for the rest.
+use super::{Arch, CodeMap};
+use crate::brainfuck::{
+    elf,
+    leb128::{write_signed, write_unsigned},
+};
+use std::{ptr, sync::Mutex};
+
+// action_flag, what GDB should do with relevant_entry
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+// DWARF tags, attributes and forms, all below 128, so they are their own LEB128 encoding
+const DW_TAG_COMPILE_UNIT: u8 = 0x11;
+const DW_TAG_SUBPROGRAM: u8 = 0x2e;
+const DW_CHILDREN_NO: u8 = 0;
+const DW_CHILDREN_YES: u8 = 1;
+const DW_AT_NAME: u8 = 0x03;
+const DW_AT_STMT_LIST: u8 = 0x10;
+const DW_AT_LOW_PC: u8 = 0x11;
+const DW_AT_HIGH_PC: u8 = 0x12;
+const DW_AT_COMP_DIR: u8 = 0x1b;
+const DW_AT_PRODUCER: u8 = 0x25;
+const DW_AT_EXTERNAL: u8 = 0x3f;
+const DW_FORM_ADDR: u8 = 0x01;
+const DW_FORM_DATA4: u8 = 0x06;
+const DW_FORM_STRING: u8 = 0x08;
+const DW_FORM_FLAG: u8 = 0x0c;
+// Line number program opcodes, standard ones and extended ones (which follow a zero)
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+// Everything is written as DWARF 2, the simplest version every debugger reads
+const DWARF_VERSION: u16 = 2;
+// Operand counts of standard opcodes 1 to 12, which any line program header has to list
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+// GDB finds both of these by name, so neither can be mangled
+#[no_mangle]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+// GDB breaks here to learn about changes to the descriptor. The empty asm block
+// counts as a side effect, so calls to it can't be optimised away.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    unsafe { std::arch::asm!("") }
+}
+
+// Any thread may register code, the descriptor's list is only touched holding this
+static DESCRIPTOR_LOCK: Mutex<()> = Mutex::new(());
+
+// A symbol file GDB has been told about, which it is told to forget when dropped.
+// The entry is boxed, as GDB holds on to its address.
+pub struct Registration {
+    entry: Box<JitCodeEntry>,
+    _symfile: Vec<u8>,
+}
+
+// Hand a symbol file to GDB, if one is attached. Without one this is just
+// some pointer juggling.
+pub fn register(symfile: Vec<u8>) -> Registration {
+    let mut entry = Box::new(JitCodeEntry {
+        next_entry: ptr::null_mut(),
+        prev_entry: ptr::null_mut(),
+        symfile_addr: symfile.as_ptr(),
+        symfile_size: symfile.len() as u64,
+    });
+    let entry_ptr: *mut JitCodeEntry = &mut *entry;
+
+    let _lock = DESCRIPTOR_LOCK.lock().unwrap();
+    unsafe {
+        let descriptor = &raw mut __jit_debug_descriptor;
+
+        // New entries go at the head of the list
+        entry.next_entry = (*descriptor).first_entry;
+        if let Some(first) = (*descriptor).first_entry.as_mut() {
+            first.prev_entry = entry_ptr;
+        }
+        (*descriptor).first_entry = entry_ptr;
+
+        (*descriptor).relevant_entry = entry_ptr;
+        (*descriptor).action_flag = JIT_REGISTER_FN;
+        __jit_debug_register_code();
+        (*descriptor).action_flag = JIT_NOACTION;
+    }
+
+    Registration {
+        entry,
+        _symfile: symfile,
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let entry_ptr: *mut JitCodeEntry = &mut *self.entry;
+
+        let _lock = DESCRIPTOR_LOCK.lock().unwrap();
+        unsafe {
+            let descriptor = &raw mut __jit_debug_descriptor;
+
+            match self.entry.prev_entry.as_mut() {
+                Some(prev) => prev.next_entry = self.entry.next_entry,
+                None => (*descriptor).first_entry = self.entry.next_entry,
+            }
+            if let Some(next) = self.entry.next_entry.as_mut() {
+                next.prev_entry = self.entry.prev_entry;
+            }
+
+            (*descriptor).relevant_entry = entry_ptr;
+            (*descriptor).action_flag = JIT_UNREGISTER_FN;
+            __jit_debug_register_code();
+            (*descriptor).action_flag = JIT_NOACTION;
+        }
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, string: &str) {
+    out.extend_from_slice(string.as_bytes());
+    out.push(0);
+}
+
+// Prefix a unit with its length, as 32-bit DWARF wants at the start of every unit
+fn with_length(contents: Vec<u8>) -> Vec<u8> {
+    let mut unit = (contents.len() as u32).to_le_bytes().to_vec();
+    unit.extend(contents);
+    unit
+}
+
+// Describe the compilation unit, the .bf file, and the one function in it
+fn debug_abbrev() -> Vec<u8> {
+    let mut out = vec![];
+
+    out.extend([1, DW_TAG_COMPILE_UNIT, DW_CHILDREN_YES]);
+    out.extend([DW_AT_NAME, DW_FORM_STRING]);
+    out.extend([DW_AT_COMP_DIR, DW_FORM_STRING]);
+    out.extend([DW_AT_PRODUCER, DW_FORM_STRING]);
+    out.extend([DW_AT_LOW_PC, DW_FORM_ADDR]);
+    out.extend([DW_AT_HIGH_PC, DW_FORM_ADDR]);
+    out.extend([DW_AT_STMT_LIST, DW_FORM_DATA4]);
+    out.extend([0, 0]);
+
+    out.extend([2, DW_TAG_SUBPROGRAM, DW_CHILDREN_NO]);
+    out.extend([DW_AT_NAME, DW_FORM_STRING]);
+    out.extend([DW_AT_EXTERNAL, DW_FORM_FLAG]);
+    out.extend([DW_AT_LOW_PC, DW_FORM_ADDR]);
+    out.extend([DW_AT_HIGH_PC, DW_FORM_ADDR]);
+    out.extend([0, 0]);
+
+    out.push(0);
+    out
+}
+
+fn debug_info(addr: u64, code_len: u64, source: &str, comp_dir: &str, symbol: &str) -> Vec<u8> {
+    let mut out = vec![];
+
+    out.extend_from_slice(&DWARF_VERSION.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes()); // offset into .debug_abbrev
+    out.push(8); // address size
+
+    out.push(1);
+    write_string(&mut out, source);
+    write_string(&mut out, comp_dir);
+    write_string(&mut out, concat!("brainrust ", env!("CARGO_PKG_VERSION")));
+    out.extend_from_slice(&addr.to_le_bytes());
+    out.extend_from_slice(&(addr + code_len).to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes()); // offset into .debug_line
+
+    out.push(2);
+    write_string(&mut out, symbol);
+    out.push(1);
+    out.extend_from_slice(&addr.to_le_bytes());
+    out.extend_from_slice(&(addr + code_len).to_le_bytes());
+
+    // End of the compilation unit's children
+    out.push(0);
+
+    with_length(out)
+}
+
+// The line number program, a row for every point in the code where the source
+// line changes. Only the basic opcodes are used, special opcodes would pack it
+// tighter but there's no need.
+fn debug_line(addr: u64, code_len: u64, map: &CodeMap, source: &str) -> Vec<u8> {
+    let mut header = vec![
+        1,                                       // minimum instruction length
+        1,                                       // default is_stmt
+        -5i8 as u8,                              // line base
+        14,                                      // line range
+        STANDARD_OPCODE_LENGTHS.len() as u8 + 1, // opcode base
+    ];
+    header.extend_from_slice(&STANDARD_OPCODE_LENGTHS);
+    header.push(0); // no include directories
+    write_string(&mut header, source);
+    header.extend([0, 0, 0]); // directory, modification time and length, all unknown
+    header.push(0); // no more files
+
+    let mut program = vec![0, 9, DW_LNE_SET_ADDRESS];
+    program.extend_from_slice(&addr.to_le_bytes());
+
+    let (mut offset, mut line) = (0usize, 1u32);
+    let mut first = true;
+
+    for (&start, &start_line) in map.ir_offsets.iter().zip(&map.lines) {
+        if !first && start_line == line {
+            continue;
+        }
+
+        if start > offset {
+            program.push(DW_LNS_ADVANCE_PC);
+            write_unsigned(&mut program, (start - offset) as u32);
+        }
+        if start_line != line {
+            program.push(DW_LNS_ADVANCE_LINE);
+            write_signed(&mut program, start_line as i32 - line as i32);
+        }
+        program.push(DW_LNS_COPY);
+
+        (offset, line, first) = (start, start_line, false);
+    }
+
+    // The sequence ends just past the code
+    program.push(DW_LNS_ADVANCE_PC);
+    write_unsigned(&mut program, (code_len as usize - offset) as u32);
+    program.extend([0, 1, DW_LNE_END_SEQUENCE]);
+
+    let mut out = DWARF_VERSION.to_le_bytes().to_vec();
+    out.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    out.extend(header);
+    out.extend(program);
+
+    with_length(out)
+}
+
+// Build the symbol file for code at `addr`, compiled from the .bf file `source`
+// (relative to `comp_dir`, unless it's absolute)
+pub fn symbol_file(
+    arch: Arch,
+    addr: u64,
+    code_len: u64,
+    map: &CodeMap,
+    source: &str,
+    comp_dir: &str,
+) -> Vec<u8> {
+    let symbol = "bf_main";
+
+    elf::symbol_file(
+        arch,
+        addr,
+        code_len,
+        symbol,
+        source,
+        &[
+            (
+                ".debug_info",
+                &debug_info(addr, code_len, source, comp_dir, symbol),
+            ),
+            (".debug_abbrev", &debug_abbrev()),
+            (".debug_line", &debug_line(addr, code_len, map, source)),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registered() -> Vec<*mut JitCodeEntry> {
+        let _lock = DESCRIPTOR_LOCK.lock().unwrap();
+        let mut entries = vec![];
+        let mut entry = unsafe { __jit_debug_descriptor.first_entry };
+
+        while !entry.is_null() {
+            entries.push(entry);
+            entry = unsafe { (*entry).next_entry };
+        }
+
+        entries
+    }
+
+    #[test]
+    fn register_and_unregister() {
+        let first = register(vec![1]);
+        let second = register(vec![2]);
+        let third = register(vec![3]);
+
+        let ptr_of = |registration: &Registration| &*registration.entry as *const _ as *mut _;
+        let (first_ptr, third_ptr) = (ptr_of(&first), ptr_of(&third));
+
+        // Other tests could be registering code at the same time, so only
+        // look at the order of our own entries
+        drop(second);
+        let entries: Vec<_> = registered()
+            .into_iter()
+            .filter(|&entry| entry == first_ptr || entry == third_ptr)
+            .collect();
+        assert_eq!(entries, [third_ptr, first_ptr]);
+
+        drop(first);
+        drop(third);
+        assert!(!registered().contains(&first_ptr));
+        assert!(!registered().contains(&third_ptr));
+    }
+}
@@ -1,8 +1,458 @@
+// Every Linux code generator builds on any host, so they can be tested (and used
+// for ahead-of-time compilation) anywhere. The Jit itself only exists on the
+// host the code generator targets.
+pub mod aarch64_linux;
+pub mod cache;
+#[cfg(target_os = "linux")]
+pub mod code_heap;
+pub mod disasm;
+pub mod gdb;
+pub mod perf;
+pub mod riscv64_linux;
+pub mod x86_64_linux;
 #[cfg(all(target_arch = "x86_64", target_os = "windows"))]
-include!("x86_64_windows.rs");
+pub mod x86_64_windows;
+
+use super::{
+    ir::{IRInsn, IR},
+    program::Program,
+    tape::Tape,
+    watched, Budget, ExecutionResult, Limit, LimitExceeded,
+};
+#[cfg(target_os = "linux")]
+use code_heap::CodeBlock;
+use std::{
+    ffi::c_int,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+};
+
+// Version of the code the backends generate, part of every cache key. Bump it with
+// any change to what a code generator emits for the same IR, the cache would keep
+// handing out code from before the change otherwise.
+pub const CODEGEN_VERSION: u32 = 1;
+
+// What profiling and debugging aids need to know about a compiled function,
+// to tie its machine code back to the program it came from
+pub struct CodeMap {
+    pub ir: Vec<IRInsn>,
+    // Where each IR instruction's code starts, then where the epilogue starts
+    pub ir_offsets: Vec<usize>,
+    // The source line each IR instruction came from
+    pub lines: Vec<u32>,
+}
+
+// The file descriptors compiled code reads its input from and writes its output to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Io {
+    pub input: c_int,
+    pub output: c_int,
+}
+
+impl Io {
+    pub fn stdio() -> Self {
+        Io {
+            input: 0,
+            output: 1,
+        }
+    }
+}
+
+// What code compiled to count steps works through, passed in as a fourth argument.
+// Every back-edge takes one off `remaining`, and once that goes negative the code
+// stores the IR index of the loop's closing bracket in `stopped_at` and returns.
+#[repr(C)]
+pub struct Fuel {
+    pub remaining: AtomicI64,
+    pub stopped_at: AtomicU64,
+}
+
+// Run code compiled to count steps on a tape, stopping it once it goes over `budget`.
+// `call` runs the code on the tape starting at the given cell, with the given Fuel.
+// Time is kept by a watchdog thread, which takes the code's fuel away once it's up,
+// see watched. Code stuck in a read gets to its next back-edge that way too.
+fn run_counted(
+    mut tape: Tape,
+    budget: Budget,
+    call: impl FnOnce(*mut u8, &Fuel) -> *mut u8,
+) -> Result<ExecutionResult, LimitExceeded> {
+    let steps = budget.max_steps.unwrap_or(u64::MAX).min(i64::MAX as u64);
+    let fuel = Fuel {
+        remaining: AtomicI64::new(steps as i64),
+        stopped_at: AtomicU64::new(0),
+    };
+    let timed_out = AtomicBool::new(false);
+
+    // The code takes its steps without a lock, so it may overwrite a store made in
+    // the middle of one, the watchdog keeps at it until the code notices
+    let start = tape.as_mut_ptr();
+    let end = watched(
+        budget.timeout,
+        || {
+            timed_out.store(true, Ordering::Relaxed);
+            fuel.remaining.store(0, Ordering::Relaxed);
+        },
+        || call(start, &fuel),
+    );
+    let pointer = unsafe { end.offset_from(start) as usize };
+
+    if fuel.remaining.load(Ordering::Relaxed) >= 0 {
+        return Ok(ExecutionResult::finished(tape, pointer));
+    }
+
+    Err(LimitExceeded {
+        limit: if timed_out.load(Ordering::Relaxed) {
+            Limit::Time
+        } else {
+            Limit::Steps
+        },
+        position: fuel.stopped_at.load(Ordering::Relaxed) as usize,
+        tape,
+        pointer,
+    })
+}
+
+// Every Linux backend's Jit produces JittedFunctions from Brainfuck IR, each one a
+// block of machine code in the shared code heap. They all generate functions taking
+// the tape, the input and output file descriptors and a Fuel pointer, and returning
+// the final tape pointer.
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "riscv64",
+        target_arch = "aarch64"
+    )
+))]
+pub struct JittedFunction(CodeBlock);
+
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "riscv64",
+        target_arch = "aarch64"
+    )
+))]
+impl JittedFunction {
+    // Copy finished machine code into the code heap
+    pub fn from_code(code: &[u8]) -> Self {
+        JittedFunction(code_heap::allocate(code))
+    }
+
+    // The machine code, as it sits in its executable mapping
+    pub fn code(&self) -> &[u8] {
+        self.0.code()
+    }
+
+    // Code compiled without count_steps never looks at the Fuel pointer
+    fn function(&self) -> extern "C" fn(*mut u8, c_int, c_int, *const Fuel) -> *mut u8 {
+        // Converting a pointer of bytes to a function pointer in Rust is, as one would expect,
+        // very unsafe. This requires an intrinsics function changing arbitrary memory objects
+        // called "transmute".
+        unsafe { std::mem::transmute(self.0.as_ptr()) }
+    }
+
+    // Run the compiled code on a fresh tape with stdio, handing back the state it left the tape in
+    pub fn run(&self) -> ExecutionResult {
+        let mut tape = Tape::new();
+        let pointer = self.run_with(&mut tape, &mut Io::stdio());
+
+        ExecutionResult::finished(tape, pointer)
+    }
+
+    // Run the compiled code on a tape of the caller's, from its first cell, doing I/O
+    // on the given file descriptors, and return the cell the code finished on. The code
+    // itself never changes, so any number of threads can run one function at once,
+    // as long as each brings its own tape.
+    pub fn run_with(&self, tape: &mut Tape, io: &mut Io) -> usize {
+        let start = tape.as_mut_ptr();
+        let end = self.function()(start, io.input, io.output, ptr::null());
+
+        unsafe { end.offset_from(start) as usize }
+    }
+
+    // Run code compiled with count_steps on a tape of the caller's, stopping it once it
+    // goes over `budget`, see run_counted
+    pub fn run_within(
+        &self,
+        tape: Tape,
+        io: &mut Io,
+        budget: Budget,
+    ) -> Result<ExecutionResult, LimitExceeded> {
+        run_counted(tape, budget, |start, fuel| {
+            self.function()(start, io.input, io.output, fuel)
+        })
+    }
+
+    // Run the compiled code on an existing tape, starting at cell `ptr`, returning the cell
+    // the code finished on. This is how the tiered executor hands a hot loop to compiled
+    // code and picks up where it left off. Like run, nothing checks the code stays on the tape.
+    pub fn run_from(&self, tape: &mut [u8], ptr: usize) -> usize {
+        assert!(ptr < tape.len());
+
+        let io = Io::stdio();
+        let start = tape.as_mut_ptr();
+        let end = self.function()(unsafe { start.add(ptr) }, io.input, io.output, ptr::null());
+
+        unsafe { end.offset_from(start) as usize }
+    }
+}
+
+// The architectures there is a code generator for
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    Riscv64,
+    Aarch64,
+}
+
+impl Arch {
+    // The architecture we are running on, if it has a code generator
+    pub fn host() -> Option<Self> {
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                Some(Arch::X86_64)
+            } else if #[cfg(target_arch = "riscv64")] {
+                Some(Arch::Riscv64)
+            } else if #[cfg(target_arch = "aarch64")] {
+                Some(Arch::Aarch64)
+            } else {
+                None
+            }
+        }
+    }
+
+    // Compile with this architecture's code generator
+    pub fn compile(self, ir: IR) -> Result<Vec<u8>, ()> {
+        match self {
+            Arch::X86_64 => x86_64_linux::compile(ir),
+            Arch::Riscv64 => riscv64_linux::compile(ir),
+            Arch::Aarch64 => aarch64_linux::compile(ir),
+        }
+    }
+
+    // Compile, also getting where each IR instruction's code starts, see compile_mapped
+    pub fn compile_mapped(self, ir: IR) -> Result<(Vec<u8>, Vec<usize>), ()> {
+        match self {
+            Arch::X86_64 => x86_64_linux::compile_mapped(ir),
+            Arch::Riscv64 => riscv64_linux::compile_mapped(ir),
+            Arch::Aarch64 => aarch64_linux::compile_mapped(ir),
+        }
+    }
+
+    // Compile with this architecture's single pass template compiler, the fast
+    // tier, also getting where each operator's code starts
+    pub fn compile_template_mapped(self, program: &Program) -> Result<(Vec<u8>, Vec<usize>), ()> {
+        match self {
+            Arch::X86_64 => x86_64_linux::compile_template_mapped(program),
+            Arch::Riscv64 => riscv64_linux::compile_template_mapped(program),
+            Arch::Aarch64 => aarch64_linux::compile_template_mapped(program),
+        }
+    }
+}
 
 #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
-include!("x86_64_linux.rs");
+pub use x86_64_linux::Jit;
 
 #[cfg(all(target_arch = "riscv64", target_os = "linux"))]
-include!("riscv64_linux.rs");
+pub use riscv64_linux::Jit;
+
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+pub use aarch64_linux::Jit;
+
+#[cfg(all(target_arch = "x86_64", target_os = "windows"))]
+pub use x86_64_windows::{Jit, JittedFunction};
+
+#[cfg(test)]
+mod rv64_emulator;
+
+#[cfg(all(
+    test,
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "riscv64",
+        target_arch = "aarch64"
+    )
+))]
+mod tests {
+    use super::*;
+    use crate::brainfuck::{interpreter::Interpreter, program::Program, tape::Tape, Eval};
+    use std::{
+        io::{self, Read, Write},
+        os::fd::AsRawFd,
+        thread,
+    };
+
+    // One function, many threads, each with a tape and pipes of its own
+    #[test]
+    fn runs_in_parallel() {
+        let function = Jit::eval_ir(Program::new(",[+.,]").into()).unwrap();
+
+        thread::scope(|scope| {
+            for worker in 0..8u8 {
+                let function = &function;
+
+                scope.spawn(move || {
+                    // Echo every byte plus one, up to the terminating zero
+                    let bytes: Vec<u8> = (0..100).map(|i| worker * 16 + i % 16 + 1).collect();
+                    let input = [bytes.as_slice(), &[0]].concat();
+
+                    let (input_reader, mut input_writer) = io::pipe().unwrap();
+                    let (mut output_reader, output_writer) = io::pipe().unwrap();
+                    input_writer.write_all(&input).unwrap();
+                    drop(input_writer);
+
+                    let mut tape = Tape::new();
+                    let mut io = Io {
+                        input: input_reader.as_raw_fd(),
+                        output: output_writer.as_raw_fd(),
+                    };
+                    let ptr = function.run_with(&mut tape, &mut io);
+                    drop(output_writer);
+
+                    let mut output = vec![];
+                    output_reader.read_to_end(&mut output).unwrap();
+
+                    let expected: Vec<u8> = bytes.iter().map(|byte| byte + 1).collect();
+                    assert_eq!(output, expected);
+                    assert_eq!((ptr, tape[0]), (0, 0));
+                });
+            }
+        });
+    }
+
+    // Both tiers leave the machine exactly as the interpreter does
+    #[test]
+    fn leaves_the_same_state_as_the_interpreter() {
+        let source = "++++++[>++++++<-]>>>+++<<[->+>+<<]>[-<+>]<--";
+        let expected = Interpreter::eval_source(Program::new(source)).unwrap();
+        assert_eq!(expected.pointer, 1);
+        assert_eq!(&expected.tape[..5], [0, 34, 0, 39, 0]);
+
+        let fast = Jit::eval_source(Program::new(source)).unwrap().run();
+        let optimised = Jit::eval_ir(Program::new(source).into()).unwrap().run();
+        assert_eq!(fast, expected);
+        assert_eq!(optimised, expected);
+    }
+
+    // Counted code stops on the same loop, with the same tape, as the interpreter
+    // given the same budget, and a loop that never ends runs out of time
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn stops_where_the_interpreter_does() {
+        use crate::brainfuck::{ir, Budget, Limit};
+        use std::time::Duration;
+
+        let source = "+++\n[>+<-]\n>[\n+]";
+        let program = Program::new(source);
+        let ir_lines = ir::source_lines(&program);
+        let options = x86_64_linux::Options {
+            count_steps: true,
+            ..Default::default()
+        };
+        let (code, _, _) = x86_64_linux::compile_with(IR::from(program), options).unwrap();
+        let function = JittedFunction::from_code(&code);
+
+        let program = Program::new(source);
+        for (steps, line, pointer) in [(0, 2, 0), (1, 2, 0), (2, 4, 1), (4, 4, 1)] {
+            let budget = Budget {
+                max_steps: Some(steps),
+                ..Default::default()
+            };
+            let expected =
+                Interpreter::run_on(&program, Tape::new(), budget, || 0, |_| {}).unwrap_err();
+            let jitted = function
+                .run_within(Tape::new(), &mut Io::stdio(), budget)
+                .unwrap_err();
+
+            assert_eq!((expected.limit, jitted.limit), (Limit::Steps, Limit::Steps));
+            assert_eq!(program.lines[expected.position], line);
+            assert_eq!(ir_lines[jitted.position], line);
+            assert_eq!((expected.pointer, jitted.pointer), (pointer, pointer));
+            assert_eq!(jitted.tape, expected.tape);
+        }
+
+        let budget = Budget {
+            max_steps: Some(300),
+            ..Default::default()
+        };
+        let finished = function.run_within(Tape::new(), &mut Io::stdio(), budget);
+        assert_eq!(finished.unwrap().pointer, 1);
+
+        let (code, _, _) =
+            x86_64_linux::compile_with(IR::from(Program::new("+[]")), options).unwrap();
+        let budget = Budget {
+            timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let stuck = JittedFunction::from_code(&code)
+            .run_within(Tape::new(), &mut Io::stdio(), budget)
+            .unwrap_err();
+        assert_eq!((stuck.limit, stuck.pointer), (Limit::Time, 0));
+    }
+
+    // Compile for the host, counting steps
+    fn compile_counted(source: &str) -> JittedFunction {
+        let ir = IR::from(Program::new(source));
+
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                let options = x86_64_linux::Options {
+                    count_steps: true,
+                    ..Default::default()
+                };
+                let (code, _, _) = x86_64_linux::compile_with(ir, options).unwrap();
+            } else if #[cfg(target_arch = "riscv64")] {
+                let options = riscv64_linux::Options { count_steps: true };
+                let (code, _) = riscv64_linux::compile_with(ir, options).unwrap();
+            } else {
+                let options = aarch64_linux::Options { count_steps: true };
+                let (code, _) = aarch64_linux::compile_with(ir, options).unwrap();
+            }
+        }
+
+        JittedFunction::from_code(&code)
+    }
+
+    // A program stuck waiting for input that never comes still runs out of time,
+    // compiled or interpreted
+    #[test]
+    fn times_out_waiting_for_input() {
+        use crate::brainfuck::{watched, Budget, Limit};
+        use std::time::Duration;
+
+        let budget = Budget {
+            timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        // Nothing ever gets written, but the writing end stays open, so reads block
+        let (input, _writer) = io::pipe().unwrap();
+
+        let mut io = Io {
+            input: input.as_raw_fd(),
+            output: 1,
+        };
+        let stuck = compile_counted("+[,]")
+            .run_within(Tape::new(), &mut io, budget)
+            .unwrap_err();
+        assert_eq!((stuck.limit, stuck.position), (Limit::Time, 3));
+        assert_eq!((stuck.pointer, stuck.tape[0]), (0, 1));
+
+        // An interrupted read gives up like getchar does, with the end of input
+        let program = Program::new("+[,]");
+        let read = || {
+            let mut byte = [0];
+            (&input).read(&mut byte).map_or(0xff, |_| byte[0])
+        };
+        let stuck = watched(
+            budget.timeout,
+            || {},
+            || Interpreter::run_on(&program, Tape::new(), budget, read, |_| {}),
+        )
+        .unwrap_err();
+        assert_eq!((stuck.limit, stuck.position), (Limit::Time, 2));
+    }
+}
@@ -0,0 +1,129 @@
+// Tell perf(1) what lives in our anonymous executable mappings. perf looks for
+// /tmp/perf-<pid>.map, one "start size name" line per symbol, all in hex, and
+// uses it to name samples it can't find in any ELF file. See perf's
+// tools/perf/Documentation/jit-interface.txt.
+//
+// Loops nest but symbols can't overlap, so code is split up by the innermost
+// loop it belongs to, and an outer loop gets a line for each stretch of its
+// own code between inner loops. Time spent in a loop's brackets counts for the
+// loop, anything outside every loop counts for the function itself, prologue
+// and epilogue included.
+use super::CodeMap;
+use crate::brainfuck::ir::IRInsn;
+use std::{fs::OpenOptions, io, io::Write, process};
+
+// A stretch of code, as an offset and length, and the symbol it gets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub offset: usize,
+    pub len: usize,
+    pub name: String,
+}
+
+// Split code up into regions named after the source file, and for loops, the
+// line of their opening bracket
+pub fn regions(map: &CodeMap, code_len: usize, file_name: &str) -> Vec<Region> {
+    // Innermost loop each IR instruction is part of, by the index of its opening bracket
+    let mut open_loops: Vec<usize> = vec![];
+    let owners = map.ir.iter().enumerate().map(|(idx, insn)| {
+        match insn {
+            IRInsn::JumpIfZero => open_loops.push(idx),
+            IRInsn::JumpIfNonZero => return open_loops.pop(),
+            _ => {}
+        }
+
+        open_loops.last().copied()
+    });
+
+    // The epilogue belongs to the function, like everything outside a loop
+    let owners: Vec<Option<usize>> = owners.chain([None]).collect();
+    let ends = map.ir_offsets[1..].iter().copied().chain([code_len]);
+
+    // So does the prologue, ahead of the first instruction
+    let mut regions: Vec<(Option<usize>, usize, usize)> = vec![(None, 0, map.ir_offsets[0])];
+
+    for ((owner, &start), end) in owners.into_iter().zip(&map.ir_offsets).zip(ends) {
+        match regions.last_mut() {
+            Some((last_owner, _, last_end)) if *last_owner == owner => *last_end = end,
+            _ => regions.push((owner, start, end)),
+        }
+    }
+
+    regions
+        .into_iter()
+        .filter(|(_, start, end)| end > start)
+        .map(|(owner, start, end)| Region {
+            offset: start,
+            len: end - start,
+            name: match owner {
+                Some(bracket) => format!("bf_loop {file_name}:{}", map.lines[bracket]),
+                None => format!("bf_main {file_name}"),
+            },
+        })
+        .collect()
+}
+
+// Append the regions of code mapped at `base` to this process's perf map
+pub fn write_map(base: usize, map: &CodeMap, code_len: usize, file_name: &str) -> io::Result<()> {
+    let path = format!("/tmp/perf-{}.map", process::id());
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    let mut entries = String::new();
+    for region in regions(map, code_len, file_name) {
+        entries.push_str(&format!(
+            "{:x} {:x} {}\n",
+            base + region.offset,
+            region.len,
+            region.name
+        ));
+    }
+
+    file.write_all(entries.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck::{
+        ir::{self, IR},
+        jit::Arch,
+        program::Program,
+    };
+
+    #[test]
+    fn nested_loops() {
+        let program = Program::new("++\n[>+\n[-]\n<-]\n.");
+        let lines = ir::source_lines(&program);
+        let ir: Vec<IRInsn> = IR::from(program).into_iter().collect();
+
+        let (code, ir_offsets) = Arch::Riscv64
+            .compile_mapped(ir.iter().cloned().collect())
+            .unwrap();
+        let map = CodeMap {
+            ir,
+            ir_offsets,
+            lines,
+        };
+        let regions = regions(&map, code.len(), "nested.bf");
+
+        let names: Vec<&str> = regions.iter().map(|region| region.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "bf_main nested.bf",
+                "bf_loop nested.bf:2",
+                "bf_loop nested.bf:3",
+                "bf_loop nested.bf:2",
+                "bf_main nested.bf"
+            ]
+        );
+
+        // Regions tile the whole function, in order and without gaps
+        let mut offset = 0;
+        for region in &regions {
+            assert_eq!(region.offset, offset);
+            offset += region.len;
+        }
+        assert_eq!(offset, code.len());
+    }
+}
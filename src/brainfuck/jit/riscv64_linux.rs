@@ -1,249 +1,1053 @@
+// The code generator in this file is plain Rust, so it is built on every host,
+// which keeps it testable. Only riscv64 Linux gets the executable `Jit` on top.
+#[cfg(all(target_arch = "riscv64", target_os = "linux"))]
+use super::JittedFunction;
 use super::{
+    cache::{self, Key},
+    Arch,
+};
+use crate::brainfuck::{
     ir::{IRInsn, IR},
-    program::Program,
+    program::{Operator, Program},
     Eval,
 };
 
-use nix::sys::mman::{mmap_anonymous, munmap, MapFlags, ProtFlags};
-use std::{ffi::c_void, io::Write, num::NonZero, ptr::NonNull, slice};
+// Register numbers we need when building instructions by hand
+const ZERO: u32 = 0;
+const T0: u32 = 5;
+const T1: u32 = 6;
+const T2: u32 = 7;
+const A0: u32 = 10;
+const A1: u32 = 11;
+const A2: u32 = 12;
+const A3: u32 = 13;
+const A4: u32 = 14;
+const A5: u32 = 15;
+const A7: u32 = 17;
+
+// Linux RISC-V system call numbers, passed in a7
+const SYS_READ: i32 = 63;
+const SYS_WRITE: i32 = 64;
+
+// Base encodings for every instruction we emit, with every
+// register and immediate field left zeroed
+const LB: u32 = 0x0000_0003; // lb zero, 0(zero)
+const SB: u32 = 0x0000_0023; // sb zero, 0(zero)
+const LD: u32 = 0x0000_3003; // ld zero, 0(zero)
+const SD: u32 = 0x0000_3023; // sd zero, 0(zero)
+const ADDI: u32 = 0x0000_0013; // addi zero, zero, 0
+const ADDIW: u32 = 0x0000_001b; // addiw zero, zero, 0
+const SLLI: u32 = 0x0000_1013; // slli zero, zero, 0
+const SRLI: u32 = 0x0000_5013; // srli zero, zero, 0
+const ADD: u32 = 0x0000_0033; // add zero, zero, zero
+const SUB: u32 = 0x4000_0033; // sub zero, zero, zero
+const LUI: u32 = 0x0000_0037; // lui zero, 0
+const BEQ: u32 = 0x0000_0063; // beq zero, zero, 0
+const BNE: u32 = 0x0000_1063; // bne zero, zero, 0
+const BGE: u32 = 0x0000_5063; // bge zero, zero, 0
+const JAL: u32 = 0x0000_006f; // jal zero, 0
+const AUIPC: u32 = 0x0000_0017; // auipc zero, 0
+const JALR: u32 = 0x0000_0067; // jalr zero, 0(zero)
+const ECALL: u32 = 0x0000_0073; // ecall
+
+// RISC-V "I" Instruction Format
+// | imm[11:0] | rs1 | funct3 | rd | opcode |
+//
+// Fills in the registers and 12 bit signed immediate of an "I" format instruction.
+// Shifts are "I" format too, with the shift amount in the lower bits of the immediate.
+fn encode_i_format(base: u32, rd: u32, rs1: u32, imm: i32) -> u32 {
+    assert!((-2048..2048).contains(&imm)); // 12 bit signed valid range
+
+    base | (((imm as u32) & 0xfff) << 20) | (rs1 << 15) | (rd << 7)
+}
+
+// RISC-V "S" Instruction Format
+// | imm[11:5] | rs2 | rs1 | funct3 | imm[4:0] | opcode |
+fn encode_s_format(base: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    assert!((-2048..2048).contains(&imm));
+
+    let imm = imm as u32;
+
+    base | (((imm >> 5) & 0b111_1111) << 25) | (rs2 << 20) | (rs1 << 15) | ((imm & 0b1_1111) << 7)
+}
+
+// RISC-V "R" Instruction Format
+// | funct7 | rs2 | rs1 | funct3 | rd | opcode |
+fn encode_r_format(base: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    base | (rs2 << 20) | (rs1 << 15) | (rd << 7)
+}
+
+// RISC-V "U" Instruction Format
+// | imm[31:12] | rd | opcode |
+fn encode_u_format(base: u32, rd: u32, imm: u32) -> u32 {
+    assert!(imm < (1 << 20)); // only the upper 20 bits are encodable
+
+    base | (imm << 12) | (rd << 7)
+}
+
+// Load any 32 bit unsigned constant into register `rd`. Small constants are a single
+// addi, anything else takes a lui for the upper 20 bits plus an addiw for the lower 12.
+// The pair produces a sign extended 32 bit value, so constants with the top bit set
+// get zero extended with a shift left/shift right pair afterwards.
+fn emit_load_immediate(code: &mut Vec<u32>, rd: u32, value: u32) {
+    if value < 2048 {
+        code.push(encode_i_format(ADDI, rd, ZERO, value as i32)); // li rd, <value>
+        return;
+    }
+
+    // addiw sign extends its immediate, so round the upper part up
+    // whenever the lower 12 bits will be treated as negative
+    let hi = (value.wrapping_add(0x800) >> 12) & 0xf_ffff;
+    let lo = ((value & 0xfff) as i32) << 20 >> 20;
+
+    code.push(encode_u_format(LUI, rd, hi)); // lui rd, %hi(<value>)
+    code.push(encode_i_format(ADDIW, rd, rd, lo)); // addiw rd, rd, %lo(<value>)
+
+    if value >= 1 << 31 {
+        code.push(encode_i_format(SLLI, rd, rd, 32)); // slli rd, rd, 32
+        code.push(encode_i_format(SRLI, rd, rd, 32)); // srli rd, rd, 32
+    }
+}
+
+// Move the tape pointer in a0 by any amount, forwards or backwards. A move that fits
+// the 12 bit signed immediate of addi is done in place, bigger moves go through t1.
+fn emit_pointer_move(code: &mut Vec<u32>, amount: u32, forward: bool) {
+    let delta = if forward {
+        amount as i64
+    } else {
+        -(amount as i64)
+    };
+
+    if (-2048..2048).contains(&delta) {
+        code.push(encode_i_format(ADDI, A0, A0, delta as i32)); // addi a0, a0, <delta>
+    } else {
+        emit_load_immediate(code, T1, amount);
+
+        let op = if forward { ADD } else { SUB };
+        code.push(encode_r_format(op, A0, A0, T1)); // add/sub a0, a0, t1
+    }
+}
+
+// An inlined read(2)/write(2) of a single byte at the tape pointer, syscall(fd, buffer, length).
+// The kernel takes arguments in a0-a2, the syscall number in a7, and only clobbers a0
+// with its return value, so the tape pointer rides out the ecall in a1 as the buffer.
+// The file descriptor comes from the register the prologue left it in.
+fn emit_syscall(code: &mut Vec<u32>, number: i32, fd: u32) {
+    code.extend_from_slice(&[
+        encode_i_format(ADDI, A1, A0, 0),        // mv a1, a0 (buffer)
+        encode_i_format(ADDI, A0, fd, 0),        // mv a0, <fd>
+        encode_i_format(ADDI, A2, ZERO, 1),      // li a2, 1 (length)
+        encode_i_format(ADDI, A7, ZERO, number), // li a7, <syscall number>
+        ECALL,                                   // ecall (system call)
+        encode_i_format(ADDI, A0, A1, 0),        // mv a0, a1 (restore tape pointer)
+    ]);
+}
 
-// Bit masks for parts of the immediate 12 bit offset operand for "B" RISC-V instructions
-const IMMED_MASK1: i32 = 0b0100_0000_0000; // 11th bit
-const IMMED_MASK2: i32 = 0b0000_0000_1111; // bits 1-4
-const IMMED_MASK3: i32 = 0b1000_0000_0000; // 12th bit
-const IMMED_MASK4: i32 = 0b0011_1111_0000; // bits 5-10
+// Add a (wrapping) amount to the cell under the tape pointer. Cells are bytes, so
+// any amount fits the addi immediate once it is negated for decrements.
+fn emit_value_add(code: &mut Vec<u32>, amount: u8, increment: bool) {
+    let delta = if increment {
+        amount as i32
+    } else {
+        -(amount as i32)
+    };
+
+    code.push(encode_i_format(LB, T0, A0, 0)); // lb t0, (a0)
+    code.push(encode_i_format(ADDI, T0, T0, delta)); // addi t0, t0, <delta>
+    code.push(encode_s_format(SB, A0, T0, 0)); // sb t0, (a0)
+}
 
 // RISC-V "B" Instruction Format
 // Every "-" is a bit in a 4-byte instruction encoding
-// |    -    | ------ | ----- | ----- | --- | ---- |    -   | ------- |
-// | imm[12] | imm[10:5] | rs2 | rs1 | imm[4:1] | imm[11] | opcode |
+// |    -    | ------    | ----- | ----- | ---    | ----     |    -    | ------- |
+// | imm[12] | imm[10:5] | rs2   | rs1   | funct3 | imm[4:1] | imm[11] | opcode  |
 //
 // This function mutates a "B" format instruction with the desired offset given:
 // imm[4:1] + imm[11] and imm[12] + imm[10:5]
-fn encode_b_format_immediate_offset(b_format_insn: &mut i32, offset: i32) {
-    assert!((-4095..4096).contains(&offset)); // +/- 4KB valid range
+fn encode_b_format_immediate_offset(b_format_insn: &mut u32, offset: i32) {
+    assert!((-4096..4096).contains(&offset)); // +/- 4KB valid range
     assert!(offset % 2 == 0); // has to be divisible by two
 
-    // offset is encoded as multiples of two, so an offset
-    // of +8 would be encoded as +4, -12 would be -6, so on.
-    let offset_multiple = offset / 2;
+    let offset = offset as u32;
 
-    let imm1 = (offset & IMMED_MASK1) >> 8;
-    let imm2 = (offset & IMMED_MASK2) << 8;
-    let imm3 = (offset & IMMED_MASK3) << 20;
-    let imm4 = (offset & IMMED_MASK4) << 20;
+    let imm12 = (offset >> 12) & 0b1;
+    let imm11 = (offset >> 11) & 0b1;
+    let imm10_5 = (offset >> 5) & 0b11_1111;
+    let imm4_1 = (offset >> 1) & 0b1111;
 
-    *b_format_insn |= (imm1 | imm2 | imm3 | imm4);
+    *b_format_insn |= (imm12 << 31) | (imm10_5 << 25) | (imm4_1 << 8) | (imm11 << 7);
 }
 
-pub struct Jit;
+// RISC-V "J" Instruction Format
+// |    -    | ----------  |    -    | --------   | ----- | ------- |
+// | imm[20] | imm[10:1]   | imm[11] | imm[19:12] | rd    | opcode  |
+//
+// Same idea as the "B" format, just a wider immediate scrambled differently
+fn encode_j_format_immediate_offset(j_format_insn: &mut u32, offset: i32) {
+    assert!((-(1 << 20)..(1 << 20)).contains(&offset)); // +/- 1MB valid range
+    assert!(offset % 2 == 0);
+
+    let offset = offset as u32;
+
+    let imm20 = (offset >> 20) & 0b1;
+    let imm19_12 = (offset >> 12) & 0b1111_1111;
+    let imm11 = (offset >> 11) & 0b1;
+    let imm10_1 = (offset >> 1) & 0b11_1111_1111;
+
+    *j_format_insn |= (imm20 << 31) | (imm10_1 << 21) | (imm11 << 20) | (imm19_12 << 12);
+}
+
+// Loops are rotated, tested once per iteration at the bottom. The opening bracket
+// jumps straight down to the test, and the closing bracket is the test, branching
+// back up to the body while the cell isn't zero:
+//
+//         j .Ltest
+//     .Lbody:
+//         <body>
+//     .Ltest:
+//         lb t0, 0(a0)
+//         bnez t0, .Lbody
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum BranchKind {
+    // An opening bracket's jump to its loop's test
+    Always,
+    // A closing bracket's branch back to its loop's body
+    IfNonZero,
+}
+
+// The shapes a bracket's branch can take, from smallest to largest.
+// A branch only ever grows into the next shape when its target is out of reach.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum BranchForm {
+    // bnez t0, target, or for jumps, j target
+    Short,
+    // beqz t0, 8; j target
+    Jal,
+    // beqz t0, 12; auipc t1, %hi(target); jalr zero, %lo(target)(t1), or for
+    // jumps the last two alone
+    Far,
+}
+
+// A bracket's branch, kept out of the straight-line code until every branch's
+// form has been settled. `at` is the word index in the straight-line code the
+// branch sits in front of, and `partner` indexes the matching bracket's branch.
+struct Branch {
+    at: usize,
+    kind: BranchKind,
+    partner: usize,
+    form: BranchForm,
+}
+
+impl Branch {
+    // Size of the branch in instruction words
+    fn len(&self) -> usize {
+        let skip = (self.kind == BranchKind::IfNonZero) as usize;
+
+        match self.form {
+            BranchForm::Short => 1,
+            BranchForm::Jal => 1 + skip,
+            BranchForm::Far => 2 + skip,
+        }
+    }
+
+    // Whether the branch can reach a target `offset` bytes away from its first instruction
+    fn reaches(&self, offset: i64) -> bool {
+        match (self.form, self.kind) {
+            (BranchForm::Short, BranchKind::IfNonZero) => (-4096..4096).contains(&offset),
+            (BranchForm::Short | BranchForm::Jal, BranchKind::Always) => {
+                (-(1 << 20)..(1 << 20)).contains(&offset)
+            }
+            // The jump sits one instruction after the inverted branch
+            (BranchForm::Jal, BranchKind::IfNonZero) => {
+                (-(1 << 20)..(1 << 20)).contains(&(offset - 4))
+            }
+            (BranchForm::Far, _) => true,
+        }
+    }
 
-// The Jit produces JittedFunctions from Brainfuck IR, its a tuple struct
-// with a void pointer, and a size of memory pointed to by pointer (weird slice)
-pub struct JittedFunction(*mut c_void, usize);
+    fn grow(&mut self) {
+        self.form = match (self.form, self.kind) {
+            (BranchForm::Short, BranchKind::IfNonZero) => BranchForm::Jal,
+            _ => BranchForm::Far,
+        };
+    }
+}
+
+// Byte address of every branch, accounting for the size of the branches before it
+fn branch_addresses(branches: &[Branch]) -> Vec<i64> {
+    let mut inserted_words = 0;
+
+    branches
+        .iter()
+        .map(|branch| {
+            let addr = (branch.at + inserted_words) as i64 * 4;
+            inserted_words += branch.len();
+            addr
+        })
+        .collect()
+}
+
+// How far the branch at `idx` has to go. A jump goes to the load in front of its
+// partner, a test goes to the first instruction after its partner.
+fn branch_offset(branches: &[Branch], addrs: &[i64], idx: usize) -> i64 {
+    let partner = branches[idx].partner;
 
-impl JittedFunction {
-    pub fn run(&self) {
-        // Converting any kind of pointer to a function pointer in Rust is, as one would expect,
-        // very unsafe. This requires an intrinsics function changing arbitrary memory objects
-        // called "transmute".
-        let function =
-            unsafe { std::mem::transmute::<*mut c_void, extern "C" fn(*const u8)>(self.0) };
+    let target = match branches[idx].kind {
+        BranchKind::Always => addrs[partner] - 4,
+        BranchKind::IfNonZero => addrs[partner] + branches[partner].len() as i64 * 4,
+    };
 
-        let byte_arr = [0u8; 30_000];
+    target - addrs[idx]
+}
+
+// Branch relaxation: start every branch in its short form, then keep growing the
+// ones that can't reach their target. Growing a branch pushes other code further
+// apart, so repeat until nothing changes. Forms only ever grow, so this terminates.
+fn relax_branches(branches: &mut [Branch]) -> Vec<i64> {
+    loop {
+        let addrs = branch_addresses(branches);
+        let mut grew = false;
 
-        // Call the function
-        function(byte_arr.as_ptr())
+        for idx in 0..branches.len() {
+            let offset = branch_offset(branches, &addrs, idx);
+
+            while !branches[idx].reaches(offset) {
+                branches[idx].grow();
+                grew = true;
+            }
+        }
+
+        if !grew {
+            return addrs;
+        }
     }
 }
 
-// Keeping in touch with Rust's stance on RAII driven design, implemented
-// Drop for the JittedFunction object, which call syscall munmap(2) to
-// relinquish the executable region of memory we requested from Linux
-impl Drop for JittedFunction {
-    fn drop(&mut self) {
-        unsafe {
-            munmap(NonNull::new_unchecked(self.0), self.1)
-                .expect("Failed to release memory back to OS!");
+// Emit the instructions for a single, already relaxed, branch jumping `offset` bytes
+fn emit_branch(code: &mut Vec<u32>, branch: &Branch, mut offset: i32) {
+    // Tests that can't reach on their own skip over a jump when the cell is zero
+    if branch.kind == BranchKind::IfNonZero {
+        if branch.form == BranchForm::Short {
+            let mut bnez = BNE | (T0 << 15);
+            encode_b_format_immediate_offset(&mut bnez, offset);
+            code.push(bnez);
+            return;
+        }
+
+        let mut skip = BEQ | (T0 << 15);
+        encode_b_format_immediate_offset(&mut skip, branch.len() as i32 * 4);
+        code.push(skip);
+        offset -= 4;
+    }
+
+    match branch.form {
+        BranchForm::Far => {
+            // auipc adds the upper 20 bits to its own address and jalr sign extends
+            // the lower 12, so round the upper part to make up for a negative lower part
+            let hi = (offset.wrapping_add(0x800) >> 12) as u32;
+            let lo = (offset & 0xfff) as u32;
+
+            let auipc = AUIPC | (hi << 12) | (T1 << 7);
+            let jalr = JALR | (lo << 20) | (T1 << 15);
+
+            code.extend_from_slice(&[auipc, jalr]);
+        }
+
+        _ => {
+            let mut jal = JAL;
+            encode_j_format_immediate_offset(&mut jal, offset);
+            code.push(jal);
         }
     }
 }
 
-struct JumpPairPos {
-    fwd_jmp: usize,
-    bwd_jmp: usize,
+// Choices the code generator leaves to its caller
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    // Count loop back-edges against the Fuel passed in as a fourth argument, in a3,
+    // and stop once it runs out
+    pub count_steps: bool,
 }
 
-impl Eval for Jit {
-    type Output = JittedFunction;
+// Compile Brainfuck IR to RISC-V machine code. The generated function takes
+// the tape pointer in a0, the input and output file descriptors in a1 and a2,
+// and returns the final tape pointer, also in a0.
+pub fn compile(ir: impl IntoIterator<Item = IRInsn>) -> Result<Vec<u8>, ()> {
+    compile_mapped(ir).map(|(code, _)| code)
+}
 
-    fn eval_source(src: Program) -> Result<Self::Output, ()> {
-        unimplemented!()
+// Same as compile, also handing back where each IR instruction's code starts,
+// with one extra entry at the end for where the function's epilogue starts
+pub fn compile_mapped(ir: impl IntoIterator<Item = IRInsn>) -> Result<(Vec<u8>, Vec<usize>), ()> {
+    compile_with(ir, Options::default())
+}
+
+// Going back around a loop takes a step, running out stops at the loop's closing
+// bracket, IR instruction `idx`. Tape pointer and cells are always up to date in
+// memory, so stopping is just returning. The count goes in front of the test
+// proper, a load and branch like any other loop's, so the jump into the loop
+// still lands on the load.
+//
+//         lb   t0, (a0)
+//         beqz t0, .Ltest
+//         ld   t1, 0(a5)
+//         addi t1, t1, -1
+//         sd   t1, 0(a5)
+//         bgez t1, .Ltest
+//         li   t1, <idx>
+//         sd   t1, 8(a5)
+//         ret
+//     .Ltest:
+fn emit_step(code: &mut Vec<u32>, idx: usize) {
+    let mut stop = vec![];
+    emit_load_immediate(&mut stop, T1, idx as u32);
+    stop.push(encode_s_format(SD, A5, T1, 8)); // sd t1, 8(a5)
+    stop.push(0x00008067); // ret
+
+    let mut skip_count = BEQ | (T0 << 15);
+    encode_b_format_immediate_offset(&mut skip_count, (5 + stop.len() as i32) * 4);
+    let mut skip_stop = BGE | (T1 << 15);
+    encode_b_format_immediate_offset(&mut skip_stop, (1 + stop.len() as i32) * 4);
+
+    code.extend_from_slice(&[
+        encode_i_format(LB, T0, A0, 0),    // lb t0, (a0)
+        skip_count,                        // beqz t0, .Ltest
+        encode_i_format(LD, T1, A5, 0),    // ld t1, 0(a5)
+        encode_i_format(ADDI, T1, T1, -1), // addi t1, t1, -1
+        encode_s_format(SD, A5, T1, 0),    // sd t1, 0(a5)
+        skip_stop,                         // bgez t1, .Ltest
+    ]);
+    code.extend_from_slice(&stop);
+}
+
+// Same as compile_mapped, with a choice of options
+pub fn compile_with(
+    ir: impl IntoIterator<Item = IRInsn>,
+    options: Options,
+) -> Result<(Vec<u8>, Vec<usize>), ()> {
+    // Straight-line code is built as a list of 32-bit instruction words, bracket
+    // branches are tracked separately and only woven in once their sizes are known
+    let mut code: Vec<u32> = Vec::with_capacity(1024);
+    let mut branches: Vec<Branch> = vec![];
+    let mut open_brackets: Vec<usize> = vec![];
+    // Word index in the straight-line code each IR instruction starts at, and
+    // how many branches the instructions before it have
+    let mut ir_starts: Vec<(usize, usize)> = vec![];
+
+    // The Fuel pointer gets out of the way of the file descriptors first
+    if options.count_steps {
+        code.push(encode_i_format(ADDI, A5, A3, 0)); // mv a5, a3
     }
 
-    fn eval_ir(ir: IR) -> Result<Self::Output, ()> {
-        let mut code: Vec<u8> = Vec::with_capacity(4096);
-        let mut jump_pair_positions: Vec<JumpPairPos> = vec![];
-
-        // Iterate over IR instructions, emitting the correct machine code
-        // to the code buffer for every instruction. Once we have iterated and
-        // emitted all our machine code, buffer should be have all instructions to run
-        for ir_insn in ir {
-            match ir_insn {
-                IRInsn::IncVal(operand) => {
-                    code.write_all(&[0x0, 0x05, 0x02, 0x83]).unwrap(); // lb t0, (a0)
-
-                    let mut addi = 0x28293; // addi t0, t0, 0x0
-                    addi |= (operand as i32);
-
-                    bytemuck::bytes_of(&addi)
-                        .iter()
-                        .rev()
-                        .for_each(|&b| code.write_all(&[b]).unwrap());
-
-                    code.write_all(&[0x0, 0x55, 0x0, 0x23]).unwrap(); // sb t0, (a0)
-                }
+    // The syscalls need a1 and a2 themselves, so the file descriptors
+    // move to a3 (input) and a4 (output), which nothing else touches
+    code.extend_from_slice(&[
+        encode_i_format(ADDI, A3, A1, 0), // mv a3, a1
+        encode_i_format(ADDI, A4, A2, 0), // mv a4, a2
+    ]);
 
-                IRInsn::DecVal(operand) => {
-                    code.write_all(&[0x0, 0x05, 0x02, 0x83]).unwrap(); // lb t0, (a0)
+    // Iterate over IR instructions, emitting the correct machine code
+    // to the code buffer for every instruction. Once we have iterated and
+    // emitted all our machine code, buffer should be have all instructions to run
+    for (idx, ir_insn) in ir.into_iter().enumerate() {
+        ir_starts.push((code.len(), branches.len()));
 
-                    let mut addi = 0x28293; // addi t0, t0, 0x0
-                    addi |= (operand as i32) & 0b1111_1111_1111;
+        match ir_insn {
+            IRInsn::IncVal(operand) => emit_value_add(&mut code, operand, true),
 
-                    bytemuck::bytes_of(&addi)
-                        .iter()
-                        .rev()
-                        .for_each(|&b| code.write_all(&[b]).unwrap());
+            IRInsn::DecVal(operand) => emit_value_add(&mut code, operand, false),
 
-                    code.write_all(&[0x0, 0x55, 0x0, 0x23]).unwrap(); // sb t0, (a0)
-                }
+            IRInsn::IncPtr(operand) => emit_pointer_move(&mut code, operand, true),
 
-                IRInsn::IncPtr(operand) => {
-                    let mut addi = 0x50513; // addi a0, a0, 0x0
-                    addi |= (operand as i32);
+            IRInsn::DecPtr(operand) => emit_pointer_move(&mut code, operand, false),
 
-                    bytemuck::bytes_of(&addi)
-                        .iter()
-                        .rev()
-                        .for_each(|&b| code.write_all(&[b]).unwrap());
+            IRInsn::JumpIfZero => {
+                // j <the loop's test>
+                open_brackets.push(branches.len());
+                branches.push(Branch {
+                    at: code.len(),
+                    kind: BranchKind::Always,
+                    partner: 0,
+                    form: BranchForm::Short,
+                });
+            }
+
+            IRInsn::JumpIfNonZero => {
+                let partner = open_brackets.pop().ok_or(())?;
+                if options.count_steps {
+                    emit_step(&mut code, idx);
                 }
 
-                IRInsn::DecPtr(operand) => {
-                    let mut addi = 0x50513; // addi a0, a0, 0x0
-                    addi |= (operand as i32) & 0b1111_1111_1111;
+                // Compare current pointed to value by first loading
+                // its byte to temp register t0
+                code.push(encode_i_format(LB, T0, A0, 0)); // lb t0, (a0)
 
-                    bytemuck::bytes_of(&addi)
-                        .iter()
-                        .rev()
-                        .for_each(|&b| code.write_all(&[b]).unwrap());
-                }
+                // bnez t0, <the loop's body>
+                branches[partner].partner = branches.len();
+                branches.push(Branch {
+                    at: code.len(),
+                    kind: BranchKind::IfNonZero,
+                    partner,
+                    form: BranchForm::Short,
+                });
+            }
 
-                IRInsn::JumpIfZero => {
-                    // Compare current pointed to value by first loading
-                    // its byte to temp register t0
-                    code.write_all(&[0x0, 0x05, 0x02, 0x83]).unwrap(); // lb t0, (a0)
+            IRInsn::GetChar => emit_syscall(&mut code, SYS_READ, A3),
 
-                    jump_pair_positions.push(JumpPairPos {
-                        fwd_jmp: code.len(),
-                        bwd_jmp: 0,
-                    });
+            IRInsn::PutChar => emit_syscall(&mut code, SYS_WRITE, A4),
+        }
+    }
 
-                    code.write_all(&[0x0, 0x2, 0x80, 0x63]).unwrap(); // beqz t0, 0
-                }
+    ir_starts.push((code.len(), branches.len()));
+    code.push(0x00008067); // ret
 
-                IRInsn::JumpIfNonZero => {
-                    /// Compare current pointed to value by first loading
-                    // its byte to temp register t0
-                    code.write_all(&[0x0, 0x05, 0x02, 0x83]).unwrap(); // lb t0, (a0)
+    if !open_brackets.is_empty() {
+        return Err(());
+    }
 
-                    jump_pair_positions
-                        .iter_mut()
-                        .rev()
-                        .find(|pair| pair.bwd_jmp == 0)
-                        .map(|pair| {
-                            pair.bwd_jmp = code.len();
-                        });
+    // Pick the smallest form for every branch, then weave the branches
+    // into the straight-line code at their final addresses
+    let addrs = relax_branches(&mut branches);
+    let mut linked: Vec<u32> = Vec::with_capacity(code.len() + branches.len() * 3);
+    let mut copied = 0;
 
-                    code.write_all(&[0x0, 0x2, 0x90, 0x63]).unwrap(); // bnez t0, 0
-                }
+    for (idx, branch) in branches.iter().enumerate() {
+        linked.extend_from_slice(&code[copied..branch.at]);
+        copied = branch.at;
 
-                IRInsn::GetChar => {
-                    // A inlined read(2) syscall, read(file_descriptor, buffer, length)
-                    // Most of this is putting the right values in registers before making
-                    // transfering control to kernel to process read(2)
-                    // syscall_number = 0
-                    // file_descriptor = STDIN = 0,
-                    // buffer = pointer head
-                    // length = 1 (single character)
-                    code.write_all(&[
-                        0xfe, 0xa1, 0x3e, 0x23, // sd a0, -4(sp) (save pointer to stack)
-                        0x0, 0x0, 0x5, 0xb7, // lui a1, 0x0 (STDIN)
-                        0x0, 0x5, 0x6, 0x33, // add a2, a0, zero (buffer)
-                        0x0, 0x0, 0x16, 0xb7, // lui a3, 0x1 (length)
-                        0x0, 0x0, 0x5, 0x37, // lui a0, 0x0 (syscall number)
-                        0x0, 0x0, 0x0, 0x73, // ecall (system call)
-                        0xff, 0xc1, 0x35, 0x3, // ld a0, -4(sp) (load it back after syscall)
-                    ])
-                    .unwrap();
-                }
+        let offset = branch_offset(&branches, &addrs, idx);
+        emit_branch(&mut linked, branch, offset as i32);
+    }
 
-                IRInsn::PutChar => {
-                    // A inlined write(2) syscall, write(file_descriptor, buffer, length)
-                    // Writes character from pointer head to STDOUT.
-                    // file_descriptor = STOUT = 1
-                    // syscall number = 1
-                    // length = 1 (a single character)
-                    code.write_all(&[
-                        0xfe, 0xa1, 0x3e, 0x23, // sd a0, -4(sp) (save our pointer to stack)
-                        0x0, 0x0, 0x15, 0xb7, // lui a1, 0x1 (STDIN)
-                        0x0, 0x5, 0x6, 0x33, // add a2, a0, zero (buffer)
-                        0x0, 0x0, 0x16, 0xb7, // lui a3, 0x1 (length)
-                        0x0, 0x0, 0x15, 0x37, // lui a0, 0x0 (syscall number)
-                        0x0, 0x0, 0x0, 0x73, // ecall (system call)
-                        0xff, 0xc1, 0x35, 0x3, // ld a0, -4(sp) (load it back after syscall)
-                    ])
-                    .unwrap();
+    linked.extend_from_slice(&code[copied..]);
+
+    // RISC-V instructions are always little endian in memory
+    let code = linked.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+
+    // Every instruction moves along by the size of the branches woven in for the
+    // instructions before it
+    let mut woven = vec![0];
+    for branch in &branches {
+        woven.push(woven.last().unwrap() + branch.len());
+    }
+
+    let ir_offsets = ir_starts
+        .iter()
+        .map(|&(start, branches_before)| (start + woven[branches_before]) * 4)
+        .collect();
+
+    Ok((code, ir_offsets))
+}
+
+// The fast compile tier, a single pass template compiler working straight off the
+// operators, like x86_64_linux::compile_template. Every operator gets a fixed
+// snippet of code, and loops are patched the moment their closing bracket is
+// reached. An opening bracket can't know how far away its test will be, so it
+// always takes the auipc+jalr pair that reaches anywhere, while a closing bracket
+// knows exactly how far back its body is and picks the smallest branch that gets there.
+pub fn compile_template(program: &Program) -> Result<Vec<u8>, ()> {
+    compile_template_mapped(program).map(|(code, _)| code)
+}
+
+// Same as compile_template, also handing back where each operator's code starts,
+// with one extra entry at the end for where the function's epilogue starts
+pub fn compile_template_mapped(program: &Program) -> Result<(Vec<u8>, Vec<usize>), ()> {
+    let mut code: Vec<u32> = Vec::with_capacity(program.code.len() * 3);
+    let mut op_offsets: Vec<usize> = Vec::with_capacity(program.code.len() + 1);
+    let mut open_brackets: Vec<usize> = vec![];
+
+    code.extend_from_slice(&[
+        encode_i_format(ADDI, A3, A1, 0), // mv a3, a1
+        encode_i_format(ADDI, A4, A2, 0), // mv a4, a2
+    ]);
+
+    for &op in program.code.iter() {
+        op_offsets.push(code.len() * 4);
+
+        match op {
+            Operator::IncrementPtr => emit_pointer_move(&mut code, 1, true),
+
+            Operator::DecrementPtr => emit_pointer_move(&mut code, 1, false),
+
+            Operator::IncrementValue => emit_value_add(&mut code, 1, true),
+
+            Operator::DecrementValue => emit_value_add(&mut code, 1, false),
+
+            Operator::JumpIfZero => {
+                open_brackets.push(code.len());
+                code.extend_from_slice(&[AUIPC, JALR]); // <patched below>
+            }
+
+            Operator::JumpIfNonZero => {
+                let jump = open_brackets.pop().ok_or(())?;
+                let test = code.len();
+                code.push(encode_i_format(LB, T0, A0, 0)); // lb t0, (a0)
+
+                let mut branch = Branch {
+                    at: code.len(),
+                    kind: BranchKind::IfNonZero,
+                    partner: 0,
+                    form: BranchForm::Short,
+                };
+                let offset = (jump as i64 + 2 - code.len() as i64) * 4;
+                while !branch.reaches(offset) {
+                    branch.grow();
                 }
+                emit_branch(&mut code, &branch, offset as i32);
+
+                // The jump lands on the test, the branch just past the jump
+                let mut patched = vec![];
+                let jump_to_test = Branch {
+                    at: jump,
+                    kind: BranchKind::Always,
+                    partner: 0,
+                    form: BranchForm::Far,
+                };
+                emit_branch(&mut patched, &jump_to_test, (test - jump) as i32 * 4);
+                code[jump..jump + 2].copy_from_slice(&patched);
             }
+
+            Operator::GetChar => emit_syscall(&mut code, SYS_READ, A3),
+
+            Operator::PutChar => emit_syscall(&mut code, SYS_WRITE, A4),
         }
+    }
+
+    op_offsets.push(code.len() * 4);
+    code.push(0x00008067); // ret
+
+    if !open_brackets.is_empty() {
+        return Err(());
+    }
+
+    Ok((
+        code.iter().flat_map(|insn| insn.to_le_bytes()).collect(),
+        op_offsets,
+    ))
+}
+
+#[cfg(all(target_arch = "riscv64", target_os = "linux"))]
+pub struct Jit;
+
+#[cfg(all(target_arch = "riscv64", target_os = "linux"))]
+impl Eval for Jit {
+    type Output = JittedFunction;
+
+    // The fast compile tier, see compile_template
+    fn eval_source(src: Program) -> Result<Self::Output, ()> {
+        let ops: Vec<IRInsn> = src.code.iter().map(|&op| op.into()).collect();
+        let key = Key::new(Arch::Riscv64, "fast", &ops);
+        let (code, _) = cache::cached(&key, || compile_template_mapped(&src))?;
+
+        Ok(JittedFunction::from_code(&code))
+    }
+
+    fn eval_ir(ir: IR) -> Result<Self::Output, ()> {
+        let ir: Vec<IRInsn> = ir.into_iter().collect();
+        let key = Key::new(Arch::Riscv64, "optimised", &ir);
+        let (code, _) = cache::cached(&key, || compile_mapped(ir.iter().cloned()))?;
+
+        Ok(JittedFunction::from_code(&code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck::{interpreter::Interpreter, jit::rv64_emulator::Rv64Emulator};
+
+    // Reference encodings below were produced with `llvm-mc -triple=riscv64 -show-encoding`
+
+    // Compile, leaving out the prologue every function starts with
+    fn compile_source(source: &str) -> Vec<u32> {
+        let ir: IR = Program::new(source).into();
+
+        let code: Vec<u32> = compile(ir)
+            .unwrap()
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+
+        assert_eq!(
+            code[..2],
+            [
+                0x00058693, // mv a3, a1
+                0x00060713, // mv a4, a2
+            ]
+        );
+
+        code[2..].to_vec()
+    }
+
+    #[test]
+    fn encodes_i_format() {
+        assert_eq!(encode_i_format(ADDI, A0, A0, 2047), 0x7ff50513); // addi a0, a0, 2047
+        assert_eq!(encode_i_format(ADDI, A0, A0, -2048), 0x80050513); // addi a0, a0, -2048
+        assert_eq!(encode_i_format(ADDI, T0, T0, -1), 0xfff28293); // addi t0, t0, -1
+        assert_eq!(encode_i_format(ADDIW, T1, T1, -1096), 0xbb83031b); // addiw t1, t1, -1096
+        assert_eq!(encode_i_format(SLLI, T1, T1, 32), 0x02031313); // slli t1, t1, 32
+        assert_eq!(encode_i_format(SRLI, T1, T1, 32), 0x02035313); // srli t1, t1, 32
+        assert_eq!(encode_i_format(LB, T0, A0, 0), 0x00050283); // lb t0, 0(a0)
+    }
+
+    #[test]
+    fn encodes_s_r_and_u_formats() {
+        assert_eq!(encode_s_format(SB, A0, T0, 0), 0x00550023); // sb t0, 0(a0)
+        assert_eq!(encode_r_format(ADD, A0, A0, T1), 0x00650533); // add a0, a0, t1
+        assert_eq!(encode_r_format(SUB, A0, A0, T1), 0x40650533); // sub a0, a0, t1
+        assert_eq!(encode_u_format(LUI, T1, 1), 0x00001337); // lui t1, 1
+        assert_eq!(encode_u_format(LUI, T1, 0x80000), 0x80000337); // lui t1, 524288
+    }
+
+    #[test]
+    fn encodes_branch_offsets() {
+        let mut beqz = BEQ | (T0 << 15);
+        encode_b_format_immediate_offset(&mut beqz, -4096);
+        assert_eq!(beqz, 0x80028063); // beqz t0, -4096
+
+        let mut bnez = BNE | (T0 << 15);
+        encode_b_format_immediate_offset(&mut bnez, 4094);
+        assert_eq!(bnez, 0x7e029fe3); // bnez t0, 4094
+
+        let mut jal = JAL;
+        encode_j_format_immediate_offset(&mut jal, -3000);
+        assert_eq!(jal, 0xc48ff06f); // j -3000
+    }
+
+    #[test]
+    fn small_pointer_moves_are_a_single_addi() {
+        assert_eq!(compile_source(">>>"), [0x00350513, 0x00008067]); // addi a0, a0, 3
+        assert_eq!(compile_source("<<<"), [0xffd50513, 0x00008067]); // addi a0, a0, -3
+    }
+
+    #[test]
+    fn large_pointer_moves_go_through_t1() {
+        assert_eq!(
+            compile_source(&">".repeat(3000)),
+            [
+                0x00001337, // lui t1, 1
+                0xbb83031b, // addiw t1, t1, -1096
+                0x00650533, // add a0, a0, t1
+                0x00008067, // ret
+            ]
+        );
 
-        code.write_all(&[0x0, 0x0, 0x80, 0x67]).unwrap(); // ret
-
-        jump_pair_positions.into_iter().for_each(|pair| {
-            let fwd_offset = (pair.bwd_jmp - pair.fwd_jmp) as i32;
-            let bwd_offset = -fwd_offset;
-
-            encode_b_format_immediate_offset(
-                bytemuck::from_bytes_mut(&mut code[pair.fwd_jmp..pair.fwd_jmp + 4]),
-                fwd_offset,
-            );
-
-            encode_b_format_immediate_offset(
-                bytemuck::from_bytes_mut(&mut code[pair.bwd_jmp..pair.bwd_jmp + 4]),
-                bwd_offset,
-            );
-        });
-
-        // Request executable region of memory from operating system using the well-known
-        // mmap Linux syscall (see man pages for mmap). This is a Nix API wrapper around said syscall,
-        // where anonymous is just a mapping without a file
-        let mut exec_mem: &mut [u8] = unsafe {
-            let ptr = mmap_anonymous(
-                None,
-                NonZero::new_unchecked(code.len()),
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE | ProtFlags::PROT_EXEC,
-                MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS,
-            )
-            .expect("Failed to get executable memory from OS for JIT compilation!")
-            .as_ptr()
-            .cast();
-
-            slice::from_raw_parts_mut(ptr, code.len())
+        assert_eq!(
+            compile_source(&"<".repeat(3000)),
+            [
+                0x00001337, // lui t1, 1
+                0xbb83031b, // addiw t1, t1, -1096
+                0x40650533, // sub a0, a0, t1
+                0x00008067, // ret
+            ]
+        );
+    }
+
+    #[test]
+    fn loads_full_width_immediates() {
+        let mut code = vec![];
+        emit_load_immediate(&mut code, T1, u32::MAX);
+
+        assert_eq!(
+            code,
+            [
+                0x00000337, // lui t1, 0
+                0xfff3031b, // addiw t1, t1, -1
+                0x02031313, // slli t1, t1, 32
+                0x02035313, // srli t1, t1, 32
+            ]
+        );
+    }
+
+    #[test]
+    fn value_decrements_are_negated() {
+        assert_eq!(
+            compile_source("---"),
+            [
+                0x00050283, // lb t0, 0(a0)
+                0xffd28293, // addi t0, t0, -3
+                0x00550023, // sb t0, 0(a0)
+                0x00008067, // ret
+            ]
+        );
+    }
+
+    // Arguments go in a0-a2 and the system call number in a7, as Linux expects on
+    // RISC-V, and the tape pointer waits out the ecall in a1 rather than on the stack
+    #[test]
+    fn io_follows_the_linux_syscall_abi() {
+        assert_eq!(
+            compile_source(",."),
+            [
+                0x00050593, // mv a1, a0
+                0x00068513, // mv a0, a3
+                0x00100613, // li a2, 1
+                0x03f00893, // li a7, 63 (read)
+                0x00000073, // ecall
+                0x00058513, // mv a0, a1
+                0x00050593, // mv a1, a0
+                0x00070513, // mv a0, a4
+                0x00100613, // li a2, 1
+                0x04000893, // li a7, 64 (write)
+                0x00000073, // ecall
+                0x00058513, // mv a0, a1
+                0x00008067, // ret
+            ]
+        );
+    }
+
+    #[test]
+    fn loops_are_tested_at_the_bottom() {
+        assert_eq!(
+            compile_source("[-]"),
+            [
+                0x0100006f, // j <the test>
+                0x00050283, // lb t0, 0(a0)
+                0xfff28293, // addi t0, t0, -1
+                0x00550023, // sb t0, 0(a0)
+                0x00050283, // lb t0, 0(a0)
+                0xfe0298e3, // bnez t0, <the body>
+                0x00008067, // ret
+            ]
+        );
+    }
+
+    // Counted loops take a step in front of the test, with the jump in skipping it
+    #[test]
+    fn counted_loops_take_a_step_per_back_edge() {
+        let options = Options { count_steps: true };
+        let code: Vec<u32> = compile_with(IR::from(Program::new("[-]")), options)
+            .unwrap()
+            .0
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+
+        assert_eq!(
+            code,
+            [
+                0x00068793, // mv a5, a3
+                0x00058693, // mv a3, a1
+                0x00060713, // mv a4, a2
+                0x0340006f, // j <the test>
+                0x00050283, // lb t0, 0(a0)
+                0xfff28293, // addi t0, t0, -1
+                0x00550023, // sb t0, 0(a0)
+                0x00050283, // lb t0, 0(a0)
+                0x02028063, // beqz t0, <the test>
+                0x0007b303, // ld t1, 0(a5)
+                0xfff30313, // addi t1, t1, -1
+                0x0067b023, // sd t1, 0(a5)
+                0x00035863, // bgez t1, <the test>
+                0x00200313, // li t1, 2
+                0x0067b423, // sd t1, 8(a5)
+                0x00008067, // ret
+                0x00050283, // lb t0, 0(a0)
+                0xfc0296e3, // bnez t0, <the body>
+                0x00008067, // ret
+            ]
+        );
+    }
+
+    // Relax a single loop whose body is `body_words` instructions long, handing back
+    // the forms its opening jump and closing test settle on
+    fn relaxed_forms(body_words: usize) -> (BranchForm, BranchForm) {
+        let mut branches = [
+            Branch {
+                at: 0,
+                kind: BranchKind::Always,
+                partner: 1,
+                form: BranchForm::Short,
+            },
+            Branch {
+                at: body_words,
+                kind: BranchKind::IfNonZero,
+                partner: 0,
+                form: BranchForm::Short,
+            },
+        ];
+        relax_branches(&mut branches);
+
+        (branches[0].form, branches[1].form)
+    }
+
+    #[test]
+    fn branches_take_the_smallest_form_that_reaches() {
+        use BranchForm::*;
+
+        assert_eq!(relaxed_forms(10), (Short, Short));
+
+        // A bnez reaches back 4KiB, exactly
+        assert_eq!(relaxed_forms(1024), (Short, Short));
+        assert_eq!(relaxed_forms(1025), (Short, Jal));
+
+        // A jal reaches 1MiB either way, for tests it sits one instruction further on
+        assert_eq!(relaxed_forms(262_143), (Short, Jal));
+        assert_eq!(relaxed_forms(262_144), (Far, Far));
+
+        // Far tests skip over an auipc+jalr pair, which splits the offset in two
+        let far = Branch {
+            at: 0,
+            kind: BranchKind::IfNonZero,
+            partner: 0,
+            form: Far,
         };
+        let mut code = vec![];
+        emit_branch(&mut code, &far, -1_048_580);
+        assert_eq!(
+            code,
+            [
+                0x00028663, // beqz t0, 12
+                0xfff00317, // auipc t1, 1048320
+                0xff830067, // jr -8(t1)
+            ]
+        );
+    }
+
+    #[test]
+    fn long_loops_relax_their_branches() {
+        // Alternate instructions so the IR can't collapse the loop body into a few
+        let body = "+>".repeat(800);
+        let code = compile_source(&format!("[{body}]"));
+
+        // j <the test>, a jal reaches much further than a branch
+        assert_eq!(code[0] & 0xfff, JAL);
+
+        // lb t0, 0(a0); beqz t0, 8; j <the body>; ret
+        let test = code.len() - 4;
+        assert_eq!(code[test..test + 2], [0x00050283, 0x00028463]);
+        assert_eq!(code[test + 2] & 0xfff, JAL);
+    }
+
+    #[test]
+    fn templates_patch_loops_in_one_pass() {
+        let code: Vec<u32> = compile_template(&Program::new("[-]"))
+            .unwrap()
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
 
-        // Copy our code inside the dynamically sized vector to the executable memory region
-        exec_mem.copy_from_slice(code.as_slice());
+        assert_eq!(
+            code[2..],
+            [
+                0x00000317, // auipc t1, 0
+                0x01430067, // jr 20(t1)
+                0x00050283, // lb t0, 0(a0)
+                0xfff28293, // addi t0, t0, -1
+                0x00550023, // sb t0, 0(a0)
+                0x00050283, // lb t0, 0(a0)
+                0xfe0298e3, // bnez t0, <the body>
+                0x00008067, // ret
+            ]
+        );
+    }
+
+    // Run the source through the interpreter and through the emulated RISC-V code
+    // of both tiers, making sure all produce the same output for the same input,
+    // and leave the tape and its pointer the same way
+    fn assert_matches_interpreter(source: &str, input: &[u8]) {
+        let program = Program::new(source);
+
+        let mut expected = vec![];
+        let mut remaining_input = input.iter();
+        let result = Interpreter::run_with_io(
+            &program,
+            || {
+                *remaining_input
+                    .next()
+                    .expect("Interpreter ran out of input")
+            },
+            |byte| expected.push(byte),
+        );
+
+        // Both tiers, the template compiler's code running a good deal longer, and
+        // the optimised tier counting its steps, with fuel to spare
+        let template = compile_template(&program).unwrap();
+        let optimised = compile(IR::from(program)).unwrap();
+        let options = Options { count_steps: true };
+        let (counted, _) = compile_with(IR::from(Program::new(source)), options).unwrap();
+
+        for code in [template, optimised, counted] {
+            let mut emulator = Rv64Emulator::new(&code, input);
+            emulator.run(400_000_000);
+
+            assert_eq!(emulator.output, expected);
+            assert_eq!(emulator.tape_offset() as usize, result.pointer);
+            assert!(emulator.tape == *result.tape);
+        }
+    }
+
+    #[test]
+    fn runs_hello_world() {
+        assert_matches_interpreter(include_str!("../../../test_programs/hello_world.bf"), b"");
+    }
+
+    #[test]
+    fn runs_sierpinski() {
+        assert_matches_interpreter(include_str!("../../../test_programs/sierpinski.bf"), b"");
+    }
+
+    #[test]
+    fn runs_beer() {
+        assert_matches_interpreter(include_str!("../../../test_programs/beer.bf"), b"");
+    }
+
+    #[test]
+    fn runs_programs_reading_input() {
+        assert_matches_interpreter(include_str!("../../../test_programs/3out.bf"), b"abc");
+        assert_matches_interpreter(include_str!("../../../test_programs/fibonacci.bf"), b"\x0a");
+    }
+
+    #[test]
+    fn runs_large_pointer_moves() {
+        let source = format!("{}+++.{}.", ">".repeat(5000), "<".repeat(4000));
+        assert_matches_interpreter(&source, b"");
+    }
+
+    #[test]
+    fn runs_loops_needing_far_branches() {
+        // Each ">+<" is five instructions, so this loop body is well over a megabyte
+        let source = format!("+[-{}]>.", ">+<".repeat(60_000));
+        assert_matches_interpreter(&source, b"");
+    }
+
+    // Counted code stops on the same loop, with the same tape, as the interpreter
+    // given the same budget
+    #[test]
+    fn counted_code_stops_where_the_interpreter_does() {
+        use crate::brainfuck::{ir, tape::Tape, Budget, Limit};
+
+        let source = "+++\n[>+<-]\n>[\n+]";
+        let program = Program::new(source);
+        let ir_lines = ir::source_lines(&program);
+        let options = Options { count_steps: true };
+        let (code, _) = compile_with(IR::from(Program::new(source)), options).unwrap();
+
+        for (steps, line, pointer) in [(0, 2, 0), (1, 2, 0), (2, 4, 1), (4, 4, 1)] {
+            let budget = Budget {
+                max_steps: Some(steps),
+                ..Default::default()
+            };
+            let expected =
+                Interpreter::run_on(&program, Tape::new(), budget, || 0, |_| {}).unwrap_err();
+
+            let mut emulator = Rv64Emulator::new(&code, b"");
+            emulator.fuel[0] = steps as i64;
+            emulator.run(10_000);
+
+            assert_eq!(expected.limit, Limit::Steps);
+            assert!(emulator.fuel[0] < 0);
+            assert_eq!(program.lines[expected.position], line);
+            assert_eq!(ir_lines[emulator.fuel[1] as usize], line);
+            assert_eq!(emulator.tape_offset() as usize, pointer);
+            assert_eq!(expected.pointer, pointer);
+            assert!(emulator.tape == *expected.tape);
+        }
 
-        Ok(JittedFunction(exec_mem.as_mut_ptr().cast(), exec_mem.len()))
+        // Enough fuel for every back-edge runs to the end, the same as uncounted code
+        let mut emulator = Rv64Emulator::new(&code, b"");
+        emulator.fuel[0] = 300;
+        emulator.run(10_000);
+        assert_eq!((emulator.fuel[0], emulator.tape_offset()), (300 - 254, 1));
     }
 }
@@ -0,0 +1,249 @@
+// A tiny RV64I emulator, just enough of the ISA to run what the RISC-V backend
+// generates, so its output can be checked on hosts that aren't riscv64.
+//
+// Memory is three flat regions: the code, mapped at CODE_BASE, the tape, mapped
+// at TAPE_BASE, and the two doublewords of a Fuel, mapped at FUEL_BASE. The generated
+// function is entered with a0 pointing at the start of the tape, a1 and a2 holding
+// the stdin and stdout file descriptors, a3 pointing at the Fuel
+// and ra holding RETURN_ADDR, and the emulator stops
+// as soon as the program jumps there. The only syscalls are read(2) on stdin
+// and write(2) on stdout, backed by byte buffers.
+
+const CODE_BASE: u64 = 0x1000_0000;
+const TAPE_BASE: u64 = 0x2000_0000;
+const FUEL_BASE: u64 = 0x3000_0000;
+const RETURN_ADDR: u64 = 0xdead_0000;
+
+const RA: usize = 1;
+const A0: usize = 10;
+const A1: usize = 11;
+const A2: usize = 12;
+const A3: usize = 13;
+const A7: usize = 17;
+
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+
+pub struct Rv64Emulator<'a> {
+    regs: [u64; 32],
+    pc: u64,
+    code: &'a [u8],
+    pub tape: Vec<u8>,
+    // Steps remaining, then where the code stopped, see jit::Fuel
+    pub fuel: [i64; 2],
+    input: &'a [u8],
+    pub output: Vec<u8>,
+}
+
+// Sign extend the lowest `bits` bits of `value`
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value as u64) << shift) as i64 >> shift
+}
+
+impl<'a> Rv64Emulator<'a> {
+    pub fn new(code: &'a [u8], input: &'a [u8]) -> Self {
+        let mut regs = [0u64; 32];
+        regs[A0] = TAPE_BASE;
+        regs[A1] = 0;
+        regs[A2] = 1;
+        regs[A3] = FUEL_BASE;
+        regs[RA] = RETURN_ADDR;
+
+        Self {
+            regs,
+            pc: CODE_BASE,
+            code,
+            tape: vec![0u8; 30_000],
+            fuel: [i64::MAX, 0],
+            input,
+            output: vec![],
+        }
+    }
+
+    // Run until the generated function returns, panicking on anything
+    // the emulator doesn't understand or after `max_steps` instructions
+    pub fn run(&mut self, max_steps: usize) {
+        for _ in 0..max_steps {
+            if self.pc == RETURN_ADDR {
+                return;
+            }
+
+            self.step();
+        }
+
+        panic!("Emulated program did not return within {max_steps} instructions");
+    }
+
+    // Position of the tape pointer, as an offset into the tape
+    pub fn tape_offset(&self) -> u64 {
+        self.regs[A0] - TAPE_BASE
+    }
+
+    fn fetch(&self) -> u32 {
+        let offset = (self.pc - CODE_BASE) as usize;
+        let bytes = self
+            .code
+            .get(offset..offset + 4)
+            .unwrap_or_else(|| panic!("Jumped outside of the code to {:#x}", self.pc));
+
+        u32::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    fn tape_index(&self, addr: u64, len: usize) -> usize {
+        let offset = addr.wrapping_sub(TAPE_BASE) as usize;
+        assert!(
+            offset + len <= self.tape.len(),
+            "Memory access outside of the tape at {addr:#x}"
+        );
+
+        offset
+    }
+
+    fn fuel_index(&self, addr: u64) -> usize {
+        let offset = addr.wrapping_sub(FUEL_BASE);
+        assert!(
+            offset < 16 && offset.is_multiple_of(8),
+            "Doubleword access outside of the fuel at {addr:#x}"
+        );
+
+        offset as usize / 8
+    }
+
+    fn set_reg(&mut self, rd: usize, value: u64) {
+        // x0 is hardwired to zero
+        if rd != 0 {
+            self.regs[rd] = value;
+        }
+    }
+
+    fn step(&mut self) {
+        let insn = self.fetch();
+
+        let opcode = insn & 0x7f;
+        let rd = ((insn >> 7) & 0x1f) as usize;
+        let funct3 = (insn >> 12) & 0b111;
+        let rs1 = self.regs[((insn >> 15) & 0x1f) as usize];
+        let rs2 = self.regs[((insn >> 20) & 0x1f) as usize];
+        let funct7 = insn >> 25;
+
+        let i_imm = sign_extend(insn >> 20, 12);
+        let s_imm = sign_extend(((insn >> 25) << 5) | ((insn >> 7) & 0x1f), 12);
+        let b_imm = sign_extend(
+            ((insn >> 31) << 12)
+                | (((insn >> 7) & 0b1) << 11)
+                | (((insn >> 25) & 0b11_1111) << 5)
+                | (((insn >> 8) & 0b1111) << 1),
+            13,
+        );
+        let j_imm = sign_extend(
+            ((insn >> 31) << 20)
+                | (((insn >> 12) & 0xff) << 12)
+                | (((insn >> 20) & 0b1) << 11)
+                | (((insn >> 21) & 0x3ff) << 1),
+            21,
+        );
+        let u_imm = sign_extend(insn & 0xffff_f000, 32);
+
+        let mut next_pc = self.pc.wrapping_add(4);
+
+        match (opcode, funct3) {
+            // lb, lbu
+            (0x03, 0b000 | 0b100) => {
+                let idx = self.tape_index(rs1.wrapping_add(i_imm as u64), 1);
+                let byte = self.tape[idx];
+                let value = if funct3 == 0 {
+                    byte as i8 as i64 as u64
+                } else {
+                    byte as u64
+                };
+                self.set_reg(rd, value);
+            }
+
+            // ld, only ever of the fuel
+            (0x03, 0b011) => {
+                let idx = self.fuel_index(rs1.wrapping_add(i_imm as u64));
+                self.set_reg(rd, self.fuel[idx] as u64);
+            }
+
+            // sb
+            (0x23, 0b000) => {
+                let idx = self.tape_index(rs1.wrapping_add(s_imm as u64), 1);
+                self.tape[idx] = rs2 as u8;
+            }
+
+            // sd, likewise
+            (0x23, 0b011) => {
+                let idx = self.fuel_index(rs1.wrapping_add(s_imm as u64));
+                self.fuel[idx] = rs2 as i64;
+            }
+
+            // addi, slli, srli
+            (0x13, 0b000) => self.set_reg(rd, rs1.wrapping_add(i_imm as u64)),
+            (0x13, 0b001) => self.set_reg(rd, rs1 << (i_imm & 0x3f)),
+            (0x13, 0b101) if funct7 >> 1 == 0 => self.set_reg(rd, rs1 >> (i_imm & 0x3f)),
+
+            // addiw
+            (0x1b, 0b000) => {
+                let value = (rs1 as i32).wrapping_add(i_imm as i32);
+                self.set_reg(rd, value as i64 as u64);
+            }
+
+            // add, sub
+            (0x33, 0b000) if funct7 == 0 => self.set_reg(rd, rs1.wrapping_add(rs2)),
+            (0x33, 0b000) if funct7 == 0b010_0000 => self.set_reg(rd, rs1.wrapping_sub(rs2)),
+
+            // lui, auipc
+            (0x37, _) => self.set_reg(rd, u_imm as u64),
+            (0x17, _) => self.set_reg(rd, self.pc.wrapping_add(u_imm as u64)),
+
+            // beq, bne, bge
+            (0x63, 0b000) if rs1 == rs2 => next_pc = self.pc.wrapping_add(b_imm as u64),
+            (0x63, 0b001) if rs1 != rs2 => next_pc = self.pc.wrapping_add(b_imm as u64),
+            (0x63, 0b101) if rs1 as i64 >= rs2 as i64 => {
+                next_pc = self.pc.wrapping_add(b_imm as u64)
+            }
+            (0x63, 0b000 | 0b001 | 0b101) => {}
+
+            // jal, jalr
+            (0x6f, _) => {
+                self.set_reg(rd, next_pc);
+                next_pc = self.pc.wrapping_add(j_imm as u64);
+            }
+            (0x67, 0b000) => {
+                self.set_reg(rd, next_pc);
+                next_pc = rs1.wrapping_add(i_imm as u64) & !1;
+            }
+
+            // ecall
+            (0x73, 0b000) if insn == 0x73 => self.syscall(),
+
+            _ => panic!("Unsupported instruction {insn:#010x} at {:#x}", self.pc),
+        }
+
+        self.pc = next_pc;
+    }
+
+    fn syscall(&mut self) {
+        let (fd, buf, len) = (self.regs[A0], self.regs[A1], self.regs[A2] as usize);
+        let idx = self.tape_index(buf, len);
+
+        let result = match (self.regs[A7], fd) {
+            (SYS_READ, 0) => {
+                let count = len.min(self.input.len());
+                self.tape[idx..idx + count].copy_from_slice(&self.input[..count]);
+                self.input = &self.input[count..];
+                count
+            }
+
+            (SYS_WRITE, 1) => {
+                self.output.extend_from_slice(&self.tape[idx..idx + len]);
+                len
+            }
+
+            (number, fd) => panic!("Unsupported syscall {number} on fd {fd}"),
+        };
+
+        self.regs[A0] = result as u64;
+    }
+}
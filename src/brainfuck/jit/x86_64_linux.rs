@@ -1,213 +1,633 @@
+// The code generator in this file is plain Rust, so it is built on every host,
+// which keeps it usable for ahead-of-time compilation. Only x86-64 Linux gets
+// the executable `Jit` on top.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use super::JittedFunction;
 use super::{
+    cache::{self, Key},
+    Arch,
+};
+use crate::brainfuck::{
     ir::{IRInsn, IR},
-    program::Program,
+    program::{Operator, Program},
     Eval,
 };
 
-use nix::sys::mman::{mmap_anonymous, munmap, MapFlags, ProtFlags};
-use std::{ffi::c_void, io::Write, num::NonZero, ptr::NonNull, slice};
+use std::io::Write;
+
+// Generated functions take the file descriptors to do I/O on as their second and
+// third arguments, in %rsi and %rdx. Both registers get used for the syscalls
+// themselves, so the descriptors are moved to %r8 (input) and %r9 (output) up
+// front, the syscall instruction leaves those alone.
+const PROLOGUE: [u8; 6] = [
+    0x49, 0x89, 0xf0, // mov %rsi, %r8
+    0x49, 0x89, 0xd1, // mov %rdx, %r9
+];
+
+// A inlined read(2) syscall, read(file_descriptor, buffer, length)
+// Most of this is putting the right values in registers before making
+// transfering control to kernel to process read(2)
+// syscall_number = 0
+// file_descriptor = input, kept in %r8
+// buffer = pointer head
+// length = 1 (single character)
+const GETCHAR_SYSCALL: [u8; 24] = [
+    // push %rdi
+    0x57, // mov $0, %rax (syscall number)
+    0x48, 0xc7, 0xc0, 0x0, 0x0, 0x0, 0x0,
+    // mov %rdi, %rsi (second argument, buffer pointer)
+    0x48, 0x89, 0xfe, // mov %r8, %rdi (first argument)
+    0x4c, 0x89, 0xc7, // mov $1, %rdx (third argument)
+    0x48, 0xc7, 0xc2, 0x01, 0x0, 0x0, 0x0, // syscall, transfer to kernel
+    0x0f, 0x05, // pop %rdi
+    0x5f,
+];
+
+// A inlined write(2) syscall, write(file_descriptor, buffer, length)
+// Writes character from pointer head to the output.
+// file_descriptor = output, kept in %r9
+// syscall number = 1
+// length = 1 (a single character)
+const PUTCHAR_SYSCALL: [u8; 24] = [
+    // push %rdi
+    0x57, // mov $1, %rax (syscall number)
+    0x48, 0xc7, 0xc0, 0x01, 0x0, 0x0, 0x0,
+    // mov %rdi, %rsi (second argument, buffer pointer)
+    0x48, 0x89, 0xfe, // mov %r9, %rdi (first argument)
+    0x4c, 0x89, 0xcf, // mov $1, %rdx (third argument)
+    0x48, 0xc7, 0xc2, 0x01, 0x0, 0x0, 0x0, // syscall, transfer to kernel
+    0x0f, 0x05, // pop %rdi
+    0x5f,
+];
+
+// The recommended NOPs of every length up to 9 bytes, from Intel's optimization manual
+const NOPS: [&[u8]; 9] = [
+    &[0x90],                                                 // nop
+    &[0x66, 0x90],                                           // xchg %ax, %ax
+    &[0x0f, 0x1f, 0x00],                                     // nopl (%rax)
+    &[0x0f, 0x1f, 0x40, 0x00],                               // nopl 0(%rax)
+    &[0x0f, 0x1f, 0x44, 0x00, 0x00],                         // nopl 0(%rax,%rax,1)
+    &[0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00],                   // nopw 0(%rax,%rax,1)
+    &[0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00],             // nopl 0(%rax), 32 bit displacement
+    &[0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],       // nopl 0(%rax,%rax,1), likewise
+    &[0x66, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00], // nopw 0(%rax,%rax,1), likewise
+];
+
+// Loop bodies start on this boundary when aligning loops
+const LOOP_ALIGN: usize = 16;
+
+// The fast compile tier, a single pass template compiler working straight off the
+// operators. Every operator gets a fixed snippet of machine code, and loops are
+// patched the moment their closing bracket is reached, so there is no IR to build
+// and no second pass. The code is slower than what compile produces, but for short
+// lived programs the time saved compiling more than makes up for it.
+pub fn compile_template(program: &Program) -> Result<Vec<u8>, ()> {
+    compile_template_mapped(program).map(|(code, _)| code)
+}
 
-pub struct Jit;
+// Same as compile_template, also handing back where each operator's code starts,
+// with one extra entry at the end for where the function's epilogue starts
+pub fn compile_template_mapped(program: &Program) -> Result<(Vec<u8>, Vec<usize>), ()> {
+    let mut code: Vec<u8> = Vec::with_capacity(program.code.len() * 4);
+    let mut op_offsets: Vec<usize> = Vec::with_capacity(program.code.len() + 1);
+    let mut open_brackets: Vec<usize> = vec![];
 
-// The Jit produces JittedFunctions from Brainfuck IR, its a tuple struct
-// with a void pointer, and a size of memory pointed to by pointer (weird slice)
-pub struct JittedFunction(*mut c_void, usize);
+    code.extend_from_slice(&PROLOGUE);
 
-impl JittedFunction {
-    pub fn run(&self) {
-        // Converting a pointer of bytes to a function pointer in Rust is, as one would expect,
-        // very unsafe. This requires an intrinsics function changing arbitrary memory objects
-        // called "transmute".
-        let function =
-            unsafe { std::mem::transmute::<*mut c_void, extern "C" fn(*const u8)>(self.0) };
+    for &op in program.code.iter() {
+        op_offsets.push(code.len());
 
-        let byte_arr = [0u8; 30_000];
+        match op {
+            Operator::IncrementPtr => code.extend_from_slice(&[0x48, 0xff, 0xc7]), // incq %rdi
 
-        // Call the function
-        function(byte_arr.as_ptr())
-    }
-}
+            Operator::DecrementPtr => code.extend_from_slice(&[0x48, 0xff, 0xcf]), // decq %rdi
+
+            Operator::IncrementValue => code.extend_from_slice(&[0xfe, 0x07]), // incb (%rdi)
+
+            Operator::DecrementValue => code.extend_from_slice(&[0xfe, 0x0f]), // decb (%rdi)
+
+            // Loops are rotated like in compile's code, see generate
+            Operator::JumpIfZero => {
+                open_brackets.push(code.len());
+                code.extend_from_slice(&[0xe9, 0x0, 0x0, 0x0, 0x0]); // jmp <patched below>
+            }
+
+            Operator::JumpIfNonZero => {
+                let fwd_jmp = open_brackets.pop().ok_or(())?;
+                let test = code.len();
+                code.extend_from_slice(&[0x80, 0x3f, 0x00]); // cmpb $0, (%rdi)
+
+                let bwd_jmp = code.len();
+                code.extend_from_slice(&[0x0f, 0x85, 0x0, 0x0, 0x0, 0x0]); // jnz <patched below>
+
+                // The jmp lands on the test, the jnz just past the jmp
+                let fwd_offset = (test - (fwd_jmp + 5)) as i32;
+                let bwd_offset = (fwd_jmp + 5) as i32 - (bwd_jmp + 6) as i32;
+
+                code[fwd_jmp + 1..fwd_jmp + 5].copy_from_slice(&fwd_offset.to_le_bytes());
+                code[bwd_jmp + 2..bwd_jmp + 6].copy_from_slice(&bwd_offset.to_le_bytes());
+            }
+
+            Operator::GetChar => code.extend_from_slice(&GETCHAR_SYSCALL),
 
-// Keeping in touch with Rust's stance on RAII driven design, implemented
-// Drop for the JittedFunction object, which call syscall munmap(2) to
-// relinquish the executable region of memory we requested from Linux
-impl Drop for JittedFunction {
-    fn drop(&mut self) {
-        unsafe {
-            munmap(NonNull::new_unchecked(self.0), self.1)
-                .expect("Failed to release memory back to OS!:");
+            Operator::PutChar => code.extend_from_slice(&PUTCHAR_SYSCALL),
         }
     }
+
+    // Hand the final tape pointer back to the caller
+    op_offsets.push(code.len());
+    code.extend_from_slice(&[0x48, 0x89, 0xf8]); // mov %rdi, %rax
+    code.push(0xc3); // retq
+
+    if !open_brackets.is_empty() {
+        return Err(());
+    }
+
+    Ok((code, op_offsets))
 }
 
-struct JumpPairPos {
-    fwd_jmp: usize,
-    bwd_jmp: usize,
+// How compiled code does its I/O
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum IoMode {
+    // Inlined read(2)/write(2) system calls, on file descriptors the caller
+    // passes in, the code needs nothing else to run
+    #[default]
+    Syscall,
+    // Calls to the C library's getchar/putchar, left for a linker to resolve
+    LibcCall,
 }
 
-impl Eval for Jit {
-    type Output = JittedFunction;
+// Choices the code generator leaves to its caller
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    pub io: IoMode,
+    // Pad with NOPs so every loop body starts on a 16 byte boundary, where the
+    // front end fetches and predicts it best. Costs a few bytes per loop, and only
+    // pays off if the function itself starts on a 16 byte boundary.
+    pub align_loops: bool,
+    // Count loop back-edges against the Fuel passed in as a fourth argument, and
+    // stop once it runs out. Needs Syscall I/O, calls into libc would clobber it.
+    pub count_steps: bool,
+}
 
-    fn eval_source(src: Program) -> Result<Self::Output, ()> {
-        unimplemented!()
+// A spot in the code referring to an external symbol, here always the 32 bit
+// operand of a call, for the linker to fill in (R_X86_64_PLT32 in ELF terms)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: usize,
+    pub symbol: &'static str,
+}
+
+// Compile Brainfuck IR to x86-64 machine code. The generated function takes
+// the tape pointer in %rdi, the input and output file descriptors in %rsi and
+// %rdx, and returns the final tape pointer in %rax.
+pub fn compile(ir: impl IntoIterator<Item = IRInsn>) -> Result<Vec<u8>, ()> {
+    compile_mapped(ir).map(|(code, _)| code)
+}
+
+// Same as compile, also handing back where each IR instruction's code starts,
+// with one extra entry at the end for where the function's epilogue starts
+pub fn compile_mapped(ir: impl IntoIterator<Item = IRInsn>) -> Result<(Vec<u8>, Vec<usize>), ()> {
+    compile_with(ir, Options::default()).map(|(code, _, ir_offsets)| (code, ir_offsets))
+}
+
+// Same as compile_mapped, with a choice of options. Also hands back every
+// relocation the code needs, which is none unless calling into libc.
+pub fn compile_with(
+    ir: impl IntoIterator<Item = IRInsn>,
+    options: Options,
+) -> Result<Generated, ()> {
+    generate(ir, options)
+}
+
+pub type Generated = (Vec<u8>, Vec<Relocation>, Vec<usize>);
+
+// Branches are generated without knowing how far they go, so they're left out of
+// the code at first, noted down with everything else that can only be sized once
+// the code around it is laid out. Each one goes at a position in the code as
+// generated.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Fixup {
+    // A jmp to a label
+    Jump { label: usize },
+    // A jz or jnz, by its condition code, to a label
+    Branch { condition: u8, label: usize },
+    // NOPs up to the next LOOP_ALIGN boundary
+    Align,
+    // Where a label is, taking no space
+    Label(usize),
+}
+
+impl Fixup {
+    fn len(self, addr: usize, long: bool) -> usize {
+        match self {
+            Fixup::Jump { .. } if long => 5,
+            Fixup::Branch { .. } if long => 6,
+            Fixup::Jump { .. } | Fixup::Branch { .. } => 2,
+            Fixup::Align => addr.next_multiple_of(LOOP_ALIGN) - addr,
+            Fixup::Label(_) => 0,
+        }
     }
+}
 
-    fn eval_ir(ir: IR) -> Result<Self::Output, ()> {
-        let mut code: Vec<u8> = Vec::with_capacity(4096);
-        let mut jump_pair_positions: Vec<JumpPairPos> = vec![];
-
-        // Iterate over IR instructions, emitting the correct machine code
-        // to the code buffer for every instruction. Once we have iterated and
-        // emitted all our machine code, buffer should be have all instructions to run
-        for ir_insn in ir {
-            match ir_insn {
-                IRInsn::IncVal(operand) => {
-                    code.write_all(&[0x80, 0x07, operand]) // addb $<operand>, (%rdi)
-                        .unwrap();
-                }
+// Put the fixups into the code. Every branch starts out as a 2 byte jmp or jcc rel8,
+// and those that can't reach their label are grown to a 5 byte jmp or 6 byte jcc
+// rel32 until they all can. Branches only ever grow, so this settles after a few rounds at most.
+// Returns the final code along with how many bytes went in ahead of each fixup,
+// and after the last one, for moving offsets into the code along.
+fn lay_out(code: &[u8], fixups: &[(usize, Fixup)], labels: usize) -> (Vec<u8>, Vec<usize>) {
+    let mut long = vec![false; fixups.len()];
+
+    let (addrs, label_addrs) = loop {
+        let mut addrs = Vec::with_capacity(fixups.len());
+        let mut label_addrs = vec![0; labels];
+        let mut inserted = 0;
+
+        for (idx, &(at, fixup)) in fixups.iter().enumerate() {
+            let addr = at + inserted;
+            if let Fixup::Label(label) = fixup {
+                label_addrs[label] = addr;
+            }
 
-                IRInsn::DecVal(operand) => {
-                    code.write_all(&[0x80, 0x2f, operand]) // subb $<operand>, (%rdi)
-                        .unwrap();
-                }
+            addrs.push(addr);
+            inserted += fixup.len(addr, long[idx]);
+        }
 
-                IRInsn::IncPtr(operand) => {
-                    let bytecode: Vec<u8> = {
-                        let mut v = vec![0x48, 0x81, 0xc7];
-                        v.extend_from_slice(bytemuck::bytes_of(&operand));
-                        v
-                    }; // addq $<operand>, %rdi
+        let mut grew = false;
+        for (idx, &(_, fixup)) in fixups.iter().enumerate() {
+            if let Fixup::Jump { label } | Fixup::Branch { label, .. } = fixup {
+                let rel = label_addrs[label] as isize - (addrs[idx] + 2) as isize;
 
-                    code.write_all(bytecode.as_slice()).unwrap();
+                if !long[idx] && i8::try_from(rel).is_err() {
+                    long[idx] = true;
+                    grew = true;
                 }
+            }
+        }
 
-                IRInsn::DecPtr(operand) => {
-                    let bytecode: Vec<u8> = {
-                        let mut v = vec![0x48, 0x81, 0xef];
-                        v.extend_from_slice(bytemuck::bytes_of(&operand));
-                        v
-                    }; // subq $<operand>, %rdi
+        if !grew {
+            break (addrs, label_addrs);
+        }
+    };
 
-                    code.write_all(bytecode.as_slice()).unwrap();
-                }
+    let mut out = Vec::with_capacity(code.len() + fixups.len() * 6);
+    let mut inserted = vec![0];
+    let mut copied = 0;
 
-                IRInsn::JumpIfZero => {
-                    /// Compare current pointed to value by loading
-                    // its byte in %al, comparing it with zero.
-                    code.write_all(&[
-                        0x8a, 0x07, // mov %al byte [rdi]
-                        0x84, 0xc0, // test %al, %al
-                    ])
-                    .unwrap();
-
-                    jump_pair_positions.push(JumpPairPos {
-                        fwd_jmp: code.len(),
-                        bwd_jmp: 0,
-                    });
-
-                    code.write_all(&[0x0f, 0x84, 0x0, 0x0, 0x0, 0x0]).unwrap();
-                }
+    for (idx, &(at, fixup)) in fixups.iter().enumerate() {
+        out.extend_from_slice(&code[copied..at]);
+        copied = at;
+
+        match fixup {
+            Fixup::Jump { label } if long[idx] => {
+                let rel = label_addrs[label] as i32 - (addrs[idx] + 5) as i32;
+                out.push(0xe9); // jmp rel32
+                out.extend_from_slice(&rel.to_le_bytes());
+            }
+
+            Fixup::Jump { label } => {
+                let rel = label_addrs[label] as isize - (addrs[idx] + 2) as isize;
+                out.extend_from_slice(&[0xeb, rel as u8]); // jmp rel8
+            }
+
+            Fixup::Branch { condition, label } if long[idx] => {
+                let rel = label_addrs[label] as i32 - (addrs[idx] + 6) as i32;
+                out.extend_from_slice(&[0x0f, condition]); // jcc rel32
+                out.extend_from_slice(&rel.to_le_bytes());
+            }
 
-                IRInsn::JumpIfNonZero => {
-                    // Compare current pointed to value by loading
-                    // its byte in %al, comparing it with zero.
-                    code.write_all(&[
-                        // mov %al byte [rdi]
-                        0x8a, 0x07, // test %al, %al
-                        0x84, 0xc0, // jnz <0 offset to be patched later>
-                    ])
-                    .unwrap();
-
-                    jump_pair_positions
-                        .iter_mut()
-                        .rev()
-                        .find(|pair| pair.bwd_jmp == 0)
-                        .map(|pair| pair.bwd_jmp = code.len());
-
-                    code.write_all(&[0x0f, 0x85, 0x0, 0x0, 0x0, 0x0]).unwrap();
+            Fixup::Branch { condition, label } => {
+                let rel = label_addrs[label] as isize - (addrs[idx] + 2) as isize;
+                out.extend_from_slice(&[condition - 0x10, rel as u8]); // jcc rel8
+            }
+
+            Fixup::Align => {
+                let mut padding = fixup.len(addrs[idx], false);
+
+                while padding > 0 {
+                    let nop = NOPS[padding.min(NOPS.len()) - 1];
+                    out.extend_from_slice(nop);
+                    padding -= nop.len();
                 }
+            }
+
+            Fixup::Label(_) => {}
+        }
+
+        inserted.push(out.len() - copied);
+    }
+
+    out.extend_from_slice(&code[copied..]);
+
+    (out, inserted)
+}
+
+// Where the current cell's value is while generating code. Straight-line code
+// keeps it in %al, so a run of arithmetic and the bracket after it don't each go
+// to memory. It's written back before anything that needs memory up to date:
+// moving the pointer, I/O, leaving a loop iteration and returning.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Cell {
+    // Only in memory, %al holds nothing of use
+    InMemory,
+    // In %al, with memory holding the same value
+    Cached,
+    // In %al, newer than what's in memory
+    Dirty,
+}
+
+impl Cell {
+    // Make sure %al holds the current cell
+    fn load(&mut self, code: &mut Vec<u8>) {
+        if *self == Cell::InMemory {
+            // Zero extending into all of %eax rather than loading just %al, which
+            // would make the load wait on whatever last wrote the rest of %rax
+            code.write_all(&[0x0f, 0xb6, 0x07]).unwrap(); // movzbl (%rdi), %eax
+            *self = Cell::Cached;
+        }
+    }
+
+    // Make sure memory holds the current cell
+    fn spill(&mut self, code: &mut Vec<u8>) {
+        if *self == Cell::Dirty {
+            code.write_all(&[0x88, 0x07]).unwrap(); // mov %al, (%rdi)
+            *self = Cell::Cached;
+        }
+    }
+}
+
+fn generate(ir: impl IntoIterator<Item = IRInsn>, options: Options) -> Result<Generated, ()> {
+    let io = options.io;
+    if options.count_steps && io == IoMode::LibcCall {
+        return Err(());
+    }
+
+    let mut code: Vec<u8> = Vec::with_capacity(4096);
+    let mut relocations: Vec<Relocation> = vec![];
+    let mut cell = Cell::InMemory;
+
+    // Where each IR instruction starts in the code as generated, and how many
+    // fixups go in ahead of it
+    let mut ir_starts: Vec<(usize, usize)> = vec![];
+
+    // Every loop gets three labels, its body, its test and its exit, numbered in order
+    let mut fixups: Vec<(usize, Fixup)> = vec![];
+    let mut open_loops: Vec<usize> = vec![];
+    let mut labels = 0;
+
+    // Calls into libc don't need the file descriptors
+    if io == IoMode::Syscall {
+        code.extend_from_slice(&PROLOGUE);
+    }
+
+    // Like the file descriptors, the Fuel pointer is moved out of the way of
+    // syscall, which clobbers %rcx. Running out of fuel returns from the middle
+    // of the function, past the epilogue's write back.
+    let stop = labels;
+    if options.count_steps {
+        code.write_all(&[0x49, 0x89, 0xca]).unwrap(); // mov %rcx, %r10
+        labels += 1;
+    }
+
+    // Iterate over IR instructions, emitting the correct machine code
+    // to the code buffer for every instruction. Once we have iterated and
+    // emitted all our machine code, buffer should be have all instructions to run
+    for (idx, ir_insn) in ir.into_iter().enumerate() {
+        ir_starts.push((code.len(), fixups.len()));
+
+        // I/O works on the cell in memory, and clobbers %al along the way
+        if matches!(ir_insn, IRInsn::GetChar | IRInsn::PutChar) {
+            cell.spill(&mut code);
+            cell = Cell::InMemory;
+        }
+
+        match ir_insn {
+            IRInsn::IncVal(operand) => {
+                cell.load(&mut code);
+                code.write_all(&[0x04, operand]).unwrap(); // add $<operand>, %al
+                cell = Cell::Dirty;
+            }
+
+            IRInsn::DecVal(operand) => {
+                cell.load(&mut code);
+                code.write_all(&[0x2c, operand]).unwrap(); // sub $<operand>, %al
+                cell = Cell::Dirty;
+            }
 
-                IRInsn::GetChar => {
-                    // A inlined read(2) syscall, read(file_descriptor, buffer, length)
-                    // Most of this is putting the right values in registers before making
-                    // transfering control to kernel to process read(2)
-                    // syscall_number = 0
-                    // file_descriptor = STDIN = 0,
-                    // buffer = pointer head
-                    // length = 1 (single character)
-                    code.write_all(&[
-                        // push %rdi
-                        0x57, // mov $0, %rax (syscall number)
-                        0x48, 0xc7, 0xc0, 0x0, 0x0, 0x0, 0x0,
-                        // mov %rdi, %rsi (second argument, buffer pointer)
-                        0x48, 0x89, 0xfe, // mov $0, %rdi (first argument)
-                        0x48, 0xc7, 0xc7, 0x0, 0x0, 0x0, 0x0,
-                        // mov $1, %rdx (third argument)
-                        0x48, 0xc7, 0xc2, 0x01, 0x0, 0x0, 0x0,
-                        // syscall, transfer to kernel
-                        0x0f, 0x05, // pop %rdi
-                        0x5f,
-                    ])
-                    .unwrap();
+            IRInsn::IncPtr(operand) => {
+                cell.spill(&mut code);
+
+                let bytecode: Vec<u8> = {
+                    let mut v = vec![0x48, 0x81, 0xc7];
+                    v.extend_from_slice(bytemuck::bytes_of(&operand));
+                    v
+                }; // addq $<operand>, %rdi
+
+                code.write_all(bytecode.as_slice()).unwrap();
+                cell = Cell::InMemory;
+            }
+
+            IRInsn::DecPtr(operand) => {
+                cell.spill(&mut code);
+
+                let bytecode: Vec<u8> = {
+                    let mut v = vec![0x48, 0x81, 0xef];
+                    v.extend_from_slice(bytemuck::bytes_of(&operand));
+                    v
+                }; // subq $<operand>, %rdi
+
+                code.write_all(bytecode.as_slice()).unwrap();
+                cell = Cell::InMemory;
+            }
+
+            // Loops are rotated, tested once per iteration at the bottom. The opening
+            // bracket jumps straight down to the test, and the closing bracket is
+            // the test, branching back up to the body while the cell isn't zero:
+            //
+            //         jmp .Ltest
+            //     .Lbody:
+            //         <body>
+            //     .Ltest:
+            //         test %al, %al
+            //         jnz .Lbody
+            //
+            // Both ways into the test have the cell in %al and written back to
+            // memory, so the loop body and the code after the loop start out with
+            // it cached.
+            //
+            // Counting steps, only going back around a loop may take one, so the
+            // loop is entered by testing the cell up front instead:
+            //
+            //         test %al, %al
+            //         jz .Lexit
+            //     .Lbody:
+            //         <body>
+            //         test %al, %al
+            //         jz .Lexit
+            //         subq $1, (%r10)
+            //         jns .Lbody
+            //         movq $<closing bracket>, 8(%r10)
+            //         jmp <the return>
+            //     .Lexit:
+            IRInsn::JumpIfZero => {
+                cell.load(&mut code);
+                cell.spill(&mut code);
+
+                let body = labels;
+                open_loops.push(body);
+                labels += 3;
+
+                if options.count_steps {
+                    code.write_all(&[0x84, 0xc0]).unwrap(); // test %al, %al
+                    fixups.push((
+                        code.len(),
+                        Fixup::Branch {
+                            condition: 0x84, // jz <past the loop>
+                            label: body + 2,
+                        },
+                    ));
+                } else {
+                    fixups.push((code.len(), Fixup::Jump { label: body + 1 })); // jmp <the loop's test>
                 }
+                if options.align_loops {
+                    fixups.push((code.len(), Fixup::Align));
+                }
+                fixups.push((code.len(), Fixup::Label(body)));
+            }
 
-                IRInsn::PutChar => {
-                    // A inlined write(2) syscall, write(file_descriptor, buffer, length)
-                    // Writes character from pointer head to STDOUT.
-                    // file_descriptor = STOUT = 1
-                    // syscall number = 1
-                    // length = 1 (a si ngle character)
-                    code.write_all(&[
-                        // push %rdi
-                        0x57, // mov $1, %rax (syscall number)
-                        0x48, 0xc7, 0xc0, 0x01, 0x0, 0x0, 0x0,
-                        // mov %rdi, %rsi (second argument, buffer pointer)
-                        0x48, 0x89, 0xfe, // mov $1, %rdi (first argument)
-                        0x48, 0xc7, 0xc7, 0x01, 0x0, 0x0, 0x0,
-                        // mov $1, %rdx (third argument)
-                        0x48, 0xc7, 0xc2, 0x01, 0x0, 0x0, 0x0,
-                        // syscall, transfer to kernel
-                        0x0f, 0x05, // pop %rdi
-                        0x5f,
-                    ])
-                    .unwrap();
+            IRInsn::JumpIfNonZero => {
+                cell.load(&mut code);
+                cell.spill(&mut code);
+
+                let body = open_loops.pop().ok_or(())?;
+                let exit = body + 2;
+
+                fixups.push((code.len(), Fixup::Label(body + 1)));
+                code.write_all(&[0x84, 0xc0]).unwrap(); // test %al, %al
+
+                if !options.count_steps {
+                    fixups.push((
+                        code.len(),
+                        Fixup::Branch {
+                            condition: 0x85, // jnz <the loop's body>
+                            label: body,
+                        },
+                    ));
+                    continue;
                 }
+
+                // Going back around the loop takes a step, running out stops here
+                fixups.push((
+                    code.len(),
+                    Fixup::Branch {
+                        condition: 0x84, // jz <past the loop>
+                        label: exit,
+                    },
+                ));
+                code.write_all(&[0x49, 0x83, 0x2a, 0x01]).unwrap(); // subq $1, (%r10)
+                fixups.push((
+                    code.len(),
+                    Fixup::Branch {
+                        condition: 0x89, // jns <the loop's body>
+                        label: body,
+                    },
+                ));
+
+                code.write_all(&[0x49, 0xc7, 0x42, 0x08]).unwrap(); // movq $<idx>, 8(%r10)
+                code.write_all(&(idx as u32).to_le_bytes()).unwrap();
+                fixups.push((code.len(), Fixup::Jump { label: stop })); // jmp <the return>
+                fixups.push((code.len(), Fixup::Label(exit)));
+            }
+
+            IRInsn::GetChar if io == IoMode::Syscall => code.write_all(&GETCHAR_SYSCALL).unwrap(),
+
+            IRInsn::PutChar if io == IoMode::Syscall => code.write_all(&PUTCHAR_SYSCALL).unwrap(),
+
+            // The tape pointer is caller saved, so it has to be kept on the stack
+            // over the call. Pushing it also keeps the stack 16 byte aligned for
+            // the callee, as we are always one return address deep.
+            IRInsn::GetChar => {
+                code.write_all(&[0x57, 0xe8]).unwrap(); // push %rdi; call getchar
+                relocations.push(Relocation {
+                    offset: code.len(),
+                    symbol: "getchar",
+                });
+                code.write_all(&[0x0, 0x0, 0x0, 0x0]).unwrap();
+
+                code.write_all(&[
+                    0x5f, // pop %rdi
+                    0x88, 0x07, // mov %al, (%rdi)
+                ])
+                .unwrap();
+            }
+
+            IRInsn::PutChar => {
+                code.write_all(&[
+                    0x57, // push %rdi
+                    0x0f, 0xb6, 0x3f, // movzbl (%rdi), %edi
+                    0xe8, // call putchar
+                ])
+                .unwrap();
+                relocations.push(Relocation {
+                    offset: code.len(),
+                    symbol: "putchar",
+                });
+                code.write_all(&[0x0, 0x0, 0x0, 0x0]).unwrap();
+
+                code.write_all(&[0x5f]).unwrap(); // pop %rdi
             }
         }
+    }
+
+    // Hand the final tape pointer back to the caller, with the tape up to date
+    ir_starts.push((code.len(), fixups.len()));
+    cell.spill(&mut code);
+    if options.count_steps {
+        fixups.push((code.len(), Fixup::Label(stop)));
+    }
+    code.write_all(&[0x48, 0x89, 0xf8]).unwrap(); // mov %rdi, %rax
+    code.write_all(&[0xc3]).unwrap(); // retq
 
-        code.write_all(&[0xc3]).unwrap(); // retq
+    if !open_loops.is_empty() {
+        return Err(());
+    }
+
+    // An instruction moves along by what went in for the fixups ahead of it. A
+    // relocation is always inside an instruction, past every fixup at the same spot.
+    let (code, inserted) = lay_out(&code, &fixups, labels);
+
+    let ir_offsets = ir_starts
+        .into_iter()
+        .map(|(start, fixups_before)| start + inserted[fixups_before])
+        .collect();
+    for relocation in &mut relocations {
+        relocation.offset += inserted[fixups.partition_point(|&(at, _)| at <= relocation.offset)];
+    }
 
-        jump_pair_positions.into_iter().for_each(|pair| {
-            let fwd_offset = (pair.bwd_jmp - pair.fwd_jmp) as i32;
-            let bwd_offset = -fwd_offset;
+    Ok((code, relocations, ir_offsets))
+}
 
-            code[pair.fwd_jmp + 2..pair.fwd_jmp + 6]
-                .copy_from_slice(bytemuck::bytes_of(&fwd_offset));
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub struct Jit;
 
-            code[pair.bwd_jmp + 2..pair.bwd_jmp + 6]
-                .copy_from_slice(bytemuck::bytes_of(&bwd_offset))
-        });
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+impl Eval for Jit {
+    type Output = JittedFunction;
 
-        // Request executable region of memory from operating system using the well-known
-        // mmap Linux syscall (see man pages for mmap). This is a Nix API wrapper around said syscall,
-        // where anonymous is just a mapping without a file
-        let mut exec_mem: &mut [u8] = unsafe {
-            let ptr = mmap_anonymous(
-                None,
-                NonZero::new_unchecked(code.len()),
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE | ProtFlags::PROT_EXEC,
-                MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS,
-            )
-            .expect("Failed to get executable memory from OS for JIT compilation!")
-            .as_ptr()
-            .cast();
+    // The fast compile tier, see compile_template
+    fn eval_source(src: Program) -> Result<Self::Output, ()> {
+        let ops: Vec<IRInsn> = src.code.iter().map(|&op| op.into()).collect();
+        let key = Key::new(Arch::X86_64, "fast", &ops);
+        let (code, _) = cache::cached(&key, || compile_template_mapped(&src))?;
 
-            slice::from_raw_parts_mut(ptr, code.len())
-        };
+        Ok(JittedFunction::from_code(&code))
+    }
 
-        // Copy our code inside the dynamically sized vector to the executable memory region
-        exec_mem.copy_from_slice(code.as_slice());
+    fn eval_ir(ir: IR) -> Result<Self::Output, ()> {
+        let ir: Vec<IRInsn> = ir.into_iter().collect();
+        let key = Key::new(Arch::X86_64, "optimised", &ir);
+        let (code, _) = cache::cached(&key, || compile_mapped(ir.iter().cloned()))?;
 
-        Ok(JittedFunction(exec_mem.as_mut_ptr().cast(), exec_mem.len()))
+        Ok(JittedFunction::from_code(&code))
     }
 }
@@ -1,11 +1,50 @@
-use super::{
+use crate::brainfuck::{
     ir::{IRInsn, IR},
-    program::Program,
-    Eval,
+    program::{Operator, Program},
+    tape::Tape,
+    Eval, ExecutionResult,
 };
 
-use nix::sys::mman::{mmap_anonymous, munmap, MapFlags, ProtFlags};
-use std::{ffi::c_void, io::Write, num::NonZero, ptr::NonNull, slice};
+use std::{ffi::c_void, io::Write, slice};
+use windows::Win32::System::Memory::{
+    VirtualAlloc, VirtualFree, MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_EXECUTE_READWRITE,
+};
+
+// A inlined read(2) syscall, read(file_descriptor, buffer, length)
+// Most of this is putting the right values in registers before making
+// transfering control to kernel to process read(2)
+// syscall_number = 0
+// file_descriptor = STDIN = 0,
+// buffer = pointer head
+// length = 1 (single character)
+const GETCHAR_SYSCALL: [u8; 28] = [
+    // push %rdi
+    0x57, // mov $0, %rax (syscall number)
+    0x48, 0xc7, 0xc0, 0x0, 0x0, 0x0, 0x0,
+    // mov %rdi, %rsi (second argument, buffer pointer)
+    0x48, 0x89, 0xfe, // mov $0, %rdi (first argument)
+    0x48, 0xc7, 0xc7, 0x0, 0x0, 0x0, 0x0, // mov $1, %rdx (third argument)
+    0x48, 0xc7, 0xc2, 0x01, 0x0, 0x0, 0x0, // syscall, transfer to kernel
+    0x0f, 0x05, // pop %rdi
+    0x5f,
+];
+
+// A inlined write(2) syscall, write(file_descriptor, buffer, length)
+// Writes character from pointer head to STDOUT.
+// file_descriptor = STOUT = 1
+// syscall number = 1
+// length = 1 (a si ngle character)
+const PUTCHAR_SYSCALL: [u8; 28] = [
+    // push %rdi
+    0x57, // mov $1, %rax (syscall number)
+    0x48, 0xc7, 0xc0, 0x01, 0x0, 0x0, 0x0,
+    // mov %rdi, %rsi (second argument, buffer pointer)
+    0x48, 0x89, 0xfe, // mov $1, %rdi (first argument)
+    0x48, 0xc7, 0xc7, 0x01, 0x0, 0x0, 0x0, // mov $1, %rdx (third argument)
+    0x48, 0xc7, 0xc2, 0x01, 0x0, 0x0, 0x0, // syscall, transfer to kernel
+    0x0f, 0x05, // pop %rdi
+    0x5f,
+];
 
 pub struct Jit;
 
@@ -14,28 +53,58 @@ pub struct Jit;
 pub struct JittedFunction(*mut c_void, usize);
 
 impl JittedFunction {
-    pub fn run(&self) {
+    // Request executable region of memory from operating system with VirtualAlloc,
+    // Windows' counterpart to an anonymous mmap(2), reserving and committing
+    // fresh pages in one go
+    fn from_code(code: &[u8]) -> Self {
+        let exec_mem: &mut [u8] = unsafe {
+            let ptr = VirtualAlloc(
+                None,
+                code.len(),
+                MEM_COMMIT | MEM_RESERVE,
+                PAGE_EXECUTE_READWRITE,
+            );
+            assert!(
+                !ptr.is_null(),
+                "Failed to get executable memory from OS for JIT compilation!"
+            );
+
+            slice::from_raw_parts_mut(ptr.cast(), code.len())
+        };
+
+        // Copy our code inside the dynamically sized vector to the executable memory region
+        exec_mem.copy_from_slice(code);
+
+        JittedFunction(exec_mem.as_mut_ptr().cast(), exec_mem.len())
+    }
+
+    pub fn run(&self) -> ExecutionResult {
         // Converting a pointer of bytes to a function pointer in Rust is, as one would expect,
         // very unsafe. This requires an intrinsics function changing arbitrary memory objects
         // called "transmute".
-        let function =
-            unsafe { std::mem::transmute::<*mut c_void, extern "C" fn(*const u8)>(self.0) };
+        let function = unsafe {
+            std::mem::transmute::<*mut c_void, extern "C" fn(*mut u8) -> *mut u8>(self.0)
+        };
+
+        let mut tape = Tape::new();
 
-        let byte_arr = [0u8; 30_000];
+        // Call the function, which hands back where the tape pointer ended up
+        let start = tape.as_mut_ptr();
+        let end = function(start);
+        let pointer = unsafe { end.offset_from(start) as usize };
 
-        // Call the function
-        function(byte_arr.as_ptr())
+        ExecutionResult::finished(tape, pointer)
     }
 }
 
 // Keeping in touch with Rust's stance on RAII driven design, implemented
-// Drop for the JittedFunction object, which call syscall munmap(2) to
-// relinquish the executable region of memory we requested from Linux
+// Drop for the JittedFunction object, which calls VirtualFree to relinquish
+// the executable region of memory we requested from Windows. Releasing takes
+// a size of zero, it always frees the whole allocation.
 impl Drop for JittedFunction {
     fn drop(&mut self) {
         unsafe {
-            munmap(NonNull::new_unchecked(self.0), self.1)
-                .expect("Failed to release memory back to OS!:");
+            VirtualFree(self.0, 0, MEM_RELEASE).expect("Failed to release memory back to OS!:");
         }
     }
 }
@@ -48,8 +117,59 @@ struct JumpPairPos {
 impl Eval for Jit {
     type Output = JittedFunction;
 
+    // The fast compile tier, a single pass template compiler working straight off the
+    // operators. Every operator gets a fixed snippet of machine code, and each loop is
+    // patched the moment its closing bracket is reached, there is no IR to build.
     fn eval_source(src: Program) -> Result<Self::Output, ()> {
-        unimplemented!()
+        let mut code: Vec<u8> = Vec::with_capacity(src.code.len() * 4);
+        let mut open_brackets: Vec<usize> = vec![];
+
+        for op in src {
+            match op {
+                Operator::IncrementPtr => code.write_all(&[0x48, 0xff, 0xc7]).unwrap(), // incq %rdi
+
+                Operator::DecrementPtr => code.write_all(&[0x48, 0xff, 0xcf]).unwrap(), // decq %rdi
+
+                Operator::IncrementValue => code.write_all(&[0xfe, 0x07]).unwrap(), // incb (%rdi)
+
+                Operator::DecrementValue => code.write_all(&[0xfe, 0x0f]).unwrap(), // decb (%rdi)
+
+                Operator::JumpIfZero => {
+                    code.write_all(&[0x80, 0x3f, 0x00]).unwrap(); // cmpb $0, (%rdi)
+
+                    open_brackets.push(code.len());
+                    code.write_all(&[0x0f, 0x84, 0x0, 0x0, 0x0, 0x0]).unwrap(); // jz <patched below>
+                }
+
+                Operator::JumpIfNonZero => {
+                    let fwd_jmp = open_brackets.pop().ok_or(())?;
+                    code.write_all(&[0x80, 0x3f, 0x00]).unwrap(); // cmpb $0, (%rdi)
+
+                    let bwd_jmp = code.len();
+                    code.write_all(&[0x0f, 0x85, 0x0, 0x0, 0x0, 0x0]).unwrap(); // jnz <patched below>
+
+                    // Each jump lands just past the other
+                    let fwd_offset = (bwd_jmp - fwd_jmp) as i32;
+                    let bwd_offset = -fwd_offset;
+
+                    code[fwd_jmp + 2..fwd_jmp + 6].copy_from_slice(&fwd_offset.to_le_bytes());
+                    code[bwd_jmp + 2..bwd_jmp + 6].copy_from_slice(&bwd_offset.to_le_bytes());
+                }
+
+                Operator::GetChar => code.write_all(&GETCHAR_SYSCALL).unwrap(),
+
+                Operator::PutChar => code.write_all(&PUTCHAR_SYSCALL).unwrap(),
+            }
+        }
+
+        if !open_brackets.is_empty() {
+            return Err(());
+        }
+
+        code.write_all(&[0x48, 0x89, 0xf8]).unwrap(); // mov %rdi, %rax
+        code.write_all(&[0xc3]).unwrap(); // retq
+
+        Ok(JittedFunction::from_code(&code))
     }
 
     fn eval_ir(ir: IR) -> Result<Self::Output, ()> {
@@ -127,54 +247,13 @@ impl Eval for Jit {
                     code.write_all(&[0x0f, 0x85, 0x0, 0x0, 0x0, 0x0]).unwrap();
                 }
 
-                IRInsn::GetChar => {
-                    // A inlined read(2) syscall, read(file_descriptor, buffer, length)
-                    // Most of this is putting the right values in registers before making
-                    // transfering control to kernel to process read(2)
-                    // syscall_number = 0
-                    // file_descriptor = STDIN = 0,
-                    // buffer = pointer head
-                    // length = 1 (single character)
-                    code.write_all(&[
-                        // push %rdi
-                        0x57, // mov $0, %rax (syscall number)
-                        0x48, 0xc7, 0xc0, 0x0, 0x0, 0x0, 0x0,
-                        // mov %rdi, %rsi (second argument, buffer pointer)
-                        0x48, 0x89, 0xfe, // mov $0, %rdi (first argument)
-                        0x48, 0xc7, 0xc7, 0x0, 0x0, 0x0, 0x0,
-                        // mov $1, %rdx (third argument)
-                        0x48, 0xc7, 0xc2, 0x01, 0x0, 0x0, 0x0,
-                        // syscall, transfer to kernel
-                        0x0f, 0x05, // pop %rdi
-                        0x5f,
-                    ])
-                    .unwrap();
-                }
+                IRInsn::GetChar => code.write_all(&GETCHAR_SYSCALL).unwrap(),
 
-                IRInsn::PutChar => {
-                    // A inlined write(2) syscall, write(file_descriptor, buffer, length)
-                    // Writes character from pointer head to STDOUT.
-                    // file_descriptor = STOUT = 1
-                    // syscall number = 1
-                    // length = 1 (a si ngle character)
-                    code.write_all(&[
-                        // push %rdi
-                        0x57, // mov $1, %rax (syscall number)
-                        0x48, 0xc7, 0xc0, 0x01, 0x0, 0x0, 0x0,
-                        // mov %rdi, %rsi (second argument, buffer pointer)
-                        0x48, 0x89, 0xfe, // mov $1, %rdi (first argument)
-                        0x48, 0xc7, 0xc7, 0x01, 0x0, 0x0, 0x0,
-                        // mov $1, %rdx (third argument)
-                        0x48, 0xc7, 0xc2, 0x01, 0x0, 0x0, 0x0,
-                        // syscall, transfer to kernel
-                        0x0f, 0x05, // pop %rdi
-                        0x5f,
-                    ])
-                    .unwrap();
-                }
+                IRInsn::PutChar => code.write_all(&PUTCHAR_SYSCALL).unwrap(),
             }
         }
 
+        code.write_all(&[0x48, 0x89, 0xf8]).unwrap(); // mov %rdi, %rax
         code.write_all(&[0xc3]).unwrap(); // retq
 
         jump_pair_positions.into_iter().for_each(|pair| {
@@ -188,26 +267,6 @@ impl Eval for Jit {
                 .copy_from_slice(bytemuck::bytes_of(&bwd_offset))
         });
 
-        // Request executable region of memory from operating system using the well-known
-        // mmap Linux syscall (see man pages for mmap). This is a Nix API wrapper around said syscall,
-        // where anonymous is just a mapping without a file
-        let mut exec_mem: &mut [u8] = unsafe {
-            let ptr = mmap_anonymous(
-                None,
-                NonZero::new_unchecked(code.len()),
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE | ProtFlags::PROT_EXEC,
-                MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS,
-            )
-            .expect("Failed to get executable memory from OS for JIT compilation!")
-            .as_ptr()
-            .cast();
-
-            slice::from_raw_parts_mut(ptr, code.len())
-        };
-
-        // Copy our code inside the dynamically sized vector to the executable memory region
-        exec_mem.copy_from_slice(code.as_slice());
-
-        Ok(JittedFunction(exec_mem.as_mut_ptr().cast(), exec_mem.len()))
+        Ok(JittedFunction::from_code(&code))
     }
 }
@@ -0,0 +1,63 @@
+// LEB128, the variable length integers Wasm and DWARF use everywhere. Seven bits
+// of the value per byte, least significant first, with the top bit set on every
+// byte but the last.
+pub fn write_unsigned(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+
+        out.push(byte | 0x80);
+    }
+}
+
+pub fn write_signed(out: &mut Vec<u8>, mut value: i32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        // Done once the rest is all sign bits, and the sign bit of this byte agrees
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_encodings() {
+        let unsigned = |value| {
+            let mut out = vec![];
+            write_unsigned(&mut out, value);
+            out
+        };
+        let signed = |value| {
+            let mut out = vec![];
+            write_signed(&mut out, value);
+            out
+        };
+
+        assert_eq!(unsigned(0), [0x00]);
+        assert_eq!(unsigned(127), [0x7f]);
+        assert_eq!(unsigned(128), [0x80, 0x01]);
+        assert_eq!(unsigned(624485), [0xe5, 0x8e, 0x26]);
+
+        assert_eq!(signed(0), [0x00]);
+        assert_eq!(signed(63), [0x3f]);
+        assert_eq!(signed(64), [0xc0, 0x00]);
+        assert_eq!(signed(-1), [0x7f]);
+        assert_eq!(signed(-64), [0x40]);
+        assert_eq!(signed(-65), [0xbf, 0x7f]);
+        assert_eq!(signed(-123456), [0xc0, 0xbb, 0x78]);
+    }
+}
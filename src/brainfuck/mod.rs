@@ -1,7 +1,37 @@
+pub mod elf;
+pub mod emit;
 pub mod interpreter;
 pub mod ir;
 pub mod jit;
+pub mod leb128;
 pub mod program;
+pub mod tape;
+// Hands hot loops to the Jit, so only where there is one
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "riscv64",
+        target_arch = "aarch64"
+    )
+))]
+pub mod tiered;
+
+#[cfg(unix)]
+use nix::sys::{
+    pthread,
+    signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal},
+};
+use std::{
+    ffi::c_int,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Once,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tape::Tape;
 
 pub trait Eval {
     type Output;
@@ -10,3 +40,151 @@ pub trait Eval {
 
     fn eval_ir(ir: ir::IR) -> Result<Self::Output, ()>;
 }
+
+// The state a program leaves the machine in once it has run, whichever way it was run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionResult {
+    pub tape: Tape,
+    // The cell the program finished on
+    pub pointer: usize,
+    // What the process should exit with, zero for a program that ran to completion
+    pub exit_status: i32,
+}
+
+impl ExecutionResult {
+    pub fn finished(tape: Tape, pointer: usize) -> Self {
+        ExecutionResult {
+            tape,
+            pointer,
+            exit_status: 0,
+        }
+    }
+}
+
+// How far a program may get before it is stopped, for programs that might never
+// finish. A step is a jump back to the start of a loop, which is where a program
+// that runs forever spends its time, and which compiled code can count cheaply.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Budget {
+    pub max_steps: Option<u64>,
+    pub timeout: Option<Duration>,
+}
+
+impl Budget {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Budget::default()
+    }
+}
+
+// Which part of a budget ran out
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Steps,
+    Time,
+}
+
+// A program stopped for going over its budget, and how far it got
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitExceeded {
+    pub limit: Limit,
+    // The closing bracket it was about to jump back from, an index into the
+    // operators or IR instructions that were running. The interpreter also stops
+    // on a read that only comes back once time is up.
+    pub position: usize,
+    pub tape: Tape,
+    pub pointer: usize,
+}
+
+// A budget being spent, step by step, for the interpreters
+pub struct Meter {
+    steps_left: Option<u64>,
+    deadline: Option<Instant>,
+    steps_taken: u64,
+}
+
+impl Meter {
+    pub fn new(budget: Budget) -> Self {
+        Meter {
+            steps_left: budget.max_steps,
+            deadline: budget.timeout.map(|timeout| Instant::now() + timeout),
+            steps_taken: 0,
+        }
+    }
+
+    // Take a step, returning the limit that goes over, if any. Reading the clock
+    // every step would slow tight loops down, every thousand or so is plenty.
+    pub fn step(&mut self) -> Option<Limit> {
+        if let Some(steps_left) = &mut self.steps_left {
+            if *steps_left == 0 {
+                return Some(Limit::Steps);
+            }
+            *steps_left -= 1;
+        }
+
+        self.steps_taken += 1;
+        if self.steps_taken.is_multiple_of(1024) && self.out_of_time() {
+            return Some(Limit::Time);
+        }
+
+        None
+    }
+
+    pub fn out_of_time(&self) -> bool {
+        self.deadline.is_some_and(|end| Instant::now() >= end)
+    }
+}
+
+// Run `run` on this thread, with a watchdog thread calling `expired` once `timeout`
+// is up, and every millisecond after, until `run` returns. Budgets are only checked
+// between steps, so each time the watchdog also interrupts whatever system call
+// `run` is blocked in, a read that never gets any input in particular, with a
+// signal. The read comes back with nothing, as if at the end of the input.
+pub fn watched<T>(
+    timeout: Option<Duration>,
+    expired: impl Fn() + Sync,
+    run: impl FnOnce() -> T,
+) -> T {
+    let Some(timeout) = timeout else {
+        return run();
+    };
+
+    // A handler that does nothing, installed without SA_RESTART, is what makes the
+    // system call fail with EINTR instead of carrying on. SIGURG is ignored unless
+    // handled, so nothing else is expecting it, Go's runtime uses it the same way.
+    #[cfg(unix)]
+    let runner = {
+        static INTERRUPTS: Once = Once::new();
+        extern "C" fn interrupt(_: c_int) {}
+
+        INTERRUPTS.call_once(|| {
+            let action = SigAction::new(
+                SigHandler::Handler(interrupt),
+                SaFlags::empty(),
+                SigSet::empty(),
+            );
+            unsafe { signal::sigaction(Signal::SIGURG, &action) }.unwrap();
+        });
+
+        pthread::pthread_self()
+    };
+
+    thread::scope(|scope| {
+        let (finished, done) = mpsc::channel::<()>();
+        let expired = &expired;
+
+        scope.spawn(move || {
+            let mut wait = timeout;
+
+            while done.recv_timeout(wait) == Err(RecvTimeoutError::Timeout) {
+                expired();
+                #[cfg(unix)]
+                let _ = pthread::pthread_kill(runner, Signal::SIGURG);
+                wait = Duration::from_millis(1);
+            }
+        });
+
+        let result = run();
+        drop(finished);
+        result
+    })
+}
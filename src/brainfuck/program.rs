@@ -34,6 +34,8 @@ impl TryFrom<u8> for Operator {
 #[derive(Debug)]
 pub struct Program {
     pub code: Box<[Operator]>,
+    // The source line, counting from one, every operator was found on
+    pub lines: Box<[u32]>,
     pub fwd_jump_table: HashMap<usize, usize>,
     pub bwd_jump_table: HashMap<usize, usize>,
 }
@@ -42,11 +44,19 @@ impl Program {
     pub fn new(source: &str) -> Self {
         // Define the code as all the valid operators in the file.
         // Anything that is not '>', '<', '+' and so on is a comment
-        let operators: Box<[Operator]> = source
+        let mut line = 1;
+        let (operators, lines): (Vec<Operator>, Vec<u32>) = source
             .as_bytes()
             .iter()
-            .filter_map(|&byte| byte.try_into().ok())
-            .collect();
+            .filter_map(|&byte| {
+                if byte == b'\n' {
+                    line += 1;
+                }
+
+                Operator::try_from(byte).ok().map(|op| (op, line))
+            })
+            .unzip();
+        let operators = operators.into_boxed_slice();
 
         // Define jump tables as a pair of hashmaps, one being the inverse of the other.
         // One for forward jumps jumping from '[' to ']', the other vice versa
@@ -75,6 +85,7 @@ impl Program {
 
         Self {
             code: operators,
+            lines: lines.into_boxed_slice(),
             fwd_jump_table,
             bwd_jump_table,
         }
@@ -0,0 +1,35 @@
+use std::ops::{Deref, DerefMut};
+
+// How many cells every Brainfuck program gets
+pub const TAPE_LEN: usize = 30_000;
+
+// The memory a Brainfuck program works on, a row of byte cells all starting at zero.
+// Derefs to the cells themselves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tape(Box<[u8]>);
+
+impl Tape {
+    pub fn new() -> Self {
+        Tape(vec![0; TAPE_LEN].into_boxed_slice())
+    }
+}
+
+impl Default for Tape {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for Tape {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for Tape {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
@@ -0,0 +1,142 @@
+use super::{
+    interpreter::{IrMachine, Step},
+    ir::IR,
+    jit::{Jit, JittedFunction},
+    program::Program,
+    tape::Tape,
+    Eval, ExecutionResult,
+};
+use std::{
+    collections::HashMap,
+    ffi::{c_int, c_void},
+};
+
+extern "C" {
+    fn read(fd: c_int, buf: *mut c_void, count: usize) -> isize;
+    fn write(fd: c_int, buf: *const c_void, count: usize) -> isize;
+}
+
+// How many times a loop has to jump back to its start before it
+// is considered hot enough to be worth compiling
+const HOT_LOOP_THRESHOLD: u32 = 1_000;
+
+// Tiered execution, start interpreting the IR straight away, and compile loops
+// with the JIT once they've proven to be hot. Programs that finish quickly never
+// pay for compilation, programs that crunch numbers spend most of their time in
+// compiled code.
+pub struct Tiered;
+
+impl Eval for Tiered {
+    type Output = ExecutionResult;
+
+    fn eval_source(src: Program) -> Result<Self::Output, ()> {
+        Self::eval_ir(src.into())
+    }
+
+    fn eval_ir(ir: IR) -> Result<Self::Output, ()> {
+        Self::run(ir).map(|(result, _)| result)
+    }
+}
+
+impl Tiered {
+    // Run the IR, handing back the state it left the tape in along with where
+    // every loop that got compiled opens, in order
+    fn run(ir: IR) -> Result<(ExecutionResult, Vec<usize>), ()> {
+        let mut machine = IrMachine::new(ir)?;
+
+        // Back-edges taken per loop, and the compiled code of hot loops,
+        // both keyed by the position of the loop's opening bracket
+        let mut back_edges: HashMap<usize, u32> = HashMap::new();
+        let mut compiled: HashMap<usize, JittedFunction> = HashMap::new();
+
+        while !machine.is_finished() {
+            // The JIT does its I/O with raw read(2)/write(2) calls, so do the same here.
+            // Buffered stdio would let the two tiers' output arrive out of order.
+            let step = machine.step(
+                |cell| unsafe {
+                    read(0, (cell as *mut u8).cast(), 1);
+                },
+                |byte| unsafe {
+                    write(1, (&byte as *const u8).cast(), 1);
+                },
+            );
+
+            let start = match step {
+                Step::Next => continue,
+
+                Step::EnteredLoop(start) => start,
+
+                Step::LoopedBack(start) => {
+                    let count = back_edges.entry(start).or_default();
+                    *count += 1;
+
+                    if *count == HOT_LOOP_THRESHOLD {
+                        // Compile the loop as a function of its own, brackets and all
+                        compiled.insert(start, Jit::eval_ir(machine.loop_ir(start))?);
+                    }
+
+                    start
+                }
+            };
+
+            // Run the rest of the loop natively. The compiled code starts by testing
+            // the current cell, which isn't zero here, so it carries on with the body
+            // and hands back the tape pointer once the cell is zeroed.
+            if let Some(function) = compiled.get(&start) {
+                machine.mem_ptr = function.run_from(&mut machine.mem, machine.mem_ptr);
+                machine.skip_loop(start);
+            }
+        }
+
+        let mut compiled: Vec<usize> = compiled.into_keys().collect();
+        compiled.sort();
+
+        Ok((machine.into_result(), compiled))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck::interpreter::Interpreter;
+
+    // Six times round a cold outer loop, each time round a hot loop adding 250 to
+    // the cell next door, with a cold loop inside that never runs
+    fn nested() -> String {
+        format!("++++++[>{}[->+>[-]<<]<-]", "+".repeat(250))
+    }
+
+    #[test]
+    fn hot_loops_get_compiled() {
+        let (result, compiled) = Tiered::run(Program::new(&nested()).into()).unwrap();
+
+        // The hot loop goes over the threshold on its fifth run, 249 back-edges a time
+        assert_eq!(compiled, [4]);
+        assert_eq!(result.pointer, 0);
+        assert_eq!(&result.tape[..3], [0, 0, (6 * 250 % 256) as u8]);
+    }
+
+    #[test]
+    fn leaves_the_same_state_as_the_interpreter() {
+        let sources = [
+            (nested(), vec![4]),
+            (
+                "++++++[>++++++<-]>>>+++<<[->+>+<<]>[-<+>]<--".into(),
+                vec![],
+            ),
+            // A hot loop deep inside cold ones, the innermost entered 256 times
+            (
+                "++++[>++++++++[>++++++++[>++++++++[>+>+++<<-]<-]<-]<-]".into(),
+                vec![10],
+            ),
+        ];
+
+        for (source, hot) in sources {
+            let expected = Interpreter::eval_ir(Program::new(&source).into()).unwrap();
+            let (result, compiled) = Tiered::run(Program::new(&source).into()).unwrap();
+
+            assert_eq!(compiled, hot);
+            assert_eq!(result, expected);
+        }
+    }
+}
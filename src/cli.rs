@@ -1,8 +1,13 @@
 use clap::{
     builder::{OsStr, PossibleValue},
-    Parser, ValueEnum,
+    value_parser, Parser, ValueEnum,
+};
+use std::{path::PathBuf, time::Duration};
+
+use crate::brainfuck::{
+    emit::rust::{Cell, Eof},
+    jit::Arch,
 };
-use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -10,15 +15,145 @@ pub struct Cli {
     /// A positional file containing the Brainfuck code you would like to run
     pub file: Option<PathBuf>,
 
-    /// Specifies the mode of execution, Interpret/Just-In-Time Compilation
+    /// Specifies the mode of execution, Interpret/Just-In-Time Compilation/Auto/Build
     #[arg(short, long, value_enum, default_value = Mode::Interpret)]
     pub mode: Mode,
+
+    /// Specifies the JIT compilation tier, trading compile time for faster code
+    #[arg(short, long, value_enum, default_value = Tier::Optimised)]
+    pub tier: Tier,
+
+    /// Print the code JIT mode compiles to stderr before running it, disassembled, with the IR instruction each part came from
+    #[arg(long)]
+    pub dump_jit: bool,
+
+    /// Describe the code JIT mode compiles in /tmp/perf-<pid>.map, so perf can attribute samples to Brainfuck loops
+    #[arg(long)]
+    pub perf_map: bool,
+
+    /// Tell GDB about the code JIT mode compiles, so breakpoints can be set on lines of the Brainfuck source
+    #[arg(long)]
+    pub gdb: bool,
+
+    /// Keep the code JIT mode compiles on disk, under $XDG_CACHE_HOME/brainrust, and reuse it whenever the same program is run again
+    #[arg(long)]
+    pub cache: bool,
+
+    /// Start every loop body on a 16 byte boundary in x86-64 code, padding with NOPs, for JIT mode's optimised tier and builds
+    #[arg(long)]
+    pub align_loops: bool,
+
+    /// Run the program in a child process limited in CPU time and memory, only allowed to read, write and exit, for interpret and JIT modes
+    #[cfg(all(
+        target_os = "linux",
+        any(
+            target_arch = "x86_64",
+            target_arch = "riscv64",
+            target_arch = "aarch64"
+        )
+    ))]
+    #[arg(long)]
+    pub sandbox: bool,
+
+    /// CPU time a --sandbox program gets, killed once it's used up
+    #[cfg(all(
+        target_os = "linux",
+        any(
+            target_arch = "x86_64",
+            target_arch = "riscv64",
+            target_arch = "aarch64"
+        )
+    ))]
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 10,
+        value_parser = value_parser!(u64).range(1..u64::MAX),
+        requires = "sandbox"
+    )]
+    pub cpu_limit: u64,
+
+    /// Address space a --sandbox program may grow to. The tape and compiled code are mapped before it applies, and the sandbox refuses any new mapping, so in practice it only limits how far the stack grows
+    #[cfg(all(
+        target_os = "linux",
+        any(
+            target_arch = "x86_64",
+            target_arch = "riscv64",
+            target_arch = "aarch64"
+        )
+    ))]
+    #[arg(
+        long,
+        value_name = "MIB",
+        default_value_t = 256,
+        value_parser = value_parser!(u64).range(1..=u64::MAX >> 20),
+        requires = "sandbox"
+    )]
+    pub memory_limit: u64,
+
+    /// Stop the program once it has jumped back to the start of a loop this many times, for interpret mode and JIT mode's optimised tier
+    #[arg(long, value_name = "STEPS")]
+    pub max_steps: Option<u64>,
+
+    /// Stop the program once it has run for this many seconds, for interpret mode and JIT mode's optimised tier
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    pub timeout: Option<Duration>,
+
+    /// What build mode produces, implies build mode when given, defaults to an executable
+    #[arg(short, long, value_enum)]
+    pub emit: Option<Emit>,
+
+    /// The type of each cell in the Rust --emit rust generates
+    #[arg(long, value_enum, default_value = Cell::U8)]
+    pub cell: Cell,
+
+    /// What reading past the end of input does to the cell, in the Rust --emit rust generates
+    #[arg(long, value_enum, default_value = Eof::Unchanged)]
+    pub eof: Eof,
+
+    /// Which backend's machine code asm is emitted for, defaults to the host's
+    #[arg(short, long, value_enum)]
+    pub arch: Option<Arch>,
+
+    /// Where to write the output of build mode, defaults to the file name with an extension fitting what is emitted
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+impl Cli {
+    // Whether to run in the sandbox, never where there isn't one
+    pub fn sandboxed(&self) -> bool {
+        cfg_if::cfg_if! {
+            if #[cfg(all(
+                target_os = "linux",
+                any(
+                    target_arch = "x86_64",
+                    target_arch = "riscv64",
+                    target_arch = "aarch64"
+                )
+            ))] {
+                self.sandbox
+            } else {
+                false
+            }
+        }
+    }
+}
+
+// A number of seconds, fractions of one included
+fn parse_seconds(arg: &str) -> Result<Duration, String> {
+    arg.parse()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("`{arg}` isn't a number of seconds"))
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Mode {
     Jit,
     Interpret,
+    Auto,
+    Build,
 }
 
 impl From<Mode> for OsStr {
@@ -26,13 +161,15 @@ impl From<Mode> for OsStr {
         match mode {
             Mode::Interpret => "interpret".into(),
             Mode::Jit => "jit".into(),
+            Mode::Auto => "auto".into(),
+            Mode::Build => "build".into(),
         }
     }
 }
 
 impl ValueEnum for Mode {
     fn value_variants<'a>() -> &'a [Self] {
-        &[Mode::Interpret, Mode::Jit]
+        &[Mode::Interpret, Mode::Jit, Mode::Auto, Mode::Build]
     }
 
     fn to_possible_value(&self) -> Option<PossibleValue> {
@@ -41,6 +178,180 @@ impl ValueEnum for Mode {
             Mode::Jit => {
                 PossibleValue::new("jit").help("Execute via Jit compilation and execution")
             }
+            Mode::Auto => PossibleValue::new("auto")
+                .help("Start interpreting, Jit compile loops once they get hot"),
+            Mode::Build => PossibleValue::new("build")
+                .help("Compile ahead of time, to whatever --emit asks for"),
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Tier {
+    Fast,
+    Optimised,
+}
+
+impl From<Tier> for OsStr {
+    fn from(tier: Tier) -> OsStr {
+        match tier {
+            Tier::Fast => "fast".into(),
+            Tier::Optimised => "optimised".into(),
+        }
+    }
+}
+
+impl ValueEnum for Tier {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Tier::Fast, Tier::Optimised]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Tier::Fast => PossibleValue::new("fast")
+                .help("Compile operators one by one, for short lived programs"),
+            Tier::Optimised => PossibleValue::new("optimised")
+                .help("Compile optimised IR, for long running programs"),
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Emit {
+    Exe,
+    Obj,
+    C,
+    Rust,
+    Wasm,
+    Wat,
+    Llvm,
+    Asm,
+}
+
+impl From<Emit> for OsStr {
+    fn from(emit: Emit) -> OsStr {
+        match emit {
+            Emit::Exe => "exe".into(),
+            Emit::Obj => "obj".into(),
+            Emit::C => "c".into(),
+            Emit::Rust => "rust".into(),
+            Emit::Wasm => "wasm".into(),
+            Emit::Wat => "wat".into(),
+            Emit::Llvm => "llvm".into(),
+            Emit::Asm => "asm".into(),
+        }
+    }
+}
+
+impl ValueEnum for Emit {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            Emit::Exe,
+            Emit::Obj,
+            Emit::C,
+            Emit::Rust,
+            Emit::Wasm,
+            Emit::Wat,
+            Emit::Llvm,
+            Emit::Asm,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Emit::Exe => {
+                PossibleValue::new("exe").help("A standalone x86-64 Linux executable")
+            }
+            Emit::Obj => PossibleValue::new("obj").help(
+                "An x86-64 ELF object exporting `uint8_t *bf_main(uint8_t *tape)`, to link with a C runtime",
+            ),
+            Emit::C => PossibleValue::new("c").help("A self-contained C program"),
+            Emit::Rust => PossibleValue::new("rust").help(
+                "A Rust module exposing `pub fn run(tape: &mut [u8], input: &mut impl Read, output: &mut impl Write) -> io::Result<()>`, see --cell and --eof",
+            ),
+            Emit::Wasm => PossibleValue::new("wasm").help(
+                "A binary Wasm module exporting `run` and its memory, importing `getchar` and `putchar` from `env`",
+            ),
+            Emit::Wat => PossibleValue::new("wat").help("The same Wasm module, in the text format"),
+            Emit::Llvm => PossibleValue::new("llvm").help(
+                "Textual LLVM IR defining `bf_main` like obj does, and a main calling it. Uses opaque pointers, so needs LLVM 15 or later, or -opaque-pointers on LLVM 14",
+            ),
+            Emit::Asm => PossibleValue::new("asm")
+                .help("GNU as source of the exact code a JIT backend generates, see --arch"),
+        })
+    }
+}
+
+impl From<Cell> for OsStr {
+    fn from(cell: Cell) -> OsStr {
+        match cell {
+            Cell::U8 => "u8".into(),
+            Cell::U16 => "u16".into(),
+            Cell::U32 => "u32".into(),
+        }
+    }
+}
+
+impl ValueEnum for Cell {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Cell::U8, Cell::U16, Cell::U32]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Cell::U8 => PossibleValue::new("u8"),
+            Cell::U16 => PossibleValue::new("u16"),
+            Cell::U32 => PossibleValue::new("u32"),
+        })
+    }
+}
+
+impl From<Eof> for OsStr {
+    fn from(eof: Eof) -> OsStr {
+        match eof {
+            Eof::Unchanged => "unchanged".into(),
+            Eof::Zero => "zero".into(),
+            Eof::Max => "max".into(),
+        }
+    }
+}
+
+impl ValueEnum for Eof {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Eof::Unchanged, Eof::Zero, Eof::Max]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Eof::Unchanged => PossibleValue::new("unchanged")
+                .help("Leave the cell as it was, like JIT and auto modes"),
+            Eof::Zero => PossibleValue::new("zero").help("Store zero"),
+            Eof::Max => PossibleValue::new("max")
+                .help("Store the cell's largest value, like interpret mode does for bytes"),
+        })
+    }
+}
+
+impl From<Arch> for OsStr {
+    fn from(arch: Arch) -> OsStr {
+        match arch {
+            Arch::X86_64 => "x86-64".into(),
+            Arch::Riscv64 => "riscv64".into(),
+            Arch::Aarch64 => "aarch64".into(),
+        }
+    }
+}
+
+impl ValueEnum for Arch {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Arch::X86_64, Arch::Riscv64, Arch::Aarch64]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Arch::X86_64 => PossibleValue::new("x86-64"),
+            Arch::Riscv64 => PossibleValue::new("riscv64"),
+            Arch::Aarch64 => PossibleValue::new("aarch64"),
         })
     }
 }
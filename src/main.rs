@@ -1,28 +1,470 @@
 mod brainfuck;
 mod cli;
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "riscv64",
+        target_arch = "aarch64"
+    )
+))]
+mod sandbox;
 
-use brainfuck::{interpreter::Interpreter, ir::IR, jit::Jit, program::Program, Eval};
+use brainfuck::{
+    elf, emit,
+    interpreter::Interpreter,
+    ir::{self, IRInsn, IR},
+    jit,
+    jit::CodeMap,
+    program::Program,
+    tape::Tape,
+    Budget, Eval, ExecutionResult, Limit, LimitExceeded,
+};
 use clap::Parser;
-use cli::{Cli, Mode};
-use std::{env, ffi::c_void, fs, process};
+use cli::{Cli, Emit, Mode, Tier};
+use jit::{
+    aarch64_linux,
+    cache::{self, Key},
+    riscv64_linux,
+    x86_64_linux::{self, IoMode, Options},
+    Arch,
+};
+use std::{env, ffi::c_void, fmt, fs, path::Path, process, time::Duration};
+
+fn write_output(output: &Path, contents: &[u8]) {
+    if fs::write(output, contents).is_err() {
+        eprintln!("Failed to write output {}", output.display());
+        process::exit(-1)
+    }
+}
+
+// Report a program stopped for going over its budget with `report`, handing back
+// the state it was left in as the result of the run
+fn stopped(exceeded: LimitExceeded, lines: &[u32], report: fn(fmt::Arguments)) -> ExecutionResult {
+    let limit = match exceeded.limit {
+        Limit::Steps => "Step",
+        Limit::Time => "Time",
+    };
+    report(format_args!(
+        "{limit} limit exceeded at line {}, on cell {}",
+        lines[exceeded.position], exceeded.pointer
+    ));
+
+    ExecutionResult {
+        tape: exceeded.tape,
+        pointer: exceeded.pointer,
+        exit_status: -1,
+    }
+}
+
+// Print a line to stderr, outside the sandbox
+fn eprint_line(message: fmt::Arguments) {
+    eprintln!("{message}");
+}
+
+// JIT and auto modes run code compiled for the host, which takes a Linux host
+// with a code generator. So does the sandbox, its filter only knows the system
+// calls of those architectures.
+cfg_if::cfg_if! {
+    if #[cfg(all(
+        target_os = "linux",
+        any(
+            target_arch = "x86_64",
+            target_arch = "riscv64",
+            target_arch = "aarch64"
+        )
+    ))] {
+        use brainfuck::{
+            jit::{Io, Jit, JittedFunction},
+            tiered::Tiered,
+        };
+        use sandbox::{Limits, Outcome, RawStdio};
+
+    // Compile for the host the same way --mode jit does, keeping track of
+    // where every part of the code came from
+    fn compile_mapped(program: Program, tier: Tier, options: Options) -> (JittedFunction, CodeMap) {
+        let arch = Arch::host().unwrap();
+
+        let (ir, lines, (code, ir_offsets)) = match tier {
+            Tier::Fast => {
+                let ops: Vec<IRInsn> = program.code.iter().map(|&op| op.into()).collect();
+                let compiled = cache::cached(&Key::new(arch, "fast", &ops), || {
+                    arch.compile_template_mapped(&program)
+                });
+
+                (ops, program.lines.to_vec(), compiled.unwrap())
+            }
+
+            Tier::Optimised => {
+                let lines = ir::source_lines(&program);
+                let ir: Vec<IRInsn> = IR::from(program).into_iter().collect();
+
+                let mut tier = String::from("optimised");
+                if options.align_loops {
+                    tier.push_str(" aligned");
+                }
+                if options.count_steps {
+                    tier.push_str(" counted");
+                }
+
+                let count_steps = options.count_steps;
+                let compiled = cache::cached(&Key::new(arch, &tier, &ir), || match arch {
+                    Arch::X86_64 => x86_64_linux::compile_with(ir.iter().cloned(), options)
+                        .map(|(code, _, ir_offsets)| (code, ir_offsets)),
+                    Arch::Riscv64 => riscv64_linux::compile_with(
+                        ir.iter().cloned(),
+                        riscv64_linux::Options { count_steps },
+                    ),
+                    Arch::Aarch64 => aarch64_linux::compile_with(
+                        ir.iter().cloned(),
+                        aarch64_linux::Options { count_steps },
+                    ),
+                })
+                .unwrap();
+
+                (ir, lines, compiled)
+            }
+        };
+
+        let map = CodeMap {
+            ir,
+            ir_offsets,
+            lines,
+        };
+
+        (JittedFunction::from_code(&code), map)
+    }
+
+    // Run compiled code on a fresh tape, in the sandbox if asked to, returning what
+    // to exit with. The code counts its steps whenever there's a budget. The tape is
+    // allocated up front, the sandbox couldn't allocate it, and the sandbox keeps
+    // time itself, it couldn't start a watchdog thread either.
+    fn run_jitted(
+        compiled_fn: &JittedFunction,
+        lines: &[u32],
+        budget: Budget,
+        sandbox: Option<Limits>,
+    ) -> i32 {
+        let mut tape = Tape::new();
+        let counted = !budget.is_unlimited();
+        let report = match sandbox {
+            Some(_) => sandbox::report,
+            None => eprint_line,
+        };
+        let run = move |budget: Budget| {
+            if !counted {
+                let pointer = compiled_fn.run_with(&mut tape, &mut Io::stdio());
+                ExecutionResult::finished(tape, pointer)
+            } else {
+                compiled_fn
+                    .run_within(tape, &mut Io::stdio(), budget)
+                    .unwrap_or_else(|exceeded| stopped(exceeded, lines, report))
+            }
+        };
+
+        if let Some(limits) = sandbox {
+            run_sandboxed(limits, || {
+                run(Budget {
+                    timeout: None,
+                    ..budget
+                })
+            })
+        } else {
+            run(budget).exit_status
+        }
+    }
+
+        // The limits to run in, if the sandbox was asked for. It keeps time itself.
+        fn sandbox_limits(cli: &Cli, budget: Budget) -> Option<Limits> {
+            cli.sandbox.then_some(Limits {
+                cpu_seconds: cli.cpu_limit,
+                memory_bytes: cli
+                    .memory_limit
+                    .checked_mul(1 << 20)
+                    .expect("--memory-limit is range checked"),
+                timeout: budget.timeout,
+            })
+        }
+
+        // Run a program in the sandbox, reporting anything but a normal exit, and
+        // returning what to exit with
+        fn run_sandboxed(limits: Limits, program: impl FnOnce() -> ExecutionResult) -> i32 {
+            match sandbox::run(limits, program) {
+                Ok(Outcome::Exited(status)) => return status,
+                Ok(Outcome::TimedOut) => eprintln!("Sandboxed program ran out of time"),
+                Ok(Outcome::Blocked) => {
+                    eprintln!("Sandboxed program made a system call other than read, write or exit")
+                }
+                Ok(Outcome::Faulted(signal)) => {
+                    eprintln!("Sandboxed program was killed by {signal}")
+                }
+                Err(_) => eprintln!("Failed to start the sandbox"),
+            }
+
+            -1
+        }
+
+        // Interpret a program in the sandbox, returning what to exit with. Its I/O
+        // buffer is allocated up front, like the tape.
+        fn interpret_sandboxed(program: &Program, budget: Budget, limits: Limits) -> i32 {
+            let tape = Tape::new();
+            let stdio = RawStdio::new();
+            let budget = Budget {
+                timeout: None,
+                ..budget
+            };
+
+            run_sandboxed(limits, || {
+                let result = Interpreter::run_on(
+                    program,
+                    tape,
+                    budget,
+                    || stdio.getchar(),
+                    |byte| stdio.putchar(byte),
+                );
+                stdio.flush();
+                result.unwrap_or_else(|exceeded| stopped(exceeded, &program.lines, sandbox::report))
+            })
+        }
+
+        // Run in JIT mode, returning what to exit with. Anything that needs to know
+        // where the code came from compiles it the long way round.
+        fn run_jit(cli: &Cli, program: Program, filepath: &Path, budget: Budget) -> i32 {
+            let mapped = cli.dump_jit || cli.perf_map || cli.gdb || cli.align_loops;
+            if !mapped && budget.is_unlimited() {
+                let compiled_fn = match cli.tier {
+                    Tier::Fast => Jit::eval_source(program).unwrap(),
+                    Tier::Optimised => Jit::eval_ir(program.into()).unwrap(),
+                };
+                return run_jitted(&compiled_fn, &[], budget, sandbox_limits(cli, budget));
+            }
+
+            let options = Options {
+                align_loops: cli.align_loops,
+                count_steps: !budget.is_unlimited(),
+                ..Options::default()
+            };
+            let (compiled_fn, map) = compile_mapped(program, cli.tier, options);
+            let code = compiled_fn.code();
+            let base = code.as_ptr() as usize;
+
+            let arch = Arch::host().unwrap();
+
+            if cli.dump_jit {
+                let dump = jit::disasm::dump(arch, code, base, &map.ir, &map.ir_offsets);
+                eprint!("{dump}");
+            }
+
+            if cli.perf_map {
+                let file_name = filepath.file_name().unwrap().to_string_lossy();
+
+                if jit::perf::write_map(base, &map, code.len(), &file_name).is_err() {
+                    eprintln!("Failed to write perf map");
+                }
+            }
+
+            // Declared after the code, so GDB forgets about it before the code is unmapped
+            let _registration = cli.gdb.then(|| {
+                let source = filepath.to_string_lossy();
+                let comp_dir = env::current_dir().unwrap_or_default();
+                let symfile = jit::gdb::symbol_file(
+                    arch,
+                    base as u64,
+                    code.len() as u64,
+                    &map,
+                    &source,
+                    &comp_dir.to_string_lossy(),
+                );
+
+                jit::gdb::register(symfile)
+            });
+
+            run_jitted(&compiled_fn, &map.lines, budget, sandbox_limits(cli, budget))
+        }
+
+        // Run in auto mode, returning what to exit with
+        fn run_auto(program: Program) -> i32 {
+            Tiered::eval_source(program).unwrap().exit_status
+        }
+    } else {
+        fn run_jit(cli: &Cli, program: Program, filepath: &Path, budget: Budget) -> i32 {
+            eprintln!("JIT mode needs Linux on x86-64, RISC-V or AArch64");
+            process::exit(-1)
+        }
+
+        fn run_auto(program: Program) -> i32 {
+            eprintln!("Auto mode needs Linux on x86-64, RISC-V or AArch64");
+            process::exit(-1)
+        }
+    }
+}
 
 fn main() {
     let cli = Cli::parse();
 
+    if cli.cache {
+        let enabled = cache::default_dir().map(cache::enable);
+
+        if !matches!(enabled, Some(Ok(()))) {
+            eprintln!("Failed to open the JIT cache, compiling everything");
+        }
+    }
+
+    // What the program that ran asks to exit with, builds exit early if they fail
+    let mut exit_status = 0;
+
     if let Some(ref filepath) = cli.file {
         if let Ok(source_code) = fs::read_to_string(filepath) {
             let program = Program::new(&source_code);
 
-            match cli.mode {
+            // Asking for something to be emitted only makes sense when building
+            let mode = if cli.emit.is_some() {
+                Mode::Build
+            } else {
+                cli.mode
+            };
+
+            let budget = Budget {
+                max_steps: cli.max_steps,
+                timeout: cli.timeout,
+            };
+
+            // Auto mode compiles as it goes, and builds don't run anything
+            if (cli.sandboxed() || !budget.is_unlimited())
+                && !matches!(mode, Mode::Interpret | Mode::Jit)
+            {
+                eprintln!(
+                    "--sandbox, --max-steps and --timeout only work in interpret and JIT modes"
+                );
+                process::exit(-1)
+            }
+
+            // Template code has nowhere to count its steps
+            if !budget.is_unlimited() && mode == Mode::Jit && cli.tier == Tier::Fast {
+                eprintln!("--max-steps and --timeout only work in JIT mode with --tier optimised");
+                process::exit(-1)
+            }
+
+            match mode {
+                #[cfg(all(
+                    target_os = "linux",
+                    any(
+                        target_arch = "x86_64",
+                        target_arch = "riscv64",
+                        target_arch = "aarch64"
+                    )
+                ))]
+                Mode::Interpret if cli.sandbox => {
+                    let limits = sandbox_limits(&cli, budget).unwrap();
+                    exit_status = interpret_sandboxed(&program, budget, limits);
+                }
+
                 Mode::Interpret => {
-                    Interpreter::eval_source(program).unwrap();
+                    exit_status = Interpreter::run_within(&program, budget)
+                        .unwrap_or_else(|exceeded| stopped(exceeded, &program.lines, eprint_line))
+                        .exit_status;
                 }
 
                 Mode::Jit => {
-                    let ir: IR = program.into();
-                    let compiled_fn = Jit::eval_ir(ir).unwrap();
-                    compiled_fn.run();
+                    exit_status = run_jit(&cli, program, filepath, budget);
+                }
+
+                Mode::Auto => {
+                    exit_status = run_auto(program);
                 }
+
+                Mode::Build => match cli.emit.unwrap_or(Emit::Exe) {
+                    Emit::Exe => {
+                        let output = cli.output.unwrap_or_else(|| filepath.with_extension(""));
+                        let options = Options {
+                            align_loops: cli.align_loops,
+                            ..Options::default()
+                        };
+                        let (code, _, _) =
+                            x86_64_linux::compile_with(IR::from(program), options).unwrap();
+
+                        write_output(&output, &elf::x86_64_executable(&code));
+                        #[cfg(unix)]
+                        {
+                            use std::os::unix::fs::PermissionsExt;
+                            let permissions = fs::Permissions::from_mode(0o755);
+                            fs::set_permissions(&output, permissions).unwrap();
+                        }
+                    }
+
+                    Emit::Obj => {
+                        let output = cli.output.unwrap_or_else(|| filepath.with_extension("o"));
+                        let options = Options {
+                            io: IoMode::LibcCall,
+                            align_loops: cli.align_loops,
+                            ..Options::default()
+                        };
+                        let (code, relocations, _) =
+                            x86_64_linux::compile_with(IR::from(program), options).unwrap();
+
+                        write_output(&output, &elf::x86_64_object(&code, &relocations, "bf_main"));
+                    }
+
+                    Emit::C => {
+                        let output = cli.output.unwrap_or_else(|| filepath.with_extension("c"));
+                        let src = emit::c::emit(IR::from(program)).unwrap();
+
+                        write_output(&output, src.as_bytes());
+                    }
+
+                    Emit::Rust => {
+                        let output = cli.output.unwrap_or_else(|| filepath.with_extension("rs"));
+                        let options = emit::rust::Options {
+                            cell: cli.cell,
+                            eof: cli.eof,
+                        };
+                        let src = emit::rust::emit(IR::from(program), options).unwrap();
+
+                        write_output(&output, src.as_bytes());
+                    }
+
+                    Emit::Wasm => {
+                        let output = cli
+                            .output
+                            .unwrap_or_else(|| filepath.with_extension("wasm"));
+                        let module = emit::wasm::emit(IR::from(program)).unwrap();
+
+                        write_output(&output, &module);
+                    }
+
+                    Emit::Wat => {
+                        let output = cli.output.unwrap_or_else(|| filepath.with_extension("wat"));
+                        let src = emit::wasm::emit_text(IR::from(program)).unwrap();
+
+                        write_output(&output, src.as_bytes());
+                    }
+
+                    Emit::Llvm => {
+                        let output = cli.output.unwrap_or_else(|| filepath.with_extension("ll"));
+                        let src = emit::llvm::emit(IR::from(program)).unwrap();
+
+                        write_output(&output, src.as_bytes());
+                    }
+
+                    Emit::Asm => {
+                        let output = cli.output.unwrap_or_else(|| filepath.with_extension("s"));
+                        let arch = cli.arch.or(Arch::host()).unwrap_or(Arch::X86_64);
+                        let code = match arch {
+                            Arch::X86_64 => {
+                                let options = Options {
+                                    align_loops: cli.align_loops,
+                                    ..Options::default()
+                                };
+                                x86_64_linux::compile_with(IR::from(program), options)
+                                    .unwrap()
+                                    .0
+                            }
+                            _ => arch.compile(IR::from(program)).unwrap(),
+                        };
+
+                        write_output(&output, jit::disasm::assembly(arch, &code).as_bytes());
+                    }
+                },
             }
         } else {
             eprintln!("Failed to open file {}", filepath.display());
@@ -30,5 +472,5 @@ fn main() {
         }
     }
 
-    process::exit(0);
+    process::exit(exit_status);
 }
@@ -0,0 +1,438 @@
+// Running programs that can't be trusted. Compiled code can write anywhere the
+// tape pointer wanders off to, so rather than running it in our own process, it
+// runs in a child whose CPU time and memory are limited with setrlimit(2), and
+// which a seccomp filter only lets read, write and exit. Anything else it tries
+// gets it killed, and the parent reports how it ended.
+//
+// Under the filter the child can't map, allocate or free memory, so everything
+// a program needs, its compiled code, its tape and its I/O buffers, has to be
+// set up before running it, and anything it has to say goes through report.
+use crate::brainfuck::ExecutionResult;
+use nix::{
+    errno::Errno,
+    sys::{
+        resource::{setrlimit, Resource},
+        signal::{signal, SigHandler, Signal},
+        wait::{waitpid, WaitStatus},
+    },
+    unistd::{fork, ForkResult},
+};
+use std::{
+    cell::RefCell,
+    ffi::{c_int, c_long, c_ulong, c_void},
+    fmt,
+    io::Write,
+    panic, ptr,
+    time::Duration,
+};
+
+extern "C" {
+    fn prctl(option: c_int, ...) -> c_int;
+    fn read(fd: c_int, buf: *mut c_void, count: usize) -> isize;
+    fn write(fd: c_int, buf: *const c_void, count: usize) -> isize;
+    fn setitimer(which: c_int, new: *const ITimerVal, old: *mut ITimerVal) -> c_int;
+    fn _exit(status: c_int) -> !;
+}
+
+// struct itimerval, with the struct timevals spelled out
+#[repr(C)]
+struct ITimerVal {
+    interval_sec: c_long,
+    interval_usec: c_long,
+    value_sec: c_long,
+    value_usec: c_long,
+}
+
+const ITIMER_REAL: c_int = 0;
+
+const PR_SET_SECCOMP: c_int = 22;
+const PR_SET_NO_NEW_PRIVS: c_int = 38;
+const SECCOMP_MODE_FILTER: c_ulong = 2;
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+// Classic BPF opcodes, all the filter needs
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+
+// Offsets into the struct seccomp_data the filter runs on
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+// System call numbers differ between architectures, so the filter checks it's
+// looking at calls made the host's way before going by number
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        const AUDIT_ARCH: u32 = 0xc000_003e;
+        const SYS_READ: u32 = 0;
+        const SYS_WRITE: u32 = 1;
+        const SYS_EXIT: u32 = 60;
+        const SYS_EXIT_GROUP: u32 = 231;
+    } else if #[cfg(target_arch = "aarch64")] {
+        const AUDIT_ARCH: u32 = 0xc000_00b7;
+        const SYS_READ: u32 = 63;
+        const SYS_WRITE: u32 = 64;
+        const SYS_EXIT: u32 = 93;
+        const SYS_EXIT_GROUP: u32 = 94;
+    } else if #[cfg(target_arch = "riscv64")] {
+        const AUDIT_ARCH: u32 = 0xc000_00f3;
+        const SYS_READ: u32 = 63;
+        const SYS_WRITE: u32 = 64;
+        const SYS_EXIT: u32 = 93;
+        const SYS_EXIT_GROUP: u32 = 94;
+    }
+}
+
+// struct sock_filter, a single BPF instruction
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+// struct sock_fprog, what PR_SET_SECCOMP takes
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+const fn stmt(code: u16, k: u32) -> SockFilter {
+    SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+// Jumps are counted in instructions past the jump
+const fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter { code, jt, jf, k }
+}
+
+// Allow read(2), write(2) and both ways of exiting, exit_group(2) being the one
+// libc and Rust's runtime use. Any other call kills the whole process with SIGSYS.
+const FILTER: [SockFilter; 9] = [
+    stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+    jump(BPF_JEQ_K, AUDIT_ARCH, 0, 5),
+    stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    jump(BPF_JEQ_K, SYS_READ, 4, 0),
+    jump(BPF_JEQ_K, SYS_WRITE, 3, 0),
+    jump(BPF_JEQ_K, SYS_EXIT, 2, 0),
+    jump(BPF_JEQ_K, SYS_EXIT_GROUP, 1, 0),
+    stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+    stmt(BPF_RET_K, SECCOMP_RET_ALLOW),
+];
+
+// How much a sandboxed program gets to use
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Limits {
+    // CPU time, the program gets SIGXCPU once it's used up, and SIGKILL a second
+    // later if it's still going
+    pub cpu_seconds: u64,
+    // Address space, applied once the tape and code are mapped. The filter refuses
+    // new mappings anyway, so this is what bounds the stack.
+    pub memory_bytes: u64,
+    // Wall-clock time, if limited, the program gets SIGALRM once it's up
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            cpu_seconds: 10,
+            memory_bytes: 256 << 20,
+            timeout: None,
+        }
+    }
+}
+
+// How a sandboxed program ended
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    // It exited, with this status
+    Exited(i32),
+    // It used up its CPU time, or ran out of wall-clock time
+    TimedOut,
+    // It made a system call the filter doesn't allow
+    Blocked,
+    // Some other signal killed it, usually SIGSEGV from wandering off the tape
+    Faulted(Signal),
+}
+
+// Apply the limits and install the filter, from here on the process can only
+// read, write and exit
+fn confine(limits: Limits) -> nix::Result<()> {
+    // The hard limit, a second later, is there for a program that survives SIGXCPU
+    setrlimit(
+        Resource::RLIMIT_CPU,
+        limits.cpu_seconds,
+        limits.cpu_seconds.saturating_add(1),
+    )?;
+    setrlimit(
+        Resource::RLIMIT_AS,
+        limits.memory_bytes,
+        limits.memory_bytes,
+    )?;
+
+    // SIGALRM's default action is to terminate, the program needs no handler
+    if let Some(timeout) = limits.timeout {
+        // A zero timer would never go off at all
+        let micros = timeout.as_micros().max(1);
+        let timer = ITimerVal {
+            interval_sec: 0,
+            interval_usec: 0,
+            value_sec: (micros / 1_000_000) as c_long,
+            value_usec: (micros % 1_000_000) as c_long,
+        };
+        Errno::result(unsafe { setitimer(ITIMER_REAL, &timer, ptr::null_mut()) })?;
+    }
+
+    // Rust's runtime handles these to report stack overflows, and for any other
+    // fault puts the default action back from inside the handler, which takes a
+    // system call. Have the kernel kill the process straight away instead.
+    for fault in [Signal::SIGSEGV, Signal::SIGBUS] {
+        unsafe { signal(fault, SigHandler::SigDfl)? };
+    }
+
+    // Without privileges a process may only install a filter once it has given
+    // up gaining any, through setuid executables and the like
+    Errno::result(unsafe {
+        prctl(
+            PR_SET_NO_NEW_PRIVS,
+            1 as c_ulong,
+            0 as c_ulong,
+            0 as c_ulong,
+            0 as c_ulong,
+        )
+    })?;
+
+    let program = SockFprog {
+        len: FILTER.len() as u16,
+        filter: FILTER.as_ptr(),
+    };
+    Errno::result(unsafe {
+        prctl(
+            PR_SET_SECCOMP,
+            SECCOMP_MODE_FILTER,
+            &program as *const SockFprog,
+        )
+    })?;
+
+    Ok(())
+}
+
+// Print a line to stderr from inside the sandbox. eprintln! goes through stderr's
+// lock and may allocate, which takes system calls the filter doesn't allow, so
+// the message is formatted into a buffer on the stack, cut short if it doesn't
+// fit, and written with write(2) alone.
+pub fn report(message: fmt::Arguments) {
+    let mut line = [0; 1024];
+    let unused = {
+        let mut rest = &mut line[..];
+        let _ = writeln!(rest, "{message}");
+        rest.len()
+    };
+
+    unsafe {
+        write(2, line.as_ptr().cast(), line.len() - unused);
+    }
+}
+
+// Run `program` in a child process, under `limits` and the filter, and wait to
+// see how it ends. The child exits with the exit status of the result, without
+// dropping it, freeing the tape could take a system call.
+pub fn run(limits: Limits, program: impl FnOnce() -> ExecutionResult) -> nix::Result<Outcome> {
+    match unsafe { fork()? } {
+        ForkResult::Child => {
+            // Rust's own panic handling takes system calls the filter doesn't allow,
+            // so panics print their message with write(2) alone and exit the way
+            // the runtime would
+            panic::set_hook(Box::new(|info| {
+                report(format_args!("Sandboxed program {info}"));
+                unsafe { _exit(101) }
+            }));
+
+            if confine(limits).is_err() {
+                report(format_args!("Failed to confine the sandboxed program"));
+                unsafe { _exit(-1) }
+            }
+
+            let result = program();
+            unsafe { _exit(result.exit_status) }
+        }
+
+        ForkResult::Parent { child } => loop {
+            return Ok(match waitpid(child, None)? {
+                WaitStatus::Exited(_, status) => Outcome::Exited(status),
+                // SIGKILL is the hard CPU limit, for a program that survived SIGXCPU
+                WaitStatus::Signaled(_, Signal::SIGXCPU | Signal::SIGALRM | Signal::SIGKILL, _) => {
+                    Outcome::TimedOut
+                }
+                WaitStatus::Signaled(_, Signal::SIGSYS, _) => Outcome::Blocked,
+                WaitStatus::Signaled(_, signal, _) => Outcome::Faulted(signal),
+                _ => continue,
+            });
+        },
+    }
+}
+
+// Standard I/O for the interpreter under the filter. libc's getchar/putchar would
+// allocate buffers and stat their files on first use, this gets by on a buffer
+// allocated up front and read(2)/write(2).
+pub struct RawStdio {
+    output: RefCell<Vec<u8>>,
+}
+
+impl RawStdio {
+    pub fn new() -> Self {
+        RawStdio {
+            output: RefCell::new(Vec::with_capacity(4096)),
+        }
+    }
+
+    // Read a byte from stdin, 255 at the end of input like getchar's EOF. Anything
+    // written so far goes out first, so prompts show before waiting on an answer.
+    pub fn getchar(&self) -> u8 {
+        self.flush();
+
+        let mut byte = 0xff;
+        unsafe {
+            read(0, (&mut byte as *mut u8).cast(), 1);
+        }
+        byte
+    }
+
+    pub fn putchar(&self, byte: u8) {
+        let mut output = self.output.borrow_mut();
+        if output.len() == output.capacity() {
+            drop(output);
+            self.flush();
+            output = self.output.borrow_mut();
+        }
+
+        output.push(byte);
+    }
+
+    // Write out everything buffered
+    pub fn flush(&self) {
+        let mut output = self.output.borrow_mut();
+        let mut written = 0;
+
+        while written < output.len() {
+            let count =
+                unsafe { write(1, output[written..].as_ptr().cast(), output.len() - written) };
+            if count <= 0 {
+                break;
+            }
+            written += count as usize;
+        }
+
+        output.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brainfuck::tape::Tape;
+
+    // Sandboxed programs only ever get what was set up before the fork
+    fn finished_with(exit_status: i32) -> impl FnOnce() -> ExecutionResult {
+        let tape = Tape::new();
+
+        move || ExecutionResult {
+            tape,
+            pointer: 0,
+            exit_status,
+        }
+    }
+
+    #[test]
+    fn exit_status_reaches_the_parent() {
+        let outcome = run(Limits::default(), finished_with(3)).unwrap();
+        assert_eq!(outcome, Outcome::Exited(3));
+    }
+
+    #[test]
+    fn other_system_calls_are_blocked() {
+        let exit = finished_with(0);
+        let outcome = run(Limits::default(), || {
+            nix::unistd::getppid();
+            exit()
+        })
+        .unwrap();
+
+        assert_eq!(outcome, Outcome::Blocked);
+
+        // Memory allocated too late is mapped too late
+        let exit = finished_with(0);
+        let outcome = run(Limits::default(), || {
+            std::hint::black_box(vec![0u8; 16 << 20]);
+            exit()
+        })
+        .unwrap();
+
+        assert_eq!(outcome, Outcome::Blocked);
+    }
+
+    #[test]
+    fn reports_get_through_the_filter() {
+        let exit = finished_with(0);
+        let outcome = run(Limits::default(), || {
+            report(format_args!("Reported from line {} of {}", 1, "a.bf"));
+            exit()
+        })
+        .unwrap();
+
+        assert_eq!(outcome, Outcome::Exited(0));
+    }
+
+    #[test]
+    fn faults_and_timeouts_are_reported() {
+        let exit = finished_with(0);
+        let outcome = run(Limits::default(), || {
+            unsafe { std::ptr::null_mut::<u8>().write_volatile(1) };
+            exit()
+        })
+        .unwrap();
+        assert_eq!(outcome, Outcome::Faulted(Signal::SIGSEGV));
+
+        // Out of CPU time, then out of wall-clock time well before that
+        for limits in [
+            Limits {
+                cpu_seconds: 1,
+                ..Limits::default()
+            },
+            Limits {
+                timeout: Some(Duration::from_millis(50)),
+                ..Limits::default()
+            },
+        ] {
+            let outcome = run(limits, || loop {
+                std::hint::black_box(());
+            })
+            .unwrap();
+            assert_eq!(outcome, Outcome::TimedOut);
+        }
+
+        // A program ignoring SIGXCPU, as the child inherits, gets SIGKILL at the
+        // hard limit instead
+        unsafe { signal(Signal::SIGXCPU, SigHandler::SigIgn).unwrap() };
+        let limits = Limits {
+            cpu_seconds: 1,
+            ..Limits::default()
+        };
+        let outcome = run(limits, || loop {
+            std::hint::black_box(());
+        });
+        unsafe { signal(Signal::SIGXCPU, SigHandler::SigDfl).unwrap() };
+
+        assert_eq!(outcome.unwrap(), Outcome::TimedOut);
+    }
+}
@@ -0,0 +1,53 @@
+#include <stdio.h>
+
+static unsigned char tape[30000];
+
+int main(void) {
+    unsigned char *ptr = tape;
+
+    *ptr += 10;
+    while (*ptr) {
+        ptr += 1;
+        *ptr += 7;
+        ptr += 1;
+        *ptr += 10;
+        ptr += 1;
+        *ptr += 3;
+        ptr += 1;
+        *ptr += 1;
+        ptr -= 4;
+        *ptr -= 1;
+    }
+    ptr += 1;
+    *ptr += 2;
+    putchar(*ptr);
+    ptr += 1;
+    *ptr += 1;
+    putchar(*ptr);
+    *ptr += 7;
+    putchar(*ptr);
+    putchar(*ptr);
+    *ptr += 3;
+    putchar(*ptr);
+    ptr += 1;
+    *ptr += 2;
+    putchar(*ptr);
+    ptr -= 2;
+    *ptr += 15;
+    putchar(*ptr);
+    ptr += 1;
+    putchar(*ptr);
+    *ptr += 3;
+    putchar(*ptr);
+    *ptr -= 6;
+    putchar(*ptr);
+    *ptr -= 8;
+    putchar(*ptr);
+    ptr += 1;
+    *ptr += 1;
+    putchar(*ptr);
+    ptr += 1;
+    putchar(*ptr);
+
+    return 0;
+}
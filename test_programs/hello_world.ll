@@ -0,0 +1,182 @@
+@tape = internal global [30000 x i8] zeroinitializer
+
+declare i32 @getchar()
+declare i32 @putchar(i32)
+
+define ptr @bf_main(ptr %tape) {
+entry:
+  %ptr = alloca ptr
+  store ptr %tape, ptr %ptr
+  %v1 = load ptr, ptr %ptr
+  %v2 = load i8, ptr %v1
+  %v3 = add i8 %v2, 10
+  store i8 %v3, ptr %v1
+  br label %loop0
+
+loop0:
+  %v4 = load ptr, ptr %ptr
+  %v5 = load i8, ptr %v4
+  %v6 = icmp eq i8 %v5, 0
+  br i1 %v6, label %end0, label %body0
+
+body0:
+  %v7 = load ptr, ptr %ptr
+  %v8 = getelementptr i8, ptr %v7, i64 1
+  store ptr %v8, ptr %ptr
+  %v9 = load ptr, ptr %ptr
+  %v10 = load i8, ptr %v9
+  %v11 = add i8 %v10, 7
+  store i8 %v11, ptr %v9
+  %v12 = load ptr, ptr %ptr
+  %v13 = getelementptr i8, ptr %v12, i64 1
+  store ptr %v13, ptr %ptr
+  %v14 = load ptr, ptr %ptr
+  %v15 = load i8, ptr %v14
+  %v16 = add i8 %v15, 10
+  store i8 %v16, ptr %v14
+  %v17 = load ptr, ptr %ptr
+  %v18 = getelementptr i8, ptr %v17, i64 1
+  store ptr %v18, ptr %ptr
+  %v19 = load ptr, ptr %ptr
+  %v20 = load i8, ptr %v19
+  %v21 = add i8 %v20, 3
+  store i8 %v21, ptr %v19
+  %v22 = load ptr, ptr %ptr
+  %v23 = getelementptr i8, ptr %v22, i64 1
+  store ptr %v23, ptr %ptr
+  %v24 = load ptr, ptr %ptr
+  %v25 = load i8, ptr %v24
+  %v26 = add i8 %v25, 1
+  store i8 %v26, ptr %v24
+  %v27 = load ptr, ptr %ptr
+  %v28 = getelementptr i8, ptr %v27, i64 -4
+  store ptr %v28, ptr %ptr
+  %v29 = load ptr, ptr %ptr
+  %v30 = load i8, ptr %v29
+  %v31 = sub i8 %v30, 1
+  store i8 %v31, ptr %v29
+  br label %loop0
+
+end0:
+  %v32 = load ptr, ptr %ptr
+  %v33 = getelementptr i8, ptr %v32, i64 1
+  store ptr %v33, ptr %ptr
+  %v34 = load ptr, ptr %ptr
+  %v35 = load i8, ptr %v34
+  %v36 = add i8 %v35, 2
+  store i8 %v36, ptr %v34
+  %v37 = load ptr, ptr %ptr
+  %v38 = load i8, ptr %v37
+  %v39 = zext i8 %v38 to i32
+  call i32 @putchar(i32 %v39)
+  %v40 = load ptr, ptr %ptr
+  %v41 = getelementptr i8, ptr %v40, i64 1
+  store ptr %v41, ptr %ptr
+  %v42 = load ptr, ptr %ptr
+  %v43 = load i8, ptr %v42
+  %v44 = add i8 %v43, 1
+  store i8 %v44, ptr %v42
+  %v45 = load ptr, ptr %ptr
+  %v46 = load i8, ptr %v45
+  %v47 = zext i8 %v46 to i32
+  call i32 @putchar(i32 %v47)
+  %v48 = load ptr, ptr %ptr
+  %v49 = load i8, ptr %v48
+  %v50 = add i8 %v49, 7
+  store i8 %v50, ptr %v48
+  %v51 = load ptr, ptr %ptr
+  %v52 = load i8, ptr %v51
+  %v53 = zext i8 %v52 to i32
+  call i32 @putchar(i32 %v53)
+  %v54 = load ptr, ptr %ptr
+  %v55 = load i8, ptr %v54
+  %v56 = zext i8 %v55 to i32
+  call i32 @putchar(i32 %v56)
+  %v57 = load ptr, ptr %ptr
+  %v58 = load i8, ptr %v57
+  %v59 = add i8 %v58, 3
+  store i8 %v59, ptr %v57
+  %v60 = load ptr, ptr %ptr
+  %v61 = load i8, ptr %v60
+  %v62 = zext i8 %v61 to i32
+  call i32 @putchar(i32 %v62)
+  %v63 = load ptr, ptr %ptr
+  %v64 = getelementptr i8, ptr %v63, i64 1
+  store ptr %v64, ptr %ptr
+  %v65 = load ptr, ptr %ptr
+  %v66 = load i8, ptr %v65
+  %v67 = add i8 %v66, 2
+  store i8 %v67, ptr %v65
+  %v68 = load ptr, ptr %ptr
+  %v69 = load i8, ptr %v68
+  %v70 = zext i8 %v69 to i32
+  call i32 @putchar(i32 %v70)
+  %v71 = load ptr, ptr %ptr
+  %v72 = getelementptr i8, ptr %v71, i64 -2
+  store ptr %v72, ptr %ptr
+  %v73 = load ptr, ptr %ptr
+  %v74 = load i8, ptr %v73
+  %v75 = add i8 %v74, 15
+  store i8 %v75, ptr %v73
+  %v76 = load ptr, ptr %ptr
+  %v77 = load i8, ptr %v76
+  %v78 = zext i8 %v77 to i32
+  call i32 @putchar(i32 %v78)
+  %v79 = load ptr, ptr %ptr
+  %v80 = getelementptr i8, ptr %v79, i64 1
+  store ptr %v80, ptr %ptr
+  %v81 = load ptr, ptr %ptr
+  %v82 = load i8, ptr %v81
+  %v83 = zext i8 %v82 to i32
+  call i32 @putchar(i32 %v83)
+  %v84 = load ptr, ptr %ptr
+  %v85 = load i8, ptr %v84
+  %v86 = add i8 %v85, 3
+  store i8 %v86, ptr %v84
+  %v87 = load ptr, ptr %ptr
+  %v88 = load i8, ptr %v87
+  %v89 = zext i8 %v88 to i32
+  call i32 @putchar(i32 %v89)
+  %v90 = load ptr, ptr %ptr
+  %v91 = load i8, ptr %v90
+  %v92 = sub i8 %v91, 6
+  store i8 %v92, ptr %v90
+  %v93 = load ptr, ptr %ptr
+  %v94 = load i8, ptr %v93
+  %v95 = zext i8 %v94 to i32
+  call i32 @putchar(i32 %v95)
+  %v96 = load ptr, ptr %ptr
+  %v97 = load i8, ptr %v96
+  %v98 = sub i8 %v97, 8
+  store i8 %v98, ptr %v96
+  %v99 = load ptr, ptr %ptr
+  %v100 = load i8, ptr %v99
+  %v101 = zext i8 %v100 to i32
+  call i32 @putchar(i32 %v101)
+  %v102 = load ptr, ptr %ptr
+  %v103 = getelementptr i8, ptr %v102, i64 1
+  store ptr %v103, ptr %ptr
+  %v104 = load ptr, ptr %ptr
+  %v105 = load i8, ptr %v104
+  %v106 = add i8 %v105, 1
+  store i8 %v106, ptr %v104
+  %v107 = load ptr, ptr %ptr
+  %v108 = load i8, ptr %v107
+  %v109 = zext i8 %v108 to i32
+  call i32 @putchar(i32 %v109)
+  %v110 = load ptr, ptr %ptr
+  %v111 = getelementptr i8, ptr %v110, i64 1
+  store ptr %v111, ptr %ptr
+  %v112 = load ptr, ptr %ptr
+  %v113 = load i8, ptr %v112
+  %v114 = zext i8 %v113 to i32
+  call i32 @putchar(i32 %v114)
+  %v115 = load ptr, ptr %ptr
+  ret ptr %v115
+}
+
+define i32 @main() {
+entry:
+  call ptr @bf_main(ptr @tape)
+  ret i32 0
+}